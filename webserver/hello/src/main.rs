@@ -173,7 +173,7 @@ fn handle_connection_okish(mut stream: TcpStream) {
         let contents = fs::read_to_string("404.html").unwrap();
 
         let response = format!(
-            "{}r\nContent-Length: {}\r\n\r\n{}",
+            "{}\r\nContent-Length: {}\r\n\r\n{}",
             status_line,
             contents.len(),
             contents
@@ -0,0 +1,214 @@
+// Throughput with many tiny jobs, before and after the work-stealing scheduler
+//
+// cargo bench --bench throughput
+//
+// Results go to stderr. "before" is the book's pool: every worker locks one Arc<Mutex<Receiver>>
+// for every job. Lock contention only shows up when the workers really run in parallel, on a
+// single core both pools come out about even. Neither pool prints anything per job, writing to
+// stdout would take longer than the jobs themselves and drown out the difference.
+
+use hello_multithreaded::log::NoopLogger;
+use hello_multithreaded::ThreadPool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+const WORKERS: usize = 4;
+const JOBS: usize = 200_000;
+// For the nested run: outer jobs that each execute INNER tiny jobs from inside the pool
+const OUTER: usize = 2_000;
+const INNER: usize = 100;
+
+// The pool before the redesign, copied from the book (ch20) without the comments and the
+// println! in the worker loop
+mod before {
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    type Job = Box<dyn FnOnce() + Send + 'static>;
+
+    enum Message {
+        NewJob(Job),
+        Terminate,
+    }
+
+    pub struct ThreadPool {
+        workers: Vec<Option<thread::JoinHandle<()>>>,
+        sender: mpsc::Sender<Message>,
+    }
+
+    impl ThreadPool {
+        pub fn new(size: usize) -> ThreadPool {
+            let (sender, receiver) = mpsc::channel();
+            let receiver = Arc::new(Mutex::new(receiver));
+
+            let workers = (0..size)
+                .map(|_| {
+                    let receiver: Arc<Mutex<mpsc::Receiver<Message>>> = Arc::clone(&receiver);
+                    Some(thread::spawn(move || loop {
+                        let message = receiver.lock().unwrap().recv().unwrap();
+                        match message {
+                            Message::NewJob(job) => job(),
+                            Message::Terminate => break,
+                        }
+                    }))
+                })
+                .collect();
+
+            ThreadPool { workers, sender }
+        }
+
+        pub fn execute<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'static,
+        {
+            self.sender.send(Message::NewJob(Box::new(f))).unwrap();
+        }
+    }
+
+    impl Drop for ThreadPool {
+        fn drop(&mut self) {
+            for _ in &self.workers {
+                self.sender.send(Message::Terminate).unwrap();
+            }
+            for worker in &mut self.workers {
+                if let Some(thread) = worker.take() {
+                    thread.join().unwrap();
+                }
+            }
+        }
+    }
+}
+
+// The pool under test, as quiet as the one in before
+fn pool() -> ThreadPool {
+    ThreadPool::builder(WORKERS)
+        .logger(NoopLogger)
+        .build()
+        .unwrap()
+}
+
+// Every job bumps the counter, the last one reports on the channel
+fn counter() -> (Arc<AtomicUsize>, mpsc::Sender<()>, mpsc::Receiver<()>) {
+    let (tx, rx) = mpsc::channel();
+    (Arc::new(AtomicUsize::new(0)), tx, rx)
+}
+
+fn finish(done: &AtomicUsize, total: usize, tx: &mpsc::Sender<()>) {
+    if done.fetch_add(1, Ordering::SeqCst) + 1 == total {
+        tx.send(()).unwrap();
+    }
+}
+
+fn flat_before() -> Duration {
+    let pool = before::ThreadPool::new(WORKERS);
+    let (done, tx, rx) = counter();
+
+    let start = Instant::now();
+    for _ in 0..JOBS {
+        let done = Arc::clone(&done);
+        let tx = tx.clone();
+        pool.execute(move || finish(&done, JOBS, &tx));
+    }
+    rx.recv().unwrap();
+    start.elapsed()
+}
+
+fn flat_after() -> Duration {
+    let pool = pool();
+    let (done, tx, rx) = counter();
+
+    let start = Instant::now();
+    for _ in 0..JOBS {
+        let done = Arc::clone(&done);
+        let tx = tx.clone();
+        pool.execute(move || finish(&done, JOBS, &tx)).unwrap();
+    }
+    rx.recv().unwrap();
+    start.elapsed()
+}
+
+fn nested_before() -> Duration {
+    let pool = Arc::new(before::ThreadPool::new(WORKERS));
+    let total = OUTER * INNER;
+    let (done, tx, rx) = counter();
+
+    let start = Instant::now();
+    for _ in 0..OUTER {
+        let inner_pool = Arc::clone(&pool);
+        let done = Arc::clone(&done);
+        let tx = tx.clone();
+        pool.execute(move || {
+            for _ in 0..INNER {
+                let done = Arc::clone(&done);
+                let tx = tx.clone();
+                inner_pool.execute(move || finish(&done, total, &tx));
+            }
+        });
+    }
+    rx.recv().unwrap();
+    let elapsed = start.elapsed();
+
+    // Outer jobs hold clones of the Arc, wait until the last one is gone so the pool isn't
+    // dropped on one of its own workers
+    while Arc::strong_count(&pool) > 1 {
+        std::thread::yield_now();
+    }
+    elapsed
+}
+
+fn nested_after() -> Duration {
+    let pool = Arc::new(pool());
+    let total = OUTER * INNER;
+    let (done, tx, rx) = counter();
+
+    let start = Instant::now();
+    for _ in 0..OUTER {
+        let inner_pool = Arc::clone(&pool);
+        let done = Arc::clone(&done);
+        let tx = tx.clone();
+        pool.execute(move || {
+            for _ in 0..INNER {
+                let done = Arc::clone(&done);
+                let tx = tx.clone();
+                inner_pool
+                    .execute(move || finish(&done, total, &tx))
+                    .unwrap();
+            }
+        })
+        .unwrap();
+    }
+    rx.recv().unwrap();
+    let elapsed = start.elapsed();
+
+    while Arc::strong_count(&pool) > 1 {
+        std::thread::yield_now();
+    }
+    elapsed
+}
+
+fn report(name: &str, jobs: usize, before: Duration, after: Duration) {
+    let rate = |elapsed: Duration| jobs as f64 / elapsed.as_secs_f64();
+    eprintln!(
+        "{:<8} {:>8} jobs  before {:>9.0} jobs/s ({:?})  after {:>9.0} jobs/s ({:?})  {:.2}x",
+        name,
+        jobs,
+        rate(before),
+        before,
+        rate(after),
+        after,
+        before.as_secs_f64() / after.as_secs_f64()
+    );
+}
+
+fn main() {
+    // cargo test --all-targets runs bench targets too, but without --bench. Building is enough
+    // of a check there
+    if std::env::args().all(|arg| arg != "--bench") {
+        return;
+    }
+
+    report("flat", JOBS, flat_before(), flat_after());
+    report("nested", OUTER * INNER, nested_before(), nested_after());
+}
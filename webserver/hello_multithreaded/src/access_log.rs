@@ -0,0 +1,513 @@
+// Access logging
+
+// One line per response: who asked, what for, what they got and how long it took. The workers
+// shouldn't wait for the disk, so they only hand a Record to a channel. A single background
+// thread formats the records and writes them to the file, flushing whenever it runs out of work.
+// If the disk can't keep up and the channel fills up, records are dropped (and counted) instead
+// of blocking the worker.
+//
+// The file is rotated by size, like logrotate would: access.log becomes access.log.1,
+// access.log.1 becomes access.log.2 and so on, the oldest one is deleted.
+
+use crate::request::Request;
+use crate::response::StatusCode;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How access log lines look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Common Log Format plus the duration in seconds:
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326 0.002`
+    Common,
+    /// Common plus the `Referer` and `User-Agent` headers, before the duration.
+    #[default]
+    Combined,
+    /// One JSON object per line, for log shippers.
+    Json,
+}
+
+/// Writes an access log from a background thread, see
+/// [`Server::access_log`](crate::server::Server::access_log).
+///
+/// Cloning is cheap, every clone writes to the same file. The background thread finishes
+/// writing and exits when the last clone is dropped.
+#[derive(Clone)]
+pub struct AccessLog {
+    shared: Arc<Shared>,
+}
+
+/// Configures an [`AccessLog`] before the file is opened, see [`AccessLog::builder`].
+#[derive(Debug, Clone)]
+pub struct AccessLogBuilder {
+    path: PathBuf,
+    format: LogFormat,
+    max_size: u64,
+    keep: usize,
+    capacity: usize,
+}
+
+// What the server knows about one response. The request parts stay None when the request
+// couldn't be read or parsed (408, 431, 400)
+#[derive(Debug, Clone)]
+pub(crate) struct Record {
+    time: SystemTime,
+    started: Instant,
+    client: Option<SocketAddr>,
+    method: Option<String>,
+    target: Option<String>,
+    version: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: u16,
+    bytes: u64,
+    duration: Duration,
+}
+
+struct Shared {
+    // Taken out in Drop, which hangs up the channel and lets the thread finish
+    sender: Option<SyncSender<Record>>,
+    thread: Option<thread::JoinHandle<()>>,
+    dropped: Arc<AtomicU64>,
+}
+
+// The log file and its size, rotated before a line would make it bigger than max_size
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl AccessLog {
+    /// Append to `path` in `format`, rotating at 10 MiB and keeping 5 old files.
+    pub fn open<P: AsRef<Path>>(path: P, format: LogFormat) -> io::Result<AccessLog> {
+        AccessLog::builder(path).format(format).build()
+    }
+
+    /// Start configuring an access log that appends to `path`.
+    pub fn builder<P: AsRef<Path>>(path: P) -> AccessLogBuilder {
+        AccessLogBuilder {
+            path: path.as_ref().to_path_buf(),
+            format: LogFormat::default(),
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+            capacity: 1024,
+        }
+    }
+
+    /// The number of records thrown away so far because the writer fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    // Never blocks, a full channel means the record is lost
+    pub(crate) fn record(&self, record: Record) {
+        let Some(sender) = &self.shared.sender else {
+            return;
+        };
+        match sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // The writer thread is gone, it already said why
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AccessLogBuilder {
+    pub fn format(mut self, format: LogFormat) -> AccessLogBuilder {
+        self.format = format;
+        self
+    }
+
+    /// Rotate the file once it would grow past `bytes`. Defaults to 10 MiB.
+    pub fn max_size(mut self, bytes: u64) -> AccessLogBuilder {
+        self.max_size = bytes;
+        self
+    }
+
+    /// How many rotated files to keep next to the current one. 0 truncates the file instead of
+    /// rotating it. Defaults to 5.
+    pub fn keep(mut self, files: usize) -> AccessLogBuilder {
+        self.keep = files;
+        self
+    }
+
+    /// How many records may wait for the writer before new ones are dropped. Defaults to 1024.
+    pub fn capacity(mut self, records: usize) -> AccessLogBuilder {
+        self.capacity = records;
+        self
+    }
+
+    /// Open the file and start the background writer.
+    pub fn build(self) -> io::Result<AccessLog> {
+        let file = RotatingFile::open(self.path, self.max_size, self.keep)?;
+        let (sender, receiver) = mpsc::sync_channel(self.capacity.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let format = self.format;
+        let thread = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn({
+                let dropped = Arc::clone(&dropped);
+                move || write_records(receiver, file, format, &dropped)
+            })?;
+
+        Ok(AccessLog {
+            shared: Arc::new(Shared {
+                sender: Some(sender),
+                thread: Some(thread),
+                dropped,
+            }),
+        })
+    }
+}
+
+// Wait for everything that was logged to make it into the file
+impl Drop for Shared {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The background thread. Records that arrive while it is busy are written in one go, the
+// buffer is flushed whenever the channel is empty
+fn write_records(
+    receiver: Receiver<Record>,
+    mut file: RotatingFile,
+    format: LogFormat,
+    dropped: &AtomicU64,
+) {
+    let mut reported = 0;
+    let mut line = String::new();
+
+    loop {
+        let record = match receiver.try_recv() {
+            Ok(record) => record,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = file.flush() {
+                    eprintln!("Failed to write access log: {}", e);
+                }
+                match receiver.recv() {
+                    Ok(record) => record,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        let lost = dropped.load(Ordering::Relaxed);
+        if lost > reported {
+            eprintln!("Access log fell behind, {} record(s) dropped so far", lost);
+            reported = lost;
+        }
+
+        line.clear();
+        format_record(&mut line, &record, format);
+        if let Err(e) = file.write_line(&line) {
+            eprintln!("Failed to write access log: {}", e);
+        }
+    }
+
+    if let Err(e) = file.flush() {
+        eprintln!("Failed to write access log: {}", e);
+    }
+}
+
+impl Record {
+    // Started when the connection is picked up, so the duration includes reading the request
+    pub(crate) fn new(client: Option<SocketAddr>) -> Record {
+        Record {
+            time: SystemTime::now(),
+            started: Instant::now(),
+            client,
+            method: None,
+            target: None,
+            version: None,
+            referer: None,
+            user_agent: None,
+            status: 0,
+            bytes: 0,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn request(&mut self, request: &Request) {
+        self.method = Some(request.method().to_string());
+        self.target = Some(request.target().to_string());
+        self.version = Some(request.version().to_string());
+        self.referer = request.header("Referer").map(str::to_string);
+        self.user_agent = request.header("User-Agent").map(str::to_string);
+    }
+
+    // Once the response is sent
+    pub(crate) fn response(&mut self, status: StatusCode, bytes: u64) {
+        self.status = status.as_u16();
+        self.bytes = bytes;
+        self.duration = self.started.elapsed();
+    }
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            keep,
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        // A line longer than max_size still goes into a file of its own
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.keep > 0 {
+            // access.log.4 -> access.log.5, ..., access.log -> access.log.1. Renaming over
+            // the oldest one deletes it
+            for n in (1..self.keep).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+}
+
+// Writing into a String can't fail
+fn format_record(line: &mut String, record: &Record, format: LogFormat) {
+    let time = Timestamp::from(record.time);
+    let client = record
+        .client
+        .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+
+    match format {
+        LogFormat::Common | LogFormat::Combined => {
+            // The request line is "-" if there was no request to speak of
+            let request = match (&record.method, &record.target, &record.version) {
+                (Some(method), Some(target), Some(version)) => {
+                    format!("{} {} {}", method, target, version)
+                }
+                _ => "-".to_string(),
+            };
+            let _ = write!(
+                line,
+                "{} - - [{}] \"{}\" {} ",
+                client,
+                time.clf(),
+                escape_quoted(&request),
+                record.status
+            );
+            // CLF says "-" for no body at all
+            if record.bytes == 0 {
+                line.push('-');
+            } else {
+                let _ = write!(line, "{}", record.bytes);
+            }
+            if format == LogFormat::Combined {
+                let _ = write!(
+                    line,
+                    " \"{}\" \"{}\"",
+                    escape_quoted(record.referer.as_deref().unwrap_or("-")),
+                    escape_quoted(record.user_agent.as_deref().unwrap_or("-"))
+                );
+            }
+            let _ = writeln!(line, " {:.3}", record.duration.as_secs_f64());
+        }
+        LogFormat::Json => {
+            let (path, query) = match &record.target {
+                Some(target) => match target.split_once('?') {
+                    Some((path, query)) => (Some(path), Some(query)),
+                    None => (Some(target.as_str()), None),
+                },
+                None => (None, None),
+            };
+            let _ = write!(
+                line,
+                "{{\"time\":\"{}\",\"client\":{},\"method\":{},\"path\":{},\"query\":{},\
+                 \"protocol\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\
+                 \"referer\":{},\"user_agent\":{}}}",
+                time.rfc3339(),
+                json_string(record.client.map(|addr| addr.ip().to_string()).as_deref()),
+                json_string(record.method.as_deref()),
+                json_string(path),
+                json_string(query),
+                json_string(record.version.as_deref()),
+                record.status,
+                record.bytes,
+                record.duration.as_secs_f64() * 1000.0,
+                json_string(record.referer.as_deref()),
+                json_string(record.user_agent.as_deref()),
+            );
+            line.push('\n');
+        }
+    }
+}
+
+// Inside the quotes of a CLF line: quotes and backslashes are escaped, control characters
+// (which could fake a second line) are written as \xHH, like Apache does
+fn escape_quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// A JSON string, or null
+fn json_string(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "null".to_string();
+    };
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// A point in time in UTC, split into calendar fields. std has no calendar, and a date library
+// for two formats would be overkill
+struct Timestamp {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    millis: u32,
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+        Timestamp {
+            year,
+            month,
+            day,
+            hour: secs % 86_400 / 3600,
+            minute: secs % 3600 / 60,
+            second: secs % 60,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+}
+
+impl Timestamp {
+    // 10/Oct/2000:13:55:36 +0000
+    fn clf(&self) -> String {
+        format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    // 2000-10-10T13:55:36.123Z
+    fn rfc3339(&self) -> String {
+        format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+}
+
+// Days since 1970-01-01 to (year, month, day), Howard Hinnant's algorithm:
+// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
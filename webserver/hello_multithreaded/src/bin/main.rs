@@ -12,61 +12,72 @@
 // This technique is just one of many ways to improve throughput of a web server. Other options are
 // the fork/join model and the single-threaded async I/O model
 
-use hello_multithreaded::ThreadPool;
-use std::fs;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::thread;
+use hello_multithreaded::access_log::{AccessLog, LogFormat};
+use hello_multithreaded::server::{Backend, Server};
+use hello_multithreaded::{OverflowPolicy, ThreadPool};
 use std::time::Duration;
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    // Bounded queue: during a traffic spike at most 64 connections wait for a worker, the rest
+    // get a 503 with Retry-After instead of piling up in memory
+    let pool = ThreadPool::builder(4)
+        .queue_capacity(64)
+        .overflow(OverflowPolicy::Reject)
+        .build()
+        .unwrap();
 
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    let server = Server::with_pool("127.0.0.1:7878", pool)
+        .unwrap()
+        .shutdown_timeout(Duration::from_secs(10));
 
-        // Make new threads without a limit
-        // We dont't have to wait for /sleep requests to finish
-        //thread::spawn(|| {
-        //    handle_connection(stream);
-        //});
-
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-    }
-
-    println!("Shutting down");
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
-
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+    // BACKEND=event-loop cargo run serves plain HTTP from an epoll event loop instead of a
+    // blocking accept loop, the pool then only runs handlers (Linux only)
+    let server = match std::env::var("BACKEND").as_deref() {
+        #[cfg(target_os = "linux")]
+        Ok("event-loop") => server.backend(Backend::EventLoop),
+        Ok("threads") | Err(_) => server.backend(Backend::Threads),
+        Ok(other) => panic!("Unknown backend {:?}", other),
+    };
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        // Simulating a slow request
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
+    // ACCESS_LOG=access.log cargo run logs every request in the Combined Log Format,
+    // ACCESS_LOG_FORMAT=json for JSON lines instead
+    let server = match std::env::var("ACCESS_LOG") {
+        Ok(path) => {
+            let format = match std::env::var("ACCESS_LOG_FORMAT").as_deref() {
+                Ok("json") => LogFormat::Json,
+                Ok("common") => LogFormat::Common,
+                _ => LogFormat::Combined,
+            };
+            server.access_log(AccessLog::open(path, format).expect("Error opening access log"))
+        }
+        Err(_) => server,
     };
 
-    let contents = fs::read_to_string(filename).unwrap();
+    // cargo run --features tls, with TLS_CERT and TLS_KEY pointing at PEM files, also serves
+    // HTTPS on 7879 and sends plain HTTP clients there
+    #[cfg(feature = "tls")]
+    let server = match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            let tls = hello_multithreaded::server::TlsConfig::from_pem_files(cert, key)
+                .expect("Error loading TLS certificate");
+            server
+                .listen_tls("127.0.0.1:7879", tls)
+                .unwrap()
+                .redirect_to_https(true)
+        }
+        _ => server,
+    };
 
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
+    // Ctrl-C (SIGINT) and SIGTERM no longer kill in-flight requests. The handler runs on its own
+    // thread and only flips the shutdown flag, the accept loop in run() does the rest
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        println!("Received shutdown signal");
+        shutdown.shutdown();
+    })
+    .expect("Error setting signal handler");
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    // The pool and handle_connection live in the library now (src/server.rs), so integration
+    // tests can start and stop the same server
+    server.run();
 }
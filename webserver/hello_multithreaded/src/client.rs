@@ -0,0 +1,155 @@
+// A blocking HTTP client
+
+// The other end of the server, for integration tests and small tools: request.sh does the same
+// with curl. One request per connection with Connection: close, and the whole response is read
+// into memory. No TLS, redirects or keep-alive, that's what curl is for.
+
+use crate::request::{Method, Request};
+use crate::response::{HeaderMap, StatusCode};
+use std::borrow::Cow;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub(crate) mod http;
+
+use http::{ChunkedReader, Framing};
+
+/// A minimal blocking HTTP/1.1 client for talking to a server, e.g. a [`Server`] in a test.
+///
+/// ```no_run
+/// use hello_multithreaded::client::Client;
+///
+/// let client = Client::new("127.0.0.1:7878").unwrap();
+/// let response = client.get("/").unwrap();
+/// assert_eq!(response.status().as_u16(), 200);
+/// println!("{}", response.text());
+/// ```
+///
+/// [`Server`]: crate::server::Server
+#[derive(Debug, Clone)]
+pub struct Client {
+    addr: SocketAddr,
+    timeout: Duration,
+}
+
+/// A response a [`Client`] got, body included.
+#[derive(Debug)]
+pub struct Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Client {
+    /// A client for the server at `addr`. The address is resolved once, here.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<Client> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        })?;
+        Ok(Client {
+            addr,
+            timeout: Duration::from_secs(30),
+        })
+    }
+
+    /// How long connecting, and every single read and write, may take. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn get(&self, target: &str) -> io::Result<Response> {
+        self.send(&Request::new(Method::Get, target))
+    }
+
+    /// Send `request`'s method, target, headers and body, and read the response.
+    ///
+    /// `Connection` and `Content-Length` are set by the client, `Host` too unless the request
+    /// has one.
+    pub fn send(&self, request: &Request) -> io::Result<Response> {
+        let stream = TcpStream::connect_timeout(&self.addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let method = request.method().as_str();
+        let body = request.body();
+        let mut headers = request.headers().clone();
+        if !headers.contains("Host") {
+            headers
+                .insert("Host", &self.addr.to_string())
+                .expect("valid header");
+        }
+        headers.insert("Connection", "close").expect("valid header");
+        headers.remove("Content-Length");
+        if let Some(len) = http::content_length(method, body) {
+            headers
+                .insert("Content-Length", &len.to_string())
+                .expect("valid header");
+        }
+        http::write_request(
+            &mut BufWriter::new(&stream),
+            method,
+            request.target(),
+            &headers,
+            body,
+        )?;
+
+        let mut input = BufReader::new(stream);
+        let (status, headers) = http::read_response_head(&mut input)?;
+        let mut body = Vec::new();
+        match http::framing(method == "HEAD", status, &headers)? {
+            Framing::None => {}
+            Framing::Length(len) => {
+                input.take(len).read_to_end(&mut body)?;
+                if body.len() as u64 != len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "server sent a short body",
+                    ));
+                }
+            }
+            Framing::Chunked => {
+                ChunkedReader::new(input).read_to_end(&mut body)?;
+            }
+            Framing::Close => {
+                input.read_to_end(&mut body)?;
+            }
+        }
+
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+impl Response {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The first value of header `name`, if there is one.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The body, with any chunked framing taken off. Content encodings like gzip stay.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The body as text, invalid UTF-8 replaced.
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+}
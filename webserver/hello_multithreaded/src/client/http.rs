@@ -0,0 +1,188 @@
+// The client side of HTTP/1.1, as much as Client and the proxy need: write a request, read the
+// response head, and read the body in whatever framing the server picked.
+
+use crate::request::Body;
+use crate::response::{HeaderMap, StatusCode};
+use std::io::{self, BufRead, Read, Write};
+
+// Generous, the server is one we picked to talk to. It's still a bound on what we buffer
+const MAX_HEAD: u64 = 64 * 1024;
+const MAX_LINE: u64 = 4 * 1024;
+
+pub(crate) fn write_request(
+    out: &mut impl Write,
+    method: &str,
+    target: &str,
+    headers: &HeaderMap,
+    body: &Body,
+) -> io::Result<()> {
+    write!(out, "{} {} HTTP/1.1\r\n", method, target)?;
+    for (name, value) in headers.iter() {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    out.write_all(b"\r\n")?;
+    io::copy(&mut body.reader()?, out)?;
+    out.flush()
+}
+
+// The Content-Length to send with a request, if any. A body, or a method that usually has one:
+// without a length the server can't tell an empty body from a missing one
+pub(crate) fn content_length(method: &str, body: &Body) -> Option<u64> {
+    if !body.is_empty() || !matches!(method, "GET" | "HEAD" | "DELETE") {
+        Some(body.len())
+    } else {
+        None
+    }
+}
+
+// The status line and headers. Interim responses (1xx) other than 101 are skipped, the final
+// response follows them
+pub(crate) fn read_response_head(input: &mut impl BufRead) -> io::Result<(StatusCode, HeaderMap)> {
+    loop {
+        let mut head = String::new();
+        let mut limited = (&mut *input).take(MAX_HEAD);
+        // Up to the empty line that ends the head
+        loop {
+            let start = head.len();
+            if limited.read_line(&mut head)? == 0 {
+                return Err(invalid("server closed the connection before responding"));
+            }
+            if matches!(&head[start..], "\r\n" | "\n") {
+                break;
+            }
+        }
+
+        let mut lines = head.lines();
+        // HTTP/1.1 200 OK, the reason phrase is optional and may contain spaces
+        let status_line = lines.next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let status = match (parts.next(), parts.next()) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/1.") => code
+                .parse()
+                .ok()
+                .and_then(StatusCode::from_u16)
+                .ok_or_else(|| invalid("malformed status line"))?,
+            _ => return Err(invalid("malformed status line")),
+        };
+
+        let mut headers = HeaderMap::new();
+        for line in lines.filter(|line| !line.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("malformed header"))?;
+            headers
+                .append(name, value.trim())
+                .map_err(|_| invalid("malformed header"))?;
+        }
+
+        if (100..200).contains(&status.as_u16()) && status != StatusCode::SWITCHING_PROTOCOLS {
+            continue;
+        }
+        return Ok((status, headers));
+    }
+}
+
+// How the body after a response head is framed, RFC 9112 6.3
+pub(crate) enum Framing {
+    None,
+    Length(u64),
+    Chunked,
+    // Until the server closes the connection
+    Close,
+}
+
+pub(crate) fn framing(
+    head_request: bool,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> io::Result<Framing> {
+    if head_request || !status.allows_body() {
+        return Ok(Framing::None);
+    }
+    if let Some(coding) = headers.get("Transfer-Encoding") {
+        // Codings like gzip under the chunks would have to be undone as well
+        if !coding.eq_ignore_ascii_case("chunked") {
+            return Err(invalid("unsupported transfer encoding"));
+        }
+        return Ok(Framing::Chunked);
+    }
+    match headers.get("Content-Length") {
+        Some(length) => length
+            .parse()
+            .map(Framing::Length)
+            .map_err(|_| invalid("malformed Content-Length")),
+        None => Ok(Framing::Close),
+    }
+}
+
+// Decodes a chunked body, see request/body.rs for the format. Blocking, unlike the server's
+// decoder: clients read on a thread of their own, the proxy on a worker
+pub(crate) struct ChunkedReader<R> {
+    input: R,
+    // What is left of the current chunk, None before the first size line
+    left: Option<u64>,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub(crate) fn new(input: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            input,
+            left: None,
+            done: false,
+        }
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        (&mut self.input).take(MAX_LINE).read_line(&mut line)?;
+        match line.strip_suffix("\r\n") {
+            Some(line) => Ok(line.to_string()),
+            None => Err(invalid("malformed chunked body")),
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == Some(0) {
+            // The line break after a chunk's data
+            if !self.line()?.is_empty() {
+                return Err(invalid("malformed chunked body"));
+            }
+            self.left = None;
+        }
+        if self.left.is_none() {
+            let line = self.line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size =
+                u64::from_str_radix(size, 16).map_err(|_| invalid("malformed chunk size"))?;
+            if size == 0 {
+                // Trailers up to an empty line, dropped
+                while !self.line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+            self.left = Some(size);
+        }
+
+        let left = self.left.unwrap_or_default();
+        let max = buf.len().min(left.try_into().unwrap_or(usize::MAX));
+        let read = self.input.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "server closed the connection in the middle of a chunk",
+            ));
+        }
+        self.left = Some(left - read as u64);
+        Ok(read)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
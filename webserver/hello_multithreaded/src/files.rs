@@ -0,0 +1,156 @@
+// Static files from a document root
+
+// The book serves hello.html and 404.html by name. Files serves a whole directory instead: the
+// request path is the path below the root, a directory gets its index.html, and the
+// Content-Type comes from the file extension.
+//
+// The path comes from the client, so it is taken apart segment by segment: `..` would climb out
+// of the root, and dotfiles (.git, .env) are nobody's business. Both get 404, as if they
+// weren't there, and so does a segment with an escaped slash in it. Symlinks inside the root are followed, wherever they point.
+
+use crate::handler::Handler;
+use crate::request::{percent_decode, Method, PathPrefix, Request};
+use crate::response::{Body, Response, StatusCode};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Serves the files below a directory, the document root.
+///
+/// `GET /docs/intro.html` gets `<root>/docs/intro.html`, `GET /docs/` gets
+/// `<root>/docs/index.html`. A directory without a trailing slash is redirected to one, so
+/// relative links inside its index work. Anything that isn't there gets `404 Not Found`.
+///
+/// ```no_run
+/// use hello_multithreaded::files::Files;
+/// use hello_multithreaded::handler::Router;
+///
+/// let router = Router::new().nest("/static", Files::new("public").strip_prefix("/static"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Files {
+    root: PathBuf,
+    prefix: PathPrefix,
+}
+
+// Extension, Content-Type. Everything else is application/octet-stream
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("css", "text/css; charset=utf-8"),
+    ("gif", "image/gif"),
+    ("htm", "text/html; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("webp", "image/webp"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("zip", "application/zip"),
+];
+
+impl Files {
+    pub fn new(root: impl Into<PathBuf>) -> Files {
+        Files {
+            root: root.into(),
+            prefix: PathPrefix::new(""),
+        }
+    }
+
+    /// Take `prefix` off request paths before looking them up, for a `Files` mounted with
+    /// [`Router::nest`](crate::handler::Router::nest).
+    pub fn strip_prefix(mut self, prefix: &str) -> Files {
+        self.prefix = PathPrefix::new(prefix);
+        self
+    }
+
+    // The file `path` stands for, None if it may not be served. The path is split into the
+    // segments the router and middleware saw before anything is decoded, a segment that decodes
+    // to a separator would be more than one segment to the filesystem
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let path = self.prefix.strip(path).unwrap_or(path);
+        let mut resolved = self.root.clone();
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = percent_decode(segment, false);
+            if segment.starts_with('.') || segment.contains(['/', '\\', '\0']) {
+                return None;
+            }
+            resolved.push(segment);
+        }
+        Some(resolved)
+    }
+
+    fn serve(&self, request: &Request, mut file: PathBuf) -> io::Result<Response> {
+        if fs::metadata(&file)?.is_dir() {
+            if !request.path().ends_with('/') {
+                return Ok(redirect_to_directory(request));
+            }
+            file.push("index.html");
+            // Opening a directory works on Unix, reading it is what fails, halfway through the
+            // response
+            if fs::metadata(&file)?.is_dir() {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        Ok(Response::new(StatusCode::OK)
+            .header("Content-Type", content_type(&file))
+            .body(Body::file(&file)?))
+    }
+}
+
+impl Handler for Files {
+    fn handle(&self, request: Request) -> Response {
+        if *request.method() != Method::Get {
+            return Response::new(StatusCode::METHOD_NOT_ALLOWED).header("Allow", "GET");
+        }
+        let Some(file) = self.resolve(request.path()) else {
+            return Response::new(StatusCode::NOT_FOUND);
+        };
+
+        match self.serve(&request, file) {
+            Ok(response) => response,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Response::new(StatusCode::NOT_FOUND),
+            Err(e) => {
+                eprintln!("Failed to serve {}: {}", request.path(), e);
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+// /docs -> /docs/, with the query string kept
+fn redirect_to_directory(request: &Request) -> Response {
+    let location = match request.query() {
+        Some(query) => format!("{}/?{}", request.path(), query),
+        None => format!("{}/", request.path()),
+    };
+    let mut response = Response::new(StatusCode::MOVED_PERMANENTLY);
+    // The path comes from the client, HeaderMap::insert rejects anything that would break the
+    // response
+    if response
+        .headers_mut()
+        .insert("Location", &location)
+        .is_err()
+    {
+        response = Response::new(StatusCode::BAD_REQUEST);
+    }
+    response
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
+}
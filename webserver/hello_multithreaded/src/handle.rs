@@ -0,0 +1,342 @@
+// Getting results back out of the pool
+
+// execute only takes FnOnce() jobs, so the caller never learns what a job returned or whether it
+// finished at all. ThreadPool::spawn wraps the job so its result (or panic payload) lands in a
+// shared Packet, and the JoinHandle waits on it the same way std::thread::JoinHandle waits on a
+// thread. ThreadPool::scope builds on that to allow jobs that borrow from the caller's stack, like
+// std::thread::scope.
+
+use crate::{ExecuteError, Job, Priority, ThreadPool};
+use std::any::Any;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+thread_local! {
+    // Spawned jobs catch their own panics, this tells the worker's metrics about them anyway
+    static CAUGHT_PANIC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// An owned permission to wait for a job spawned with [`ThreadPool::spawn`] or [`Scope::spawn`].
+///
+/// Dropping the handle doesn't cancel the job, its result is just thrown away.
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+    // Set for scoped jobs, joining a panicked job tells the scope the panic was handled
+    scope: Option<Arc<ScopeData>>,
+}
+
+/// Why a job didn't produce a value.
+pub enum JoinError {
+    /// The job panicked, this is the payload it panicked with.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job was dropped before a worker ran it.
+    Cancelled,
+}
+
+/// A scope to spawn jobs that borrow from the caller, see [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    data: Arc<ScopeData>,
+    // Same trick as std::thread::Scope: the invariant lifetimes stop the compiler from shrinking
+    // 'scope or growing 'env, so borrows can't escape
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// Where a job leaves its result for the JoinHandle
+struct Packet<T> {
+    result: Mutex<Option<Result<T, JoinError>>>,
+    done: Condvar,
+}
+
+// Bookkeeping shared by a scope and its jobs
+struct ScopeData {
+    running: Mutex<usize>,
+    all_done: Condvar,
+    unhandled_panics: AtomicUsize,
+}
+
+// Moved into the job. Its Drop runs whether the job finished, panicked or was never run at all,
+// so the JoinHandle and the scope are always told
+struct Completion<T> {
+    // Only None while dropping
+    packet: Option<Arc<Packet<T>>>,
+    scope: Option<Arc<ScopeData>>,
+    result: Option<Result<T, JoinError>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Wait for the job to finish.
+    ///
+    /// Returns the job's return value, or [`JoinError::Panicked`] with the payload if it panicked.
+    pub fn join(self) -> Result<T, JoinError> {
+        let mut result = self
+            .packet
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // Loop because Condvar::wait can wake up spuriously
+        loop {
+            if let Some(result) = result.take() {
+                return self.handled(result);
+            }
+            result = self
+                .packet
+                .done
+                .wait(result)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Get the result without blocking, or the handle back if the job is still queued or running.
+    pub fn try_join(self) -> Result<Result<T, JoinError>, JoinHandle<T>> {
+        let result = self
+            .packet
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        match result {
+            Some(result) => Ok(self.handled(result)),
+            None => Err(self),
+        }
+    }
+
+    /// Checks whether the job has finished, without blocking.
+    pub fn is_finished(&self) -> bool {
+        self.packet
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some()
+    }
+
+    fn handled(&self, result: Result<T, JoinError>) -> Result<T, JoinError> {
+        if let (Some(scope), Err(JoinError::Panicked(_))) = (&self.scope, &result) {
+            scope.unhandled_panics.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+impl JoinError {
+    /// The panic payload, e.g. to pass on to `std::panic::resume_unwind`.
+    pub fn into_panic(self) -> Option<Box<dyn Any + Send + 'static>> {
+        match self {
+            JoinError::Panicked(payload) => Some(payload),
+            JoinError::Cancelled => None,
+        }
+    }
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => f
+                .debug_tuple("Panicked")
+                .field(&crate::panic_message(payload))
+                .finish(),
+            JoinError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => {
+                write!(f, "job panicked: {}", crate::panic_message(payload))
+            }
+            JoinError::Cancelled => write!(f, "job was dropped before it ran"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Spawn a job that may borrow anything that outlives the scope.
+    ///
+    /// The job is guaranteed to have finished by the time [`ThreadPool::scope`] returns.
+    pub fn spawn<F, T>(&'scope self, f: F) -> Result<JoinHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        *self
+            .data
+            .running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) += 1;
+
+        let (job, handle) = package(f, Some(Arc::clone(&self.data)));
+
+        // SAFETY: the pool only accepts 'static jobs, but this one may borrow data that lives
+        // for 'scope. ThreadPool::scope doesn't return before `running` is back to zero, which
+        // happens when the job (and everything it borrowed) has been dropped, and its result
+        // too unless a JoinHandle holds on to it. So the borrows stay valid for as long as a
+        // worker can reach them
+        let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+        // If sending fails the job is dropped right here, which counts it as finished again
+        self.pool.send_job(job, Priority::Normal)?;
+        Ok(handle)
+    }
+}
+
+impl<T> Packet<T> {
+    fn new() -> Packet<T> {
+        Packet {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+}
+
+impl ScopeData {
+    fn new() -> ScopeData {
+        ScopeData {
+            running: Mutex::new(0),
+            all_done: Condvar::new(),
+            unhandled_panics: AtomicUsize::new(0),
+        }
+    }
+
+    fn wait_all(&self) {
+        let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        while *running > 0 {
+            running = self
+                .all_done
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl<T> Completion<T> {
+    // Dropping self at the end hands the result over
+    fn finish(mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let result = self.result.take().unwrap_or(Err(JoinError::Cancelled));
+
+        if let (Some(scope), Err(JoinError::Panicked(_))) = (&self.scope, &result) {
+            scope.unhandled_panics.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let packet = self.packet.take().expect("completion is only dropped once");
+        *packet.result.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+        packet.done.notify_all();
+        // If the JoinHandle is gone this is the last reference, and nobody will claim the
+        // result. It may borrow from the scope, so it has to be dropped before the scope hears
+        // that this job is done and can return, like std's Packet does
+        drop(packet);
+
+        if let Some(scope) = &self.scope {
+            let mut running = scope.running.lock().unwrap_or_else(PoisonError::into_inner);
+            *running -= 1;
+            if *running == 0 {
+                scope.all_done.notify_all();
+            }
+        }
+    }
+}
+
+// Whether the job that just ran on this thread was a spawned job that panicked. Resets the flag
+pub(crate) fn take_caught_panic() -> bool {
+    CAUGHT_PANIC.with(|caught| caught.replace(false))
+}
+
+// Wrap f into a job that reports to the returned handle
+fn package<'a, F, T>(
+    f: F,
+    scope: Option<Arc<ScopeData>>,
+) -> (Box<dyn FnOnce() + Send + 'a>, JoinHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'a,
+    T: Send + 'a,
+{
+    let packet = Arc::new(Packet::new());
+    let completion = Completion {
+        packet: Some(Arc::clone(&packet)),
+        scope: scope.clone(),
+        result: None,
+    };
+
+    let job = Box::new(move || {
+        // Catch the panic here instead of in the worker, the payload belongs to whoever joins
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JoinError::Panicked);
+        if result.is_err() {
+            CAUGHT_PANIC.with(|caught| caught.set(true));
+        }
+        completion.finish(result);
+    });
+
+    (job, JoinHandle { packet, scope })
+}
+
+impl ThreadPool {
+    /// Run `f` on the pool and get a handle to wait for its return value.
+    ///
+    /// Returns [`ExecuteError::ShuttingDown`] if the workers have stopped and the job can't run.
+    pub fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = package(f, None);
+        self.send_job(job, Priority::Normal)?;
+        Ok(handle)
+    }
+
+    /// Run jobs that borrow non-'static data, like `std::thread::scope` does for threads.
+    ///
+    /// Every job spawned on the [`Scope`] has finished by the time `scope` returns. If one of
+    /// them panicked and its handle wasn't joined, `scope` panics after all jobs are done.
+    ///
+    /// Calling `scope` from inside a job of the same pool can deadlock when no other worker is
+    /// free to run the scoped jobs.
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            data: Arc::new(ScopeData::new()),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // Even if f panics, the jobs it spawned may still be using its borrows, so wait for them
+        // before unwinding any further
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.data.wait_all();
+
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.data.unhandled_panics.load(Ordering::SeqCst) > 0 => {
+                panic!("a scoped job panicked")
+            }
+            Ok(result) => result,
+        }
+    }
+}
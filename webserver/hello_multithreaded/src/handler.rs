@@ -0,0 +1,193 @@
+// Handlers, middleware and routing
+
+// A Handler turns a Request into a Response. A Router is a Handler that picks another Handler by
+// method and path. Middleware wraps the whole chain: it gets the request first and decides
+// whether and how to pass it on with next.run(request), and gets the response back on its way
+// out. That's the place for everything that applies to many routes at once, compression,
+// logging, auth, CORS:
+//
+//     request -> layer 1 -> layer 2 -> route handler
+//     response <- layer 1 <- layer 2 <-
+//
+// Handlers run on the pool's workers, so they are Send + Sync and share whatever state they need
+// through Arcs like any other job.
+
+use crate::request::{Method, PathPrefix, Request};
+use crate::response::{Response, StatusCode};
+
+/// Produces the response to a request.
+///
+/// Implemented for closures, so `|request: Request| Response::new(StatusCode::OK)` is a handler.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> Response;
+}
+
+/// Runs around a handler, see [`Router::layer`].
+///
+/// Implemented for closures taking the request and a [`Next`].
+pub trait Middleware: Send + Sync + 'static {
+    fn call(&self, request: Request, next: Next<'_>) -> Response;
+}
+
+/// The rest of the chain after a middleware: the layers after it and the handler at the end.
+pub struct Next<'a> {
+    layers: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+/// Dispatches requests to handlers by method and path.
+///
+/// Paths match exactly, the query string is ignored. A path that is known but not for the
+/// request's method gets `405 Method Not Allowed` with an `Allow` header, anything else goes to
+/// the fallback handler, `404 Not Found` by default.
+pub struct Router {
+    routes: Vec<Route>,
+    // Prefix, handler
+    nested: Vec<(PathPrefix, Box<dyn Handler>)>,
+    fallback: Box<dyn Handler>,
+    layers: Vec<Box<dyn Middleware>>,
+}
+
+struct Route {
+    method: Method,
+    path: String,
+    handler: Box<dyn Handler>,
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, Next<'_>) -> Response + Send + Sync + 'static,
+{
+    fn call(&self, request: Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+impl Next<'_> {
+    /// Pass the request on and get the response back.
+    pub fn run(self, request: Request) -> Response {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.call(
+                request,
+                Next {
+                    layers,
+                    router: self.router,
+                },
+            ),
+            None => self.router.dispatch(request),
+        }
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            nested: Vec::new(),
+            fallback: Box::new(|_: Request| Response::new(StatusCode::NOT_FOUND)),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Send `method` requests for `path` to `handler`. A route added later for the same method
+    /// and path doesn't replace the earlier one, the first match wins.
+    pub fn route(mut self, method: Method, path: &str, handler: impl Handler) -> Router {
+        self.routes.push(Route {
+            method,
+            path: path.to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Get, path, handler)
+    }
+
+    pub fn post(self, path: &str, handler: impl Handler) -> Router {
+        self.route(Method::Post, path, handler)
+    }
+
+    /// Send requests for `prefix` and every path below it to `handler`, whatever their method:
+    /// `/api` takes `/api` and `/api/users`, but not `/apis`. For handing a whole part of the
+    /// site to another handler, e.g. a [`Proxy`](crate::proxy::Proxy).
+    ///
+    /// Routes added with [`Router::route`] come first, then prefixes in the order they were
+    /// added.
+    pub fn nest(mut self, prefix: &str, handler: impl Handler) -> Router {
+        self.nested
+            .push((PathPrefix::new(prefix), Box::new(handler)));
+        self
+    }
+
+    /// Handle requests no route matches.
+    pub fn fallback(mut self, handler: impl Handler) -> Router {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Wrap every request, routed or not, in `middleware`.
+    ///
+    /// Layers run in the order they were added: the first one sees the request first and the
+    /// response last.
+    pub fn layer(mut self, middleware: impl Middleware) -> Router {
+        self.layers.push(Box::new(middleware));
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: Request) -> Response {
+        Next {
+            layers: &self.layers,
+            router: self,
+        }
+        .run(request)
+    }
+}
+
+impl Router {
+    // The end of the middleware chain: the matching route or the fallback
+    fn dispatch(&self, request: Request) -> Response {
+        let routes = &self.routes;
+        let path = request.path();
+
+        if let Some(route) = routes
+            .iter()
+            .find(|route| route.path == path && route.method == *request.method())
+        {
+            return route.handler.handle(request);
+        }
+
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in routes.iter().filter(|route| route.path == path) {
+            if !allowed.contains(&route.method.as_str()) {
+                allowed.push(route.method.as_str());
+            }
+        }
+        if allowed.is_empty() {
+            let nested = self.nested.iter().find(|(prefix, _)| prefix.matches(path));
+            return match nested {
+                Some((_, handler)) => handler.handle(request),
+                None => self.fallback.handle(request),
+            };
+        }
+
+        Response::new(StatusCode::METHOD_NOT_ALLOWED).header("Allow", &allowed.join(", "))
+    }
+}
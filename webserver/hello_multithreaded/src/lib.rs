@@ -2,14 +2,77 @@
 
 // Building the ThreadPool Struct Using Compiler Driven Development
 
-use std::sync::mpsc::{self, Receiver};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod access_log;
+pub mod client;
+pub mod files;
+mod handle;
+pub mod handler;
+pub mod log;
+mod metrics;
+pub mod middleware;
+pub mod proxy;
+mod queue;
+pub mod request;
+pub mod response;
+mod scheduler;
+pub mod server;
+pub mod sse;
+pub mod template;
+mod timer;
+pub mod vhost;
+pub mod websocket;
+
+pub use handle::{JoinError, JoinHandle, Scope};
+pub use metrics::{Bucket, HistogramSnapshot, PoolStats};
+pub use queue::{OverflowPolicy, Priority};
+pub use timer::PeriodicHandle;
+
+use log::{log, Level, Logger, StdoutLogger};
+use metrics::Metrics;
+use queue::{JobQueue, Task};
+use scheduler::{Scheduler, Slot, Stop};
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    // Behind a Mutex because an elastic pool spawns workers from execute, which only has &self
+    workers: Mutex<Vec<Worker>>,
+    shared: Arc<Shared>,
+    next_id: AtomicUsize,
+}
+
+/// Reads a pool's stats from anywhere, e.g. from inside one of its own jobs.
+///
+/// Get one from [`ThreadPool::stats_handle`]. Cloning is cheap.
+#[derive(Clone)]
+pub struct StatsHandle {
+    shared: Arc<Shared>,
+}
+
+// Everything the workers share with the pool
+struct Shared {
+    scheduler: Scheduler,
+    metrics: Metrics,
+    logger: Arc<dyn Logger>,
+}
+
+/// Configures a [`ThreadPool`] before it is created, see [`ThreadPool::builder`].
+#[derive(Clone)]
+pub struct PoolBuilder {
+    size: usize,
+    max_size: usize,
+    keep_alive: Duration,
+    queue_capacity: Option<usize>,
+    overflow: OverflowPolicy,
+    logger: Arc<dyn Logger>,
 }
 
 // Type alias for a trait object that holds the type of closure that execute receives
@@ -26,79 +89,434 @@ impl ThreadPool {
     // function can panic
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero or if the OS refuses to spawn a thread.
+    /// Use [`ThreadPool::build`] to handle those cases instead.
     // usize, because negative number of threads doesn't make sense
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
+        ThreadPool::build(size).expect("failed to spawn worker thread")
+    }
 
-        let receiver = Arc::new(Mutex::new(receiver));
-        // with_capacity is the same as new except that it preallocates space in the vector which
-        // is slightly more efficient (new resizes the Vector for each item)
-        let mut workers = Vec::with_capacity(size);
+    /// Create a new ThreadPool, returning an error instead of panicking.
+    ///
+    /// Fails with [`PoolCreationError::ZeroSize`] if the size is zero, and with
+    /// [`PoolCreationError::Spawn`] if a worker thread can't be spawned. Workers that were
+    /// already started are shut down again before the error is returned.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder(size).build()
+    }
 
-        for id in 0..size {
-            // create some threads and store them in the vector
-            // Channel is multi producer single consumer, therefore we need the Arc Type to let
-            // multiple workers own the receiver, and Mutex will ensure that only one worker gets a
-            // job from the receiver at a time
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Start configuring a pool with `size` workers.
+    ///
+    /// By default the job queue is unbounded, [`PoolBuilder::queue_capacity`] limits it, and the
+    /// number of workers is fixed, [`PoolBuilder::max_size`] makes the pool elastic.
+    pub fn builder(size: usize) -> PoolBuilder {
+        PoolBuilder {
+            size,
+            max_size: size,
+            keep_alive: Duration::from_secs(60),
+            logger: Arc::new(StdoutLogger::default()),
+            queue_capacity: None,
+            overflow: OverflowPolicy::Block,
         }
-
-        ThreadPool { workers, sender }
     }
+
     // We still use the () after FnOnce because this FnONce represents a closure that takes not
     // parameters and returns the unit type ()
     // The F type pararmeter slo has the trait bound Send and the lifetime bound 'static, which are
     // useful in our situation: we need Send to tranfer the closure form one thread to another and
     // 'static because we don't know how long the thread will takte to execute
-    pub fn execute<F>(&self, f: F)
+    ///
+    /// Returns [`ExecuteError::ShuttingDown`] if the pool is shutting down, and
+    /// [`ExecuteError::QueueFull`] if the queue is full and the pool uses
+    /// [`OverflowPolicy::Reject`]. The job is dropped in both cases.
+    ///
+    /// Jobs executed from inside another job of the same pool skip the shared queue and go to
+    /// the current worker's own deque, where idle workers can steal them. They don't count
+    /// against the queue capacity and never block.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
         // Create new job instance using the closure
-        let job = Box::new(f);
+        self.send_job(Box::new(f), Priority::Normal)
+    }
+
+    /// Like [`ThreadPool::execute`], but waiting jobs with a higher priority are started first.
+    ///
+    /// A [`Priority::High`] job is picked up by the next worker that finishes its current job,
+    /// ahead of everything else that is waiting. Jobs that are already running are not
+    /// interrupted, so with every worker busy on a slow job even a high priority job waits.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send_job(Box::new(f), priority)
+    }
+
+    /// Run `f` once, after `delay` has passed.
+    ///
+    /// There is no extra thread per timer: the pool's workers keep an eye on the deadline and
+    /// queue the job when it is due. If every worker is busy at that moment, the job starts
+    /// when one of them is free. Timers that haven't fired yet when the pool shuts down are
+    /// dropped.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared
+            .scheduler
+            .schedule_once(Instant::now() + delay, Box::new(f))
+    }
+
+    /// Run `f` every `interval`, starting one interval from now, until the returned handle is
+    /// cancelled or the pool shuts down.
+    ///
+    /// Runs never overlap: if a run is still going when the next one is due, that tick is
+    /// skipped. A panicking run is counted like any other panicking job and doesn't stop the
+    /// following ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> Result<PeriodicHandle, ExecuteError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero(), "interval must be greater than zero");
+
+        self.shared.scheduler.schedule_every(interval, Box::new(f))
+    }
+
+    // Also used by spawn and Scope::spawn, which wrap the caller's closure into a Job first
+    fn send_job(&self, job: Job, priority: Priority) -> Result<(), ExecuteError> {
+        // Checked before submitting as well, with OverflowPolicy::Block a full queue would
+        // otherwise block us before we get the chance to add a worker
+        self.grow_if_backed_up();
+
+        // Hand the job to the scheduler
+        // The book sends it down an mpsc channel and calls unwrap. With a bounded queue pushing
+        // can fail for real, so the error goes back to the caller who decides what to do with the
+        // work
+        self.shared.scheduler.submit(Task::new(job, priority))?;
+
+        self.grow_if_backed_up();
+        Ok(())
+    }
+
+    /// Change the number of workers to `size`.
+    ///
+    /// New workers start right away. If the pool shrinks, surplus workers retire as soon as
+    /// they are idle, jobs that are running are never interrupted. An elastic pool stays at
+    /// `size` workers from then on, as if it was built with `min == max == size`.
+    pub fn resize(&self, size: usize) -> Result<(), PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+
+        self.shared.scheduler.set_size(size);
+        while self.shared.scheduler.live() < size {
+            match self.spawn_worker(size) {
+                Ok(true) => {}
+                // Someone else got there first, or the pool is shutting down
+                Ok(false) => break,
+                Err(e) => return Err(PoolCreationError::Spawn(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of workers that are currently alive.
+    pub fn size(&self) -> usize {
+        self.shared.scheduler.live()
+    }
+
+    /// Current queue length, worker counts, job counts and latency histograms.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+
+    /// A handle that reads [`ThreadPool::stats`] without borrowing the pool, for example from
+    /// a job that serves the stats over HTTP.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    // Elastic pools add a worker when jobs are waiting and every worker is busy. Failing to
+    // spawn isn't fatal here, the job is queued and the existing workers get to it eventually
+    fn grow_if_backed_up(&self) {
+        if self.shared.scheduler.is_backed_up() {
+            if let Err(e) = self.spawn_worker(self.shared.scheduler.max()) {
+                log!(
+                    self.shared.logger,
+                    Level::Warn,
+                    "Failed to spawn an extra worker: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    // Start one more worker unless `limit` workers are already running. Returns whether a
+    // worker was started
+    fn spawn_worker(&self, limit: usize) -> io::Result<bool> {
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // terminate closes the scheduler before taking the workers out, checking under the same
+        // lock means no worker slips in after shutdown started
+        if self.shared.scheduler.is_closed() {
+            return Ok(false);
+        }
+
+        let slot = match self.shared.scheduler.add_worker(limit) {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+
+        // Retired workers have finished their threads, drop their handles while we're here
+        workers.retain(|worker| {
+            !worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| thread.is_finished())
+        });
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        match Worker::new(id, Arc::clone(&slot), Arc::clone(&self.shared)) {
+            Ok(worker) => {
+                workers.push(worker);
+                Ok(true)
+            }
+            Err(e) => {
+                self.shared.scheduler.remove_worker(&slot);
+                Err(e)
+            }
+        }
+    }
+
+    /// Shut the pool down, giving jobs that are already queued or running up to `deadline` to
+    /// finish.
+    ///
+    /// Returns the number of workers that were still busy when the deadline passed. Those
+    /// workers are detached instead of joined, so a stuck job can't hang the shutdown forever.
+    pub fn shutdown_timeout(mut self, deadline: Duration) -> usize {
+        self.terminate(Some(deadline))
+    }
+
+    // Shared by Drop and shutdown_timeout. Draining the workers makes a second call (Drop running
+    // after shutdown_timeout) a no-op.
+    fn terminate(&mut self, deadline: Option<Duration>) -> usize {
+        if self.shared.scheduler.is_closed() {
+            return 0;
+        }
+
+        let logger = &self.shared.logger;
+        log!(
+            logger,
+            Level::Info,
+            "Sending terminate message to all workers."
+        );
 
-        // Send job down the sending end of the channel
-        // Call unwrap in case sending fails
-        // This might happen if, for example, we stop all our threads from executing, meaning the
-        // receiving end has stopped receiving new messages
-        // The reason we use unwrap is that we know the failure case won't happen (The threads
-        // continue executing as long as the pool exists), but the compiler
-        // doensn't know that
-        self.sender.send(Message::NewJob(job)).unwrap();
+        // Closing the queue replaces the book's one Terminate message per worker. Jobs that are
+        // still waiting get to run first, workers stop once the queue is empty
+        self.shared.scheduler.close();
+        let workers =
+            std::mem::take(&mut *self.workers.lock().unwrap_or_else(PoisonError::into_inner));
+
+        log!(logger, Level::Info, "Shutting down all workers.");
+
+        let end = deadline.map(|deadline| Instant::now() + deadline);
+        let mut abandoned = 0;
+
+        for mut worker in workers {
+            log!(logger, Level::Info, "Shutting down worker {}", worker.id);
+
+            // Take ownership of thread, leave None in place
+            if let Some(thread) = worker.thread.take() {
+                if let Some(end) = end {
+                    // JoinHandle::join has no timeout, so poll until the thread is done or we run
+                    // out of time
+                    while !thread.is_finished() && Instant::now() < end {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+
+                    if !thread.is_finished() {
+                        log!(
+                            logger,
+                            Level::Warn,
+                            "Worker {} missed the shutdown deadline; detaching.",
+                            worker.id
+                        );
+                        abandoned += 1;
+                        continue;
+                    }
+                }
+
+                thread.join().unwrap();
+            }
+        }
+
+        abandoned
     }
 }
 
 // Docs: https://doc.rust-lang.org/stable/book/ch20-03-graceful-shutdown-and-cleanup.html
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+        self.terminate(None);
+    }
+}
+
+impl PoolBuilder {
+    /// Limit the number of jobs waiting for a worker. What happens when the queue is full is
+    /// up to the [`OverflowPolicy`], blocking the caller by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> PoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    pub fn overflow(mut self, policy: OverflowPolicy) -> PoolBuilder {
+        self.overflow = policy;
+        self
+    }
 
-        // Two loops to prevent Deadlocks
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+    /// Make the pool elastic: it starts with `size` workers and spawns more, up to `max_size`,
+    /// while jobs are waiting and every worker is busy.
+    pub fn max_size(mut self, max_size: usize) -> PoolBuilder {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long a worker above the minimum size waits for work before it retires. Defaults to
+    /// 60 seconds, only matters for elastic pools.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> PoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Where the pool's log messages go. Defaults to [`StdoutLogger`], which prints everything
+    /// like the book's println!s did.
+    pub fn logger(mut self, logger: impl Logger + 'static) -> PoolBuilder {
+        self.logger = Arc::new(logger);
+        self
+    }
+
+    /// Create the pool, see [`ThreadPool::build`] for the errors.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
         }
 
-        println!("Shutting down all workers.");
+        if self.max_size < self.size {
+            return Err(PoolCreationError::MaxBelowSize);
+        }
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        let injector = JobQueue::new(self.queue_capacity, self.overflow);
+        let shared = Shared {
+            scheduler: Scheduler::new(injector, self.size, self.max_size, self.keep_alive),
+            metrics: Metrics::default(),
+            logger: self.logger,
+        };
 
-            // Take ownership of thread, leave None in place
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        // with_capacity is the same as new except that it preallocates space in the vector which
+        // is slightly more efficient (new resizes the Vector for each item)
+        let pool = ThreadPool {
+            workers: Mutex::new(Vec::with_capacity(self.size)),
+            shared: Arc::new(shared),
+            next_id: AtomicUsize::new(0),
+        };
+
+        for _ in 0..self.size {
+            // create some threads and store them in the vector
+            // Every worker needs its own handle to the scheduler, therefore we need the Arc Type
+            // If spawning fails, returning drops the pool, which terminates the workers that did
+            // start
+            pool.spawn_worker(self.size)
+                .map_err(PoolCreationError::Spawn)?;
+        }
+
+        Ok(pool)
+    }
+}
+
+// Written out because dyn Logger isn't Debug
+impl fmt::Debug for PoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoolBuilder")
+            .field("size", &self.size)
+            .field("max_size", &self.max_size)
+            .field("keep_alive", &self.keep_alive)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("overflow", &self.overflow)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Error returned by [`ThreadPool::build`].
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// A pool needs at least one worker.
+    ZeroSize,
+    /// A bounded queue needs room for at least one job.
+    ZeroCapacity,
+    /// The maximum size of an elastic pool is smaller than its initial size.
+    MaxBelowSize,
+    /// The OS failed to spawn a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            PoolCreationError::ZeroCapacity => {
+                write!(f, "queue capacity must be greater than zero")
+            }
+            PoolCreationError::MaxBelowSize => {
+                write!(f, "maximum pool size must not be smaller than the size")
             }
+            PoolCreationError::Spawn(e) => write!(f, "failed to spawn worker thread: {}", e),
         }
     }
 }
 
-enum Message {
-    NewJob(Job),
-    Terminate,
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize
+            | PoolCreationError::ZeroCapacity
+            | PoolCreationError::MaxBelowSize => None,
+            PoolCreationError::Spawn(e) => Some(e),
+        }
+    }
+}
+
+/// Error returned by [`ThreadPool::execute`] when a job can't be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// The pool is shutting down and no longer accepts jobs.
+    ShuttingDown,
+    /// The queue is full and the pool uses [`OverflowPolicy::Reject`].
+    QueueFull,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::ShuttingDown => write!(f, "thread pool is shutting down"),
+            ExecuteError::QueueFull => write!(f, "thread pool queue is full"),
+        }
+    }
 }
 
+impl Error for ExecuteError {}
+
 // Worker is a common term in pooling implementations. Think of people working in the kitchen at a
 // restaurant : the workers wait until orders come in form customers, and then they're responsible
 // for taking those orders and filling them
@@ -109,38 +527,91 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+    // thread::Builder::spawn returns an io::Result instead of panicking like thread::spawn
+    // does when the OS can't create a thread. Naming the thread also makes panic messages
+    // point at the right worker
+    fn new(id: usize, slot: Arc<Slot>, shared: Arc<Shared>) -> io::Result<Worker> {
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+
+        let thread = builder.spawn(move || {
+            let Shared {
+                scheduler,
+                metrics,
+                logger,
+            } = &*shared;
+
+            scheduler.register_worker(&slot);
+
+            // next_job only holds a lock while taking a job off a queue, the job itself runs
+            // after the lock is released so other workers can pick up jobs in the meantime
+            let stop = loop {
+                let task = match scheduler.next_job(&slot) {
+                    Ok(task) => task,
+                    Err(stop) => break stop,
+                };
 
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
+                log!(logger, Level::Debug, "Worker {} got a job; executing.", id);
 
-                    job();
+                let started = Instant::now();
+                metrics.job_started(started - task.queued_at);
+
+                // A panicking job would otherwise unwind through this loop and kill the worker
+                // for good, shrinking the pool by one every time. catch_unwind stops the
+                // unwinding here, so the worker reports it and moves on to the next job.
+                // AssertUnwindSafe is fine because the job is consumed, nothing it touched is
+                // used again by the worker
+                let result = panic::catch_unwind(AssertUnwindSafe(task.job));
+                if let Err(payload) = &result {
+                    log!(
+                        logger,
+                        Level::Warn,
+                        "Worker {} job panicked: {}; continuing.",
+                        id,
+                        panic_message(payload)
+                    );
                 }
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
 
-                    break;
+                let panicked = result.is_err() || handle::take_caught_panic();
+                metrics.job_finished(started.elapsed(), panicked);
+            };
+
+            match stop {
+                Stop::Shutdown => log!(logger, Level::Info, "Worker {} was told to terminate.", id),
+                Stop::Retire => {
+                    log!(logger, Level::Info, "Worker {} is idle and retires.", id);
+                    scheduler.release_slot(&slot);
                 }
             }
+        })?;
 
-            // Call lock on the receiver to acquire the mutex, and the nwe call unwrap to panic on
-            // any errors. Acquiring a lock might fail if the mutex is poisoned state, which can
-            // happen if some other thread panicked while holding the lock reather than releasing
-            // the lock. In this situation, calling unwrap to have this thread panic is the correct
-            // action to take.
-            // Shouldn't use a while let loop (or if let, match) here, because it would not drop temprorary values
-            // until the end of the associated block, resulting in other workers not beiing able to
-            // receive jobs. With let job = receiver.lock().unwrap().recv().unwrap(); however, any
-            // temporary values used in the expression on the right hand side of the equals sing
-            // are immediately dropped when the let statement ends.
-        });
-
-        Worker {
+        Ok(Worker {
             id,
             thread: Some(thread),
-        }
+        })
+    }
+}
+
+impl Shared {
+    fn stats(&self) -> PoolStats {
+        self.metrics
+            .snapshot(self.scheduler.queued(), self.scheduler.live())
+    }
+}
+
+impl StatsHandle {
+    /// Same as [`ThreadPool::stats`].
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+}
+
+// panic! with a string literal carries a &str, panic! with format arguments a String
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
     }
 }
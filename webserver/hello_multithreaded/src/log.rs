@@ -0,0 +1,83 @@
+// Pluggable logging
+
+// The book's pool println!s from the workers, which is nice to watch while learning but can't be
+// turned off or sent anywhere else. The pool now talks to a Logger instead. StdoutLogger prints
+// the same lines as before and is the default, NoopLogger keeps quiet, and any
+// Fn(Level, fmt::Arguments) closure works as a logger too.
+
+use std::fmt;
+
+/// How important a log message is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Per-job chatter, like "Worker 0 got a job; executing."
+    Debug,
+    /// Workers starting, retiring and shutting down.
+    Info,
+    /// Something went wrong but the pool carries on, like a panicking job.
+    Warn,
+    /// Something is broken and needs a look. The pool has nothing this bad to report itself,
+    /// it's there for loggers shared with the rest of the program.
+    Error,
+}
+
+/// Receives the pool's log messages, see [`PoolBuilder::logger`](crate::PoolBuilder::logger).
+///
+/// Called from worker threads, so implementations must be cheap and thread safe.
+pub trait Logger: Send + Sync {
+    fn log(&self, level: Level, message: fmt::Arguments<'_>);
+}
+
+/// Prints messages at or above a minimum level to stdout.
+#[derive(Debug, Clone, Copy)]
+pub struct StdoutLogger {
+    min_level: Level,
+}
+
+/// Throws every message away.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopLogger;
+
+impl StdoutLogger {
+    pub fn new(min_level: Level) -> StdoutLogger {
+        StdoutLogger { min_level }
+    }
+}
+
+// Everything, like the println!s this replaces
+impl Default for StdoutLogger {
+    fn default() -> StdoutLogger {
+        StdoutLogger::new(Level::Debug)
+    }
+}
+
+impl Logger for StdoutLogger {
+    fn log(&self, level: Level, message: fmt::Arguments<'_>) {
+        if level >= self.min_level {
+            println!("{}", message);
+        }
+    }
+}
+
+impl Logger for NoopLogger {
+    fn log(&self, _level: Level, _message: fmt::Arguments<'_>) {}
+}
+
+impl<F> Logger for F
+where
+    F: Fn(Level, fmt::Arguments<'_>) + Send + Sync,
+{
+    fn log(&self, level: Level, message: fmt::Arguments<'_>) {
+        self(level, message)
+    }
+}
+
+// log!(logger, Level::Info, "Worker {} ...", id), format_args! means nothing is allocated for
+// messages the logger ignores
+macro_rules! log {
+    ($logger:expr, $level:expr, $($arg:tt)+) => {
+        $logger.log($level, format_args!($($arg)+))
+    };
+}
+
+pub(crate) use log;
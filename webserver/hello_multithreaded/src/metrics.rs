@@ -0,0 +1,221 @@
+// Pool metrics
+
+// Counters and latency histograms the workers update as they go. Everything is an atomic, so
+// recording costs a few uncontended atomic adds per job and reading never blocks the workers.
+// PoolStats is a snapshot of them, and can render itself in the Prometheus text format for the
+// webserver's /metrics endpoint.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the histogram buckets in seconds, from 100µs up to 10s. Same idea as
+// Prometheus' default buckets, with a bit more resolution at the fast end where most jobs land
+const BUCKET_BOUNDS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 10.0,
+];
+
+/// A point-in-time view of a pool, returned by [`ThreadPool::stats`](crate::ThreadPool::stats).
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    /// Workers that are alive, busy or idle.
+    pub workers: usize,
+    /// Workers running a job right now.
+    pub active_workers: usize,
+    /// Jobs that ran to the end.
+    pub completed: u64,
+    /// Jobs that panicked.
+    pub panicked: u64,
+    /// Time from submitting a job until a worker starts it.
+    pub queue_wait: HistogramSnapshot,
+    /// Time a worker spends running a job.
+    pub execution: HistogramSnapshot,
+}
+
+/// Latency distribution, bucketed like a Prometheus histogram.
+#[derive(Debug, Clone)]
+pub struct HistogramSnapshot {
+    /// Cumulative counts: each bucket counts every observation up to its bound. Observations
+    /// above the last bound only show up in `count`.
+    pub buckets: Vec<Bucket>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    /// Upper bound in seconds ("less or equal", the `le` label in Prometheus).
+    pub le: f64,
+    pub count: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    active: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    queue_wait: Histogram,
+    execution: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // One more than there are bounds, the last one counts everything above 10s
+    counts: [AtomicU64; BUCKET_BOUNDS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn job_started(&self, queue_wait: Duration) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.queue_wait.record(queue_wait);
+    }
+
+    pub(crate) fn job_finished(&self, execution: Duration, panicked: bool) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.execution.record(execution);
+        if panicked {
+            self.panicked.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.completed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self, queued: usize, workers: usize) -> PoolStats {
+        PoolStats {
+            queued,
+            workers,
+            active_workers: self.active.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+            queue_wait: self.queue_wait.snapshot(),
+            execution: self.execution.snapshot(),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = BUCKET_BOUNDS
+            .iter()
+            .zip(&self.counts)
+            .map(|(&le, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                Bucket {
+                    le,
+                    count: cumulative,
+                }
+            })
+            .collect();
+        let count = cumulative + self.counts[BUCKET_BOUNDS.len()].load(Ordering::Relaxed);
+
+        HistogramSnapshot {
+            buckets,
+            count,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl PoolStats {
+    /// Render the stats in the Prometheus text exposition format, every metric name starting
+    /// with `prefix` (e.g. `"threadpool"`).
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            prefix,
+            "queued_jobs",
+            "Jobs waiting for a worker.",
+            self.queued,
+        );
+        gauge(&mut out, prefix, "workers", "Workers alive.", self.workers);
+        gauge(
+            &mut out,
+            prefix,
+            "active_workers",
+            "Workers running a job.",
+            self.active_workers,
+        );
+        counter(
+            &mut out,
+            prefix,
+            "jobs_completed_total",
+            "Jobs that ran to the end.",
+            self.completed,
+        );
+        counter(
+            &mut out,
+            prefix,
+            "jobs_panicked_total",
+            "Jobs that panicked.",
+            self.panicked,
+        );
+        self.queue_wait.write_prometheus(
+            &mut out,
+            prefix,
+            "queue_wait_seconds",
+            "Time jobs spent waiting for a worker.",
+        );
+        self.execution.write_prometheus(
+            &mut out,
+            prefix,
+            "execution_seconds",
+            "Time workers spent running jobs.",
+        );
+
+        out
+    }
+}
+
+impl HistogramSnapshot {
+    fn write_prometheus(&self, out: &mut String, prefix: &str, name: &str, help: &str) {
+        // Writing into a String can't fail, hence the unwraps here and below
+        writeln!(out, "# HELP {}_{} {}", prefix, name, help).unwrap();
+        writeln!(out, "# TYPE {}_{} histogram", prefix, name).unwrap();
+        for bucket in &self.buckets {
+            writeln!(
+                out,
+                "{}_{}_bucket{{le=\"{}\"}} {}",
+                prefix, name, bucket.le, bucket.count
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_{}_bucket{{le=\"+Inf\"}} {}",
+            prefix, name, self.count
+        )
+        .unwrap();
+        writeln!(out, "{}_{}_sum {}", prefix, name, self.sum.as_secs_f64()).unwrap();
+        writeln!(out, "{}_{}_count {}", prefix, name, self.count).unwrap();
+    }
+}
+
+fn gauge(out: &mut String, prefix: &str, name: &str, help: &str, value: usize) {
+    writeln!(out, "# HELP {}_{} {}", prefix, name, help).unwrap();
+    writeln!(out, "# TYPE {}_{} gauge", prefix, name).unwrap();
+    writeln!(out, "{}_{} {}", prefix, name, value).unwrap();
+}
+
+fn counter(out: &mut String, prefix: &str, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {}_{} {}", prefix, name, help).unwrap();
+    writeln!(out, "# TYPE {}_{} counter", prefix, name).unwrap();
+    writeln!(out, "{}_{} {}", prefix, name, value).unwrap();
+}
@@ -0,0 +1,28 @@
+// Middleware that comes with the server, added to a Router with Router::layer
+
+mod auth;
+mod compression;
+mod cors;
+mod rate_limit;
+mod security_headers;
+
+pub use auth::{Auth, Htpasswd, Tokens};
+pub use compression::Compression;
+pub use cors::Cors;
+pub use rate_limit::{Rate, RateLimit};
+pub use security_headers::SecurityHeaders;
+
+use crate::response::Response;
+
+// Tell caches that the response depends on request header `name`, unless it says so already
+fn vary(response: &mut Response, name: &str) {
+    let headers = response.headers_mut();
+    let already = headers.get_all("Vary").any(|value| {
+        value
+            .split(',')
+            .any(|known| known.trim() == "*" || known.trim().eq_ignore_ascii_case(name))
+    });
+    if !already {
+        headers.append("Vary", name).expect("Vary header is valid");
+    }
+}
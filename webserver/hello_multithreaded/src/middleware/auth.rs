@@ -0,0 +1,298 @@
+// HTTP authentication
+
+// Two ways for a client to say who it is, both in the Authorization header:
+//
+// - Basic: "Basic " and base64 of "user:password", what browsers send after asking the user in
+//   their login dialog. The passwords are checked against an htpasswd file, the one Apache's
+//   htpasswd tool writes, with bcrypt hashes (htpasswd -B). bcrypt salts every hash and is slow
+//   on purpose, so a leaked file doesn't give the passwords away easily
+// - Bearer: "Bearer " and a token, for scripts and other programs. The tokens are in a file of
+//   their own, with a name for each
+//
+// A request without valid credentials gets 401 Unauthorized, with a WWW-Authenticate header
+// for every scheme that would do, and the browser shows its dialog for Basic.
+//
+// Checking a secret shouldn't tell an attacker how close they got. A comparison that stops at
+// the first wrong byte takes longer the more bytes are right, so tokens are compared in full
+// and against all of them. For an unknown user a hash is checked anyway, or the quick answer
+// would tell which users exist.
+
+use crate::handler::{Middleware, Next};
+use crate::request::{PathPrefix, Request};
+use crate::response::{HeaderMap, Response, StatusCode};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Asks for credentials on some or all routes, Basic auth checked against an [`Htpasswd`] file
+/// or bearer tokens from a [`Tokens`] file.
+///
+/// Requests with valid credentials go on to the handler, which finds the user or token name
+/// in [`Request::principal`]. The others get `401 Unauthorized` with a `WWW-Authenticate`
+/// header for every scheme configured.
+///
+/// ```
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::middleware::{Auth, Htpasswd, Tokens};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let users = Htpasswd::parse("alice:$2y$05$bvIG6Nmid91Mu9RcmmWZfO5HJIMCT8riNW0hEp8f6/FuA2/mHZFpe\n")?;
+/// let tokens = Tokens::parse("deploy-bot:3f2a9c0d7e4b41b8a6c5d2e1f0a9b8c7\n")?;
+/// let auth = Auth::new("admin area")
+///     .basic(users)
+///     .bearer(tokens)
+///     .protect("/admin")
+///     .protect("/api");
+/// let router = Router::new().layer(auth);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Auth {
+    realm: String,
+    basic: Option<Htpasswd>,
+    bearer: Option<Tokens>,
+    // The WWW-Authenticate headers of a 401, and of one for a bearer token we don't know
+    challenges: HeaderMap,
+    invalid_token_challenges: HeaderMap,
+    // Empty protects everything
+    prefixes: Vec<PathPrefix>,
+}
+
+/// Users and their bcrypt password hashes, in the format of Apache's `htpasswd -B`.
+#[derive(Debug, Clone)]
+pub struct Htpasswd {
+    users: Vec<(String, String)>,
+}
+
+/// Bearer tokens, each with a name that becomes the request's principal.
+#[derive(Debug, Clone)]
+pub struct Tokens {
+    tokens: Vec<(String, Vec<u8>)>,
+}
+
+impl Auth {
+    /// Nothing accepted yet, add [`Auth::basic`], [`Auth::bearer`] or both. The realm is shown
+    /// to users in the browser's login dialog.
+    pub fn new(realm: &str) -> Auth {
+        Auth {
+            realm: realm.to_string(),
+            basic: None,
+            bearer: None,
+            challenges: HeaderMap::new(),
+            invalid_token_challenges: HeaderMap::new(),
+            prefixes: Vec::new(),
+        }
+    }
+
+    /// Accept Basic auth for the users in `users`.
+    ///
+    /// # Panics
+    ///
+    /// If the realm contains a control character, it couldn't be sent in a header.
+    pub fn basic(mut self, users: Htpasswd) -> Auth {
+        self.basic = Some(users);
+        self.with_challenges()
+    }
+
+    /// Accept the bearer tokens in `tokens`.
+    ///
+    /// # Panics
+    ///
+    /// If the realm contains a control character, it couldn't be sent in a header.
+    pub fn bearer(mut self, tokens: Tokens) -> Auth {
+        self.bearer = Some(tokens);
+        self.with_challenges()
+    }
+
+    /// Ask for credentials on `prefix` and every path below it, the way
+    /// [`Router::nest`](crate::handler::Router::nest) matches. Can be called more than once.
+    /// Without any prefix every path is protected.
+    pub fn protect(mut self, prefix: &str) -> Auth {
+        self.prefixes.push(PathPrefix::new(prefix));
+        self
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| prefix.matches(path))
+    }
+
+    // The principal for the Authorization header, Err(true) if it had a bearer token we don't
+    // know
+    fn check(&self, authorization: &str) -> Result<String, bool> {
+        let (scheme, credentials) = authorization.trim().split_once(' ').ok_or(false)?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let users = self.basic.as_ref().ok_or(false)?;
+            let decoded = BASE64.decode(credentials).map_err(|_| false)?;
+            let decoded = String::from_utf8(decoded).map_err(|_| false)?;
+            let (user, password) = decoded.split_once(':').ok_or(false)?;
+            if users.verify(user, password) {
+                return Ok(user.to_string());
+            }
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            let tokens = self.bearer.as_ref().ok_or(false)?;
+            return tokens.find(credentials).map(str::to_string).ok_or(true);
+        }
+        Err(false)
+    }
+
+    // The challenges are built once the schemes are known, so a realm that can't be a header
+    // value panics while setting up and not on every 401
+    fn with_challenges(mut self) -> Auth {
+        assert!(
+            !self.realm.chars().any(char::is_control),
+            "realm can't contain control characters"
+        );
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        self.challenges = HeaderMap::new();
+        if self.basic.is_some() {
+            let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+            self.challenges
+                .append("WWW-Authenticate", &challenge)
+                .expect("realm is a valid header value");
+        }
+        self.invalid_token_challenges = self.challenges.clone();
+        if self.bearer.is_some() {
+            let challenge = format!("Bearer realm=\"{}\"", realm);
+            self.challenges
+                .append("WWW-Authenticate", &challenge)
+                .expect("realm is a valid header value");
+            self.invalid_token_challenges
+                .append(
+                    "WWW-Authenticate",
+                    &format!("{}, error=\"invalid_token\"", challenge),
+                )
+                .expect("realm is a valid header value");
+        }
+        self
+    }
+
+    fn unauthorized(&self, invalid_token: bool) -> Response {
+        let mut response = Response::new(StatusCode::UNAUTHORIZED);
+        *response.headers_mut() = if invalid_token {
+            self.invalid_token_challenges.clone()
+        } else {
+            self.challenges.clone()
+        };
+        response
+            .header("Content-Type", "text/plain")
+            .body("authentication required")
+    }
+}
+
+impl Middleware for Auth {
+    fn call(&self, request: Request, next: Next<'_>) -> Response {
+        if !self.protects(request.path()) {
+            return next.run(request);
+        }
+        let checked = match request.header("Authorization") {
+            Some(authorization) => self.check(authorization),
+            None => Err(false),
+        };
+        match checked {
+            Ok(principal) => next.run(request.with_principal(&principal)),
+            Err(invalid_token) => self.unauthorized(invalid_token),
+        }
+    }
+}
+
+impl Htpasswd {
+    /// Read an htpasswd file, see [`Htpasswd::parse`].
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Htpasswd> {
+        Htpasswd::parse(&fs::read_to_string(path)?)
+    }
+
+    /// One `user:hash` per line, blank lines and `#` comments are skipped. Only bcrypt hashes
+    /// (`$2y$`, `$2b$`, `$2a$`) are accepted, the older htpasswd formats are an
+    /// [`io::ErrorKind::InvalidData`] error.
+    pub fn parse(contents: &str) -> io::Result<Htpasswd> {
+        let users = entries(contents)
+            .map(|(number, user, hash)| {
+                let bcrypt = ["$2y$", "$2b$", "$2a$"]
+                    .iter()
+                    .any(|prefix| hash.starts_with(prefix));
+                if !bcrypt || hash.len() != 60 {
+                    return Err(invalid(format!(
+                        "line {}: not a bcrypt hash, create it with htpasswd -B",
+                        number
+                    )));
+                }
+                Ok((user.to_string(), hash.to_string()))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Htpasswd { users })
+    }
+
+    fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.iter().find(|(known, _)| known == user) {
+            Some((_, hash)) => bcrypt::verify(password, hash).unwrap_or(false),
+            None => {
+                // As slow as a wrong password
+                if let Some((_, hash)) = self.users.first() {
+                    let _ = bcrypt::verify(password, hash);
+                }
+                false
+            }
+        }
+    }
+}
+
+impl Tokens {
+    /// Read a token file, see [`Tokens::parse`].
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Tokens> {
+        Tokens::parse(&fs::read_to_string(path)?)
+    }
+
+    /// One `name:token` per line, blank lines and `#` comments are skipped.
+    pub fn parse(contents: &str) -> io::Result<Tokens> {
+        let tokens = entries(contents)
+            .map(|(number, name, token)| {
+                if token.is_empty() || token.contains(char::is_whitespace) {
+                    return Err(invalid(format!("line {}: not a token", number)));
+                }
+                Ok((name.to_string(), token.as_bytes().to_vec()))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Tokens { tokens })
+    }
+
+    // The name of `token`. Every token is compared, all the way through
+    fn find(&self, token: &str) -> Option<&str> {
+        let mut found = None;
+        for (name, known) in &self.tokens {
+            if constant_time_eq(known, token.as_bytes()) {
+                found = Some(name.as_str());
+            }
+        }
+        found
+    }
+}
+
+// Line number, name and secret of every entry. Lines without a colon are kept with an empty
+// secret, for the caller to complain about
+fn entries(contents: &str) -> impl Iterator<Item = (usize, &str, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let (name, secret) = line.split_once(':').unwrap_or((line, ""));
+            (number, name, secret)
+        })
+}
+
+// Only the lengths can be told apart by timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,338 @@
+// Response compression
+
+// The client lists what it can decode in Accept-Encoding, e.g. "gzip, deflate, br". We pick the
+// best of what we support, compress the body and say which one we used in Content-Encoding.
+// Caches between us and the client have to keep the compressed and the plain version apart, so
+// every response that *could* have been compressed gets Vary: Accept-Encoding, whether this
+// particular one was or not.
+//
+// Not everything is worth it: a few hundred bytes don't get noticeably smaller, and JPEGs, ZIPs
+// and the like are compressed already, a second pass only costs CPU.
+
+use crate::handler::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{Body, Response, StatusCode};
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// Compresses response bodies with brotli or gzip, whichever the client prefers.
+///
+/// Bodies smaller than [`Compression::min_size`] and already compressed types (images, audio,
+/// video, archives, fonts) are sent as they are. File and chunked bodies are compressed while
+/// they are streamed.
+///
+/// ```
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::middleware::Compression;
+///
+/// let router = Router::new().layer(Compression::new().min_size(512));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u64,
+    gzip_level: u32,
+    brotli_quality: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+// Both compress into a Vec that is emptied after every chunk
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+// Content types that are compressed already. Everything else is compressed, including
+// responses without a Content-Type
+const COMPRESSED_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/octet-stream",
+    "application/pdf",
+    "application/vnd.rar",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-gzip",
+    "application/x-rar-compressed",
+    "application/x-xz",
+    "application/zip",
+    "application/zstd",
+];
+
+// Read size when streaming a file through the encoder
+const FILE_CHUNK: usize = 16 * 1024;
+
+impl Compression {
+    /// Compress bodies of at least 1 KiB, with gzip level 6 and brotli quality 5.
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            gzip_level: 6,
+            // 11 is the best but far too slow to run on every response
+            brotli_quality: 5,
+        }
+    }
+
+    /// Leave bodies smaller than `bytes` uncompressed. Chunked bodies have no size up front and
+    /// are always compressed.
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// 0 (none) to 9 (best).
+    pub fn gzip_level(mut self, level: u32) -> Compression {
+        self.gzip_level = level.min(9);
+        self
+    }
+
+    /// 0 (fastest) to 11 (best).
+    pub fn brotli_quality(mut self, quality: u32) -> Compression {
+        self.brotli_quality = quality.min(11);
+        self
+    }
+
+    fn compress(&self, response: &mut Response, encoding: Option<Encoding>) {
+        let status = response.status();
+        let headers = response.headers();
+        if !status.allows_body()
+            || status == StatusCode::PARTIAL_CONTENT
+            || headers.contains("Content-Encoding")
+            || headers.get("Content-Type").is_some_and(is_compressed_type)
+        {
+            return;
+        }
+
+        super::vary(response, "Accept-Encoding");
+
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        let too_small = match response.body_ref() {
+            Body::Empty => true,
+            Body::Bytes(bytes) => (bytes.len() as u64) < self.min_size,
+            Body::File { len, .. } => *len < self.min_size,
+            Body::Chunked(_) => false,
+        };
+        if too_small {
+            return;
+        }
+
+        let body = match response.take_body() {
+            Body::Bytes(bytes) => {
+                let mut encoder = Encoder::new(encoding, self);
+                encoder.write(&bytes);
+                let compressed = encoder.finish();
+                // Random data gets bigger, send it as it was
+                if compressed.len() >= bytes.len() {
+                    response.set_body(bytes);
+                    return;
+                }
+                Body::Bytes(compressed)
+            }
+            Body::File { file, .. } => {
+                Body::Chunked(Box::new(compress_file(file, Encoder::new(encoding, self))))
+            }
+            Body::Chunked(chunks) => Body::Chunked(Box::new(compress_chunks(
+                chunks,
+                Encoder::new(encoding, self),
+            ))),
+            Body::Empty => unreachable!("empty bodies are too small"),
+        };
+
+        response
+            .headers_mut()
+            .insert("Content-Encoding", encoding.as_str())
+            .expect("Content-Encoding header is valid");
+        response.set_body(body);
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn call(&self, request: Request, next: Next<'_>) -> Response {
+        let encoding = request.header("Accept-Encoding").and_then(negotiate);
+        let mut response = next.run(request);
+        self.compress(&mut response, encoding);
+        response
+    }
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, settings: &Compression) -> Encoder {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(settings.gzip_level),
+            )),
+            // 4096 byte buffer, 22 bit window, the values brotli's own tools use
+            Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                4096,
+                settings.brotli_quality,
+                22,
+            ))),
+        }
+    }
+
+    // Writing into a Vec can't fail, neither can the encoders on top of it
+    fn write(&mut self, data: &[u8]) {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            Encoder::Brotli(encoder) => encoder.write_all(data),
+        }
+        .expect("compressing into memory");
+    }
+
+    // Push out everything written so far, so a chunk doesn't wait for the next one
+    fn flush(&mut self) {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+        .expect("compressing into memory");
+    }
+
+    // The compressed bytes produced since the last call
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+            Encoder::Brotli(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish().expect("compressing into memory"),
+            Encoder::Brotli(encoder) => encoder.into_inner(),
+        }
+    }
+}
+
+// Pick the encoding with the highest q-value, brotli on a tie. An explicit entry wins over "*",
+// q=0 means "not this one"
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut any = None;
+
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("q") {
+                    value.trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case("br") {
+            brotli = Some(q);
+        } else if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            gzip = Some(q);
+        } else if name == "*" {
+            any = Some(q);
+        }
+    }
+
+    let brotli = brotli.or(any).unwrap_or(0.0);
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+fn is_compressed_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    match mime.split_once('/') {
+        // SVG is XML and compresses well
+        Some(("image", subtype)) => subtype != "svg+xml",
+        Some(("audio" | "video" | "font", _)) => true,
+        _ => COMPRESSED_TYPES.contains(&mime.as_str()),
+    }
+}
+
+// Read the file in FILE_CHUNK pieces and compress each one as it goes. A read error can't be
+// reported from here, it ends the body early and the client fails to decode the truncated
+// stream, much like with a dropped connection
+fn compress_file(mut file: File, encoder: Encoder) -> impl Iterator<Item = Vec<u8>> + Send {
+    let mut encoder = Some(encoder);
+    let mut buffer = vec![0; FILE_CHUNK];
+
+    std::iter::from_fn(move || loop {
+        let active = encoder.as_mut()?;
+        match file.read(&mut buffer) {
+            Ok(0) => return encoder.take().map(Encoder::finish),
+            Ok(n) => {
+                active.write(&buffer[..n]);
+                // Small reads may not produce output yet, keep reading until they do
+                let compressed = active.take();
+                if !compressed.is_empty() {
+                    return Some(compressed);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => {
+                encoder = None;
+                return None;
+            }
+        }
+    })
+}
+
+// Flush after every chunk: chunked bodies are often produced bit by bit (progress output,
+// events) and the client should see each piece when it is sent, not when the encoder's buffer
+// happens to fill up
+fn compress_chunks<I>(chunks: I, encoder: Encoder) -> impl Iterator<Item = Vec<u8>> + Send
+where
+    I: Iterator<Item = Vec<u8>> + Send,
+{
+    let mut chunks = chunks.fuse();
+    let mut encoder = Some(encoder);
+
+    std::iter::from_fn(move || {
+        let active = encoder.as_mut()?;
+        match chunks.next() {
+            Some(chunk) => {
+                active.write(&chunk);
+                active.flush();
+                Some(active.take())
+            }
+            None => encoder.take().map(Encoder::finish),
+        }
+    })
+}
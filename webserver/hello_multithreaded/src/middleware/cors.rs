@@ -0,0 +1,272 @@
+// Cross-origin resource sharing (CORS)
+
+// A page from https://app.example.com that calls this server with fetch() is making a
+// cross-origin request, and the browser only lets the page see the response if the server says
+// so: Access-Control-Allow-Origin has to name the page's origin (or be *). The browser sends
+// the origin along in the Origin header.
+//
+// Anything beyond a plain GET or form POST, a PUT, a JSON body, an Authorization header, is
+// asked about first with a preflight: an OPTIONS request with Access-Control-Request-Method and
+// -Headers saying what the real request will look like. The preflight never reaches a route,
+// it is answered here, and the real request only follows if the answer allows it.
+//
+// Cookies and HTTP auth only go along with credentials allowed, and then * won't do, the
+// origin has to be named. Echoing whatever Origin comes in would do it, but that lets any site
+// on the internet make requests with the user's cookies and read the answers, so * together
+// with credentials is a mistake in the configuration and panics. With a list of origins the
+// answer depends on the Origin header, so responses get Vary: Origin for caches.
+
+use crate::handler::{Middleware, Next};
+use crate::request::{Method, Request};
+use crate::response::{is_header_name, Response, StatusCode};
+use std::time::Duration;
+
+/// Lets browser apps on other origins call the server.
+///
+/// Preflight requests (`OPTIONS` with `Access-Control-Request-Method`) are answered by the
+/// middleware itself: `204 No Content` if the origin, method and headers are allowed, `403
+/// Forbidden` otherwise. Other requests go on to the handler and get the CORS headers added
+/// when their origin is allowed. Requests without an `Origin` header aren't cross-origin and
+/// pass as they are.
+///
+/// ```
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::middleware::Cors;
+/// use hello_multithreaded::request::Method;
+/// use std::time::Duration;
+///
+/// let cors = Cors::new()
+///     .allow_origin("https://app.example.com")
+///     .allow_methods(&[Method::Get, Method::Post, Method::Delete])
+///     .allow_headers(&["Content-Type", "Authorization"])
+///     .allow_credentials(true)
+///     .max_age(Duration::from_secs(600));
+/// let router = Router::new().layer(cors);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cors {
+    // Empty allows nothing, "*" anything
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    exposed: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// No origins allowed yet. Once they are, `GET`, `HEAD` and `POST` without extra headers.
+    pub fn new() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: Vec::new(),
+            exposed: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allow requests from `origin`, e.g. `https://app.example.com` (scheme, host and port if
+    /// it isn't the default). Can be called more than once. `*` allows every origin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `origin` is `*` and credentials are allowed.
+    pub fn allow_origin(mut self, origin: &str) -> Cors {
+        assert!(
+            origin != "*" || !self.credentials,
+            "CORS with credentials must name its origins, not *"
+        );
+        self.origins
+            .push(origin.trim_end_matches('/').to_ascii_lowercase());
+        self
+    }
+
+    /// The methods requests may use, replacing the default ones.
+    pub fn allow_methods(mut self, methods: &[Method]) -> Cors {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// The request headers the client may send, besides the ones browsers always allow
+    /// (`Accept`, `Content-Language`, form `Content-Type`s). `*` allows any.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of `headers` isn't a valid header name.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Cors {
+        self.headers = header_names(headers);
+        self
+    }
+
+    /// Response headers the page may read, besides the few every page gets (`Content-Type`,
+    /// `Cache-Control`, ...).
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of `headers` isn't a valid header name.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Cors {
+        self.exposed = header_names(headers);
+        self
+    }
+
+    /// Let requests carry cookies and HTTP auth, and let the page see responses to them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `credentials` is true and every origin is allowed with `*`.
+    pub fn allow_credentials(mut self, credentials: bool) -> Cors {
+        assert!(
+            !credentials || !self.any_origin(),
+            "CORS with credentials must name its origins, not *"
+        );
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer. Browsers cap it, Chrome at 2 hours.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn any_origin(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin()
+            || self
+                .origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(name))
+    }
+
+    // The same answer for every origin, *, needs no Vary
+    fn varies(&self) -> bool {
+        !self.any_origin()
+    }
+
+    // Access-Control-Allow-Origin and -Credentials for a request from `origin`
+    fn allow(&self, response: &mut Response, origin: &str) {
+        let allowed = if self.any_origin() { "*" } else { origin };
+        // The origin comes from the client, a broken one is just not echoed
+        let headers = response.headers_mut();
+        if headers
+            .insert("Access-Control-Allow-Origin", allowed)
+            .is_err()
+        {
+            return;
+        }
+        if self.credentials {
+            headers
+                .insert("Access-Control-Allow-Credentials", "true")
+                .expect("valid header");
+        }
+    }
+
+    fn preflight(&self, request: &Request, origin: &str, method: &str) -> Response {
+        let requested: Vec<&str> = request
+            .headers()
+            .get_all("Access-Control-Request-Headers")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let allowed = self.allows_origin(origin)
+            && self
+                .methods
+                .iter()
+                .any(|allowed| allowed.as_str() == method)
+            && requested.iter().all(|name| self.allows_header(name));
+
+        let mut response = if allowed {
+            Response::new(StatusCode::NO_CONTENT)
+        } else {
+            Response::new(StatusCode::FORBIDDEN)
+        };
+        if self.varies() {
+            super::vary(&mut response, "Origin");
+        }
+        super::vary(&mut response, "Access-Control-Request-Method");
+        super::vary(&mut response, "Access-Control-Request-Headers");
+        if !allowed {
+            return response;
+        }
+
+        self.allow(&mut response, origin);
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        let headers = response.headers_mut();
+        headers
+            .insert("Access-Control-Allow-Methods", &methods.join(", "))
+            .expect("valid header");
+        if !requested.is_empty() {
+            // Echoed rather than *, which browsers ignore for requests with credentials. They
+            // came in a header value, so they fit into one
+            let _ = headers.insert("Access-Control-Allow-Headers", &requested.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            headers
+                .insert("Access-Control-Max-Age", &max_age.as_secs().to_string())
+                .expect("valid header");
+        }
+        response
+    }
+}
+
+// The names go into a header of every allowed response, a bad one would break all of them
+fn header_names(headers: &[&str]) -> Vec<String> {
+    headers
+        .iter()
+        .map(|name| {
+            assert!(is_header_name(name), "invalid header name {:?}", name);
+            name.to_string()
+        })
+        .collect()
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn call(&self, request: Request, next: Next<'_>) -> Response {
+        let Some(origin) = request.header("Origin").map(str::to_string) else {
+            let mut response = next.run(request);
+            if self.varies() {
+                super::vary(&mut response, "Origin");
+            }
+            return response;
+        };
+
+        if *request.method() == Method::Options {
+            if let Some(method) = request.header("Access-Control-Request-Method") {
+                return self.preflight(&request, &origin, method.trim());
+            }
+        }
+
+        let mut response = next.run(request);
+        if self.varies() {
+            super::vary(&mut response, "Origin");
+        }
+        if self.allows_origin(&origin) {
+            self.allow(&mut response, &origin);
+            if !self.exposed.is_empty() {
+                response
+                    .headers_mut()
+                    .insert("Access-Control-Expose-Headers", &self.exposed.join(", "))
+                    .expect("valid header");
+            }
+        }
+        response
+    }
+}
@@ -0,0 +1,188 @@
+// Rate limiting per client IP
+
+// A token bucket per client: it holds up to `burst` tokens, every request takes one, and tokens
+// come back at a steady rate. A client can send a burst of requests at once, and after that as
+// many as the rate allows. One that has run out gets 429 Too Many Requests, with a Retry-After
+// that says when the next token is there.
+//
+// Buckets aren't refilled by a timer, that would mean touching every one of them all the time.
+// Each bucket remembers when it was last used, and the tokens earned since are added when the
+// client comes back. A bucket that would be full by now is no different from one that doesn't
+// exist, so those are swept out once the map grows.
+
+use crate::handler::{Middleware, Next};
+use crate::request::{PathPrefix, Request};
+use crate::response::{Response, StatusCode};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Limits how many requests each client IP may send, with a token bucket per client.
+///
+/// Every path gets the rate from [`RateLimit::new`], unless a [`RateLimit::route`] has its own.
+/// A client over the limit gets `429 Too Many Requests` with a `Retry-After` header. Requests
+/// without a peer address (not from a server) are let through.
+///
+/// ```
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::middleware::{Rate, RateLimit};
+///
+/// let limit = RateLimit::new(Rate::per_second(10).burst(20))
+///     .route("/login", Rate::per_minute(5));
+/// let router = Router::new().layer(limit);
+/// ```
+#[derive(Debug)]
+pub struct RateLimit {
+    default: Rate,
+    // Prefix, rate
+    routes: Vec<(PathPrefix, Rate)>,
+    buckets: Mutex<Buckets>,
+}
+
+/// How fast a client may send requests, see [`RateLimit`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    // Tokens earned per second
+    refill: f64,
+    burst: u32,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    // Keyed by client and rule: 0 is the default rate, the routes count up from 1
+    map: HashMap<(IpAddr, usize), Bucket>,
+    // Full buckets are swept out when the map gets this big
+    sweep_at: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+const MIN_SWEEP: usize = 1024;
+
+impl Rate {
+    /// `requests` per `period`, with a burst of `requests`.
+    ///
+    /// Panics if `requests` or `period` is zero.
+    pub fn new(requests: u32, period: Duration) -> Rate {
+        assert!(requests > 0, "a rate needs at least one request");
+        assert!(!period.is_zero(), "a rate needs a period");
+        Rate {
+            refill: requests as f64 / period.as_secs_f64(),
+            burst: requests,
+        }
+    }
+
+    pub fn per_second(requests: u32) -> Rate {
+        Rate::new(requests, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(requests: u32) -> Rate {
+        Rate::new(requests, Duration::from_secs(60))
+    }
+
+    /// How many requests a client may send at once, after it has been quiet for a while. At
+    /// least 1.
+    pub fn burst(mut self, requests: u32) -> Rate {
+        self.burst = requests.max(1);
+        self
+    }
+
+    // The bucket's tokens at `now`
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let earned = now.saturating_duration_since(bucket.updated).as_secs_f64() * self.refill;
+        (bucket.tokens + earned).min(self.burst as f64)
+    }
+}
+
+impl RateLimit {
+    /// Limit every path to `rate` per client.
+    pub fn new(rate: Rate) -> RateLimit {
+        RateLimit {
+            default: rate,
+            routes: Vec::new(),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                sweep_at: MIN_SWEEP,
+            }),
+        }
+    }
+
+    /// Limit `prefix` and every path below it to `rate` instead, the way
+    /// [`Router::nest`](crate::handler::Router::nest) matches. Requests there have a bucket of
+    /// their own, they don't count towards the default rate.
+    ///
+    /// The first matching route wins.
+    pub fn route(mut self, prefix: &str, rate: Rate) -> RateLimit {
+        self.routes.push((PathPrefix::new(prefix), rate));
+        self
+    }
+
+    // Which bucket a request for `path` takes its token from
+    fn rule(&self, path: &str) -> usize {
+        self.routes
+            .iter()
+            .position(|(prefix, _)| prefix.matches(path))
+            .map_or(0, |index| index + 1)
+    }
+
+    fn rate(&self, rule: usize) -> Rate {
+        match rule {
+            0 => self.default,
+            _ => self.routes[rule - 1].1,
+        }
+    }
+
+    // Take a token, or say how long until there is one
+    fn take(&self, client: IpAddr, rule: usize, now: Instant) -> Result<(), Duration> {
+        let rate = self.rate(rule);
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.map.len() >= buckets.sweep_at && !buckets.map.contains_key(&(client, rule)) {
+            buckets.map.retain(|&(_, rule), bucket| {
+                let rate = self.rate(rule);
+                rate.refilled(bucket, now) < rate.burst as f64
+            });
+            // With lots of busy clients, sweeping again on the next new one would be a waste
+            buckets.sweep_at = (buckets.map.len() * 2).max(MIN_SWEEP);
+        }
+
+        let bucket = buckets.map.entry((client, rule)).or_insert(Bucket {
+            tokens: rate.burst as f64,
+            updated: now,
+        });
+        bucket.tokens = rate.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate.refill))
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn call(&self, request: Request, next: Next<'_>) -> Response {
+        let Some(peer_addr) = request.peer_addr() else {
+            return next.run(request);
+        };
+        let rule = self.rule(request.path());
+        match self.take(peer_addr.ip(), rule, Instant::now()) {
+            Ok(()) => next.run(request),
+            Err(wait) => too_many_requests(wait),
+        }
+    }
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    // Whole seconds, rounded up: a client that comes back a bit early gets another 429
+    let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+    Response::new(StatusCode::TOO_MANY_REQUESTS)
+        .header("Retry-After", &seconds.to_string())
+        .header("Content-Type", "text/plain")
+        .body("Too many requests, please try again later.")
+}
@@ -0,0 +1,146 @@
+// Security headers
+
+// Response headers that make the browser stricter with the page they come with:
+//
+// - Content-Security-Policy says where scripts, styles, images, frames may come from, which
+//   takes most of the sting out of an injected <script>
+// - Strict-Transport-Security (HSTS) makes the browser use HTTPS for the host from then on,
+//   even when the user types http://. Browsers only take it from HTTPS responses
+// - X-Content-Type-Options: nosniff stops the browser from guessing a script or a stylesheet
+//   in a response that says it's something else
+// - Referrer-Policy limits how much of the URL other sites see in the Referer header
+//
+// Different parts of a site want different policies, an admin UI a tighter CSP than the API
+// docs with their inline examples, so routes can have their own set. A header the handler set
+// itself always wins.
+
+use crate::handler::{Middleware, Next};
+use crate::request::{PathPrefix, Request};
+use crate::response::{HeaderMap, Response};
+use std::time::Duration;
+
+/// Adds security headers to every response, or a different set per route.
+///
+/// By default that's `X-Content-Type-Options: nosniff` and `Referrer-Policy:
+/// strict-origin-when-cross-origin`. A response that has one of the headers already keeps its
+/// own value.
+///
+/// ```
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::middleware::SecurityHeaders;
+/// use std::time::Duration;
+///
+/// let headers = SecurityHeaders::new()
+///     .content_security_policy("default-src 'self'")
+///     .strict_transport_security(Duration::from_secs(365 * 24 * 60 * 60), true)
+///     .route(
+///         "/admin",
+///         SecurityHeaders::new()
+///             .content_security_policy("default-src 'none'; script-src 'self'")
+///             .referrer_policy("no-referrer"),
+///     );
+/// let router = Router::new().layer(headers);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    headers: HeaderMap,
+    // Prefix, the headers for it
+    routes: Vec<(PathPrefix, HeaderMap)>,
+}
+
+impl SecurityHeaders {
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders {
+            headers: HeaderMap::new(),
+            routes: Vec::new(),
+        }
+        .no_sniff(true)
+        .referrer_policy("strict-origin-when-cross-origin")
+    }
+
+    /// Set `Content-Security-Policy`, e.g. `default-src 'self'`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the policy contains control characters like CR or LF.
+    pub fn content_security_policy(self, policy: &str) -> SecurityHeaders {
+        self.set("Content-Security-Policy", policy)
+    }
+
+    /// Set `Strict-Transport-Security`: browsers use only HTTPS for this host for `max_age`,
+    /// and for its subdomains too with `include_subdomains`.
+    pub fn strict_transport_security(
+        self,
+        max_age: Duration,
+        include_subdomains: bool,
+    ) -> SecurityHeaders {
+        let mut value = format!("max-age={}", max_age.as_secs());
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        self.set("Strict-Transport-Security", &value)
+    }
+
+    /// Send `X-Content-Type-Options: nosniff` or not. On by default.
+    pub fn no_sniff(mut self, enabled: bool) -> SecurityHeaders {
+        if enabled {
+            self.set("X-Content-Type-Options", "nosniff")
+        } else {
+            self.headers.remove("X-Content-Type-Options");
+            self
+        }
+    }
+
+    /// Set `Referrer-Policy`, e.g. `no-referrer`. `strict-origin-when-cross-origin` by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the policy contains control characters like CR or LF.
+    pub fn referrer_policy(self, policy: &str) -> SecurityHeaders {
+        self.set("Referrer-Policy", policy)
+    }
+
+    /// Use the headers of `headers` instead for `prefix` and every path below it, the way
+    /// [`Router::nest`](crate::handler::Router::nest) matches. The first matching route wins,
+    /// routes of `headers` itself are ignored.
+    pub fn route(mut self, prefix: &str, headers: SecurityHeaders) -> SecurityHeaders {
+        self.routes.push((PathPrefix::new(prefix), headers.headers));
+        self
+    }
+
+    fn set(mut self, name: &str, value: &str) -> SecurityHeaders {
+        self.headers
+            .insert(name, value)
+            .unwrap_or_else(|e| panic!("invalid {} value: {}", name, e));
+        self
+    }
+
+    fn headers_for(&self, path: &str) -> &HeaderMap {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| prefix.matches(path))
+            .map_or(&self.headers, |(_, headers)| headers)
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn call(&self, request: Request, next: Next<'_>) -> Response {
+        let headers = self.headers_for(request.path());
+        let mut response = next.run(request);
+        for (name, value) in headers.iter() {
+            if !response.headers().contains(name) {
+                response
+                    .headers_mut()
+                    .insert(name, value)
+                    .expect("checked when it was set");
+            }
+        }
+        response
+    }
+}
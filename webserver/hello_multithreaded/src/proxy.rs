@@ -0,0 +1,427 @@
+// Reverse proxy
+
+// A Proxy is a Handler that answers requests by passing them on to another HTTP server, the
+// upstream, and passing its response back. Mounted with Router::nest it takes over a part of the
+// site, e.g. everything under /api goes to an application server while the rest is served
+// here:
+//
+//     client -> this server -> /api/users -> Proxy -> upstream /users
+//
+// Every request gets a fresh connection to the upstream with Connection: close, there is no
+// connection pool. With more than one upstream, requests take turns (round-robin). Health
+// checks, if enabled, run on a thread of their own and take upstreams that fail them out of
+// the rotation until they pass again.
+//
+// Bodies are streamed both ways: the request body from wherever the server put it (memory or a
+// temp file), the response body from the upstream socket to the client chunk by chunk. Small
+// responses with a Content-Length are read in one piece instead, so they keep their length and
+// an upstream failing halfway through is still a 502. A streamed response that fails halfway
+// just ends early, the status is long gone by then.
+//
+// Upgrades (WebSocket) aren't proxied.
+
+use crate::client::http::{self, ChunkedReader, Framing};
+use crate::handler::Handler;
+use crate::request::{PathPrefix, Request};
+use crate::response::{Body, HeaderMap, Response, StatusCode};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+// Responses up to this size are read completely before they are passed on
+const BUFFER_LIMIT: u64 = 64 * 1024;
+const CHUNK: usize = 16 * 1024;
+
+// Headers that describe one connection, not the message. They aren't passed on in either
+// direction, RFC 9110 7.6.1. Content-Length and Expect are ours to set or answer
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Content-Length",
+    "Expect",
+];
+
+/// A [`Handler`] that forwards requests to one or more upstream servers.
+///
+/// ```no_run
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::proxy::Proxy;
+/// use std::time::Duration;
+///
+/// // /api/users is forwarded as /users, alternating between two upstreams
+/// let proxy = Proxy::new(["127.0.0.1:8081", "127.0.0.1:8082"])
+///     .unwrap()
+///     .rewrite("/api", "/")
+///     .health_check("/health", Duration::from_secs(10));
+/// let app = Router::new().nest("/api", proxy);
+/// ```
+///
+/// Requests get `X-Forwarded-For` (the client's address, added to the ones earlier proxies put
+/// there) and `X-Forwarded-Host`. If no upstream can be reached the client gets `502 Bad
+/// Gateway`, or `504 Gateway Timeout` if the upstream took too long.
+pub struct Proxy {
+    shared: Arc<Shared>,
+    rewrite: Option<(PathPrefix, String)>,
+    connect_timeout: Duration,
+    timeout: Duration,
+}
+
+// Shared with the health check thread
+struct Shared {
+    upstreams: Vec<Upstream>,
+    // Round-robin counter
+    next: AtomicUsize,
+    // Without health checks an upstream that failed would never be let back in, so it is only
+    // taken out of the rotation when something checks on it
+    checked: AtomicBool,
+}
+
+struct Upstream {
+    addr: SocketAddr,
+    healthy: AtomicBool,
+}
+
+impl Proxy {
+    /// Forward to `upstreams`, taking turns. Each address is resolved once, here.
+    pub fn new<A: ToSocketAddrs>(upstreams: impl IntoIterator<Item = A>) -> io::Result<Proxy> {
+        let mut resolved = Vec::new();
+        for upstream in upstreams {
+            let addr = upstream.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "upstream has no address")
+            })?;
+            resolved.push(Upstream {
+                addr,
+                healthy: AtomicBool::new(true),
+            });
+        }
+        if resolved.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a proxy needs at least one upstream",
+            ));
+        }
+
+        Ok(Proxy {
+            shared: Arc::new(Shared {
+                upstreams: resolved,
+                next: AtomicUsize::new(0),
+                checked: AtomicBool::new(false),
+            }),
+            rewrite: None,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+        })
+    }
+
+    /// Replace the path prefix `from` with `to` before forwarding: with `("/api", "/")`,
+    /// `/api/users?page=2` goes upstream as `/users?page=2`, with `("/api", "/v2")` as
+    /// `/v2/users?page=2`. Paths outside `from` are forwarded as they are.
+    pub fn rewrite(mut self, from: &str, to: &str) -> Proxy {
+        self.rewrite = Some((PathPrefix::new(from), to.trim_end_matches('/').to_string()));
+        self
+    }
+
+    /// How long connecting to an upstream may take. Defaults to 5 seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Proxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a single read from or write to an upstream may block, waiting for its response
+    /// included. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send `GET path` to every upstream every `interval`, starting now. Upstreams that don't
+    /// answer with a 2xx or 3xx get no more requests until they do. Without health checks
+    /// every upstream stays in the rotation, up or down.
+    ///
+    /// The checks run on a thread of their own that ends once the proxy is dropped.
+    pub fn health_check(self, path: &str, interval: Duration) -> Proxy {
+        self.shared.checked.store(true, Ordering::SeqCst);
+        let shared = Arc::downgrade(&self.shared);
+        let path = path.to_string();
+        let timeout = self.connect_timeout.min(interval);
+        if let Err(e) = thread::Builder::new()
+            .name("proxy-health".to_string())
+            .spawn(move || health_checks(shared, &path, interval, timeout))
+        {
+            eprintln!("Failed to spawn health check thread: {}", e);
+        }
+        self
+    }
+
+    // The target to ask the upstream for
+    fn rewritten(&self, target: &str) -> String {
+        let Some((from, to)) = &self.rewrite else {
+            return target.to_string();
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+        // A whole segment: /api matches /api and /api/users, not /apis
+        let Some(rest) = from.strip(path) else {
+            return target.to_string();
+        };
+
+        let mut rewritten = format!("{}{}", to, rest);
+        if rewritten.is_empty() {
+            rewritten.push('/');
+        }
+        if let Some(query) = query {
+            rewritten.push('?');
+            rewritten.push_str(query);
+        }
+        rewritten
+    }
+
+    // The next healthy upstream that takes the connection, trying each one at most once
+    fn connect(&self) -> Result<(TcpStream, SocketAddr), StatusCode> {
+        let upstreams = &self.shared.upstreams;
+        let start = self.shared.next.fetch_add(1, Ordering::Relaxed);
+        let mut status = StatusCode::BAD_GATEWAY;
+
+        for i in 0..upstreams.len() {
+            let upstream = &upstreams[(start + i) % upstreams.len()];
+            if !upstream.healthy.load(Ordering::SeqCst) {
+                continue;
+            }
+            match TcpStream::connect_timeout(&upstream.addr, self.connect_timeout) {
+                Ok(stream) => return Ok((stream, upstream.addr)),
+                Err(e) => {
+                    eprintln!("Failed to connect to upstream {}: {}", upstream.addr, e);
+                    if is_timeout(&e) {
+                        status = StatusCode::GATEWAY_TIMEOUT;
+                    }
+                    // Nothing was sent yet, so the next upstream can have the request
+                    if self.shared.checked.load(Ordering::SeqCst) {
+                        upstream.healthy.store(false, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+        Err(status)
+    }
+
+    fn forward(
+        &self,
+        stream: TcpStream,
+        upstream: SocketAddr,
+        request: &Request,
+    ) -> io::Result<Response> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let body = request.body();
+        let mut headers = forwarded_headers(request);
+        // The client's Host is passed on, one built in code may not have any
+        if !headers.contains("Host") {
+            headers
+                .insert("Host", &upstream.to_string())
+                .expect("valid header");
+        }
+        headers.insert("Connection", "close").expect("valid header");
+        if let Some(len) = http::content_length(request.method().as_str(), body) {
+            headers
+                .insert("Content-Length", &len.to_string())
+                .expect("valid header");
+        }
+        http::write_request(
+            &mut BufWriter::new(&stream),
+            request.method().as_str(),
+            &self.rewritten(request.target()),
+            &headers,
+            body,
+        )?;
+
+        let mut input = BufReader::new(stream);
+        let (status, upstream_headers) = http::read_response_head(&mut input)?;
+        let framing = http::framing(
+            request.method().as_str() == "HEAD",
+            status,
+            &upstream_headers,
+        )?;
+
+        let mut response = Response::new(status);
+        *response.headers_mut() = end_to_end(&upstream_headers);
+
+        let body: Box<dyn Read + Send> = match framing {
+            Framing::None => return Ok(response),
+            Framing::Length(len) if len <= BUFFER_LIMIT => {
+                let mut bytes = Vec::with_capacity(len as usize);
+                input.take(len).read_to_end(&mut bytes)?;
+                if bytes.len() as u64 != len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "upstream sent a short body",
+                    ));
+                }
+                return Ok(response.body(bytes));
+            }
+            Framing::Length(len) => Box::new(input.take(len)),
+            Framing::Chunked => Box::new(ChunkedReader::new(input)),
+            Framing::Close => Box::new(input),
+        };
+        Ok(response.body(Body::chunked(Chunks { body, upstream })))
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: Request) -> Response {
+        let (stream, upstream) = match self.connect() {
+            Ok(connected) => connected,
+            Err(status) => return Response::new(status),
+        };
+        match self.forward(stream, upstream, &request) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Upstream {} failed: {}", upstream, e);
+                Response::new(if is_timeout(&e) {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::BAD_GATEWAY
+                })
+            }
+        }
+    }
+}
+
+// The request's headers for the upstream: hop-by-hop ones taken out, X-Forwarded-* put in
+fn forwarded_headers(request: &Request) -> HeaderMap {
+    let mut headers = end_to_end(request.headers());
+
+    if let Some(peer_addr) = request.peer_addr() {
+        // Every hop so far, from all the lines the header came in
+        let mut hops: Vec<String> = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .map(str::to_string)
+            .collect();
+        hops.push(peer_addr.ip().to_string());
+        let forwarded_for = hops.join(", ");
+        headers
+            .insert("X-Forwarded-For", &forwarded_for)
+            .expect("valid header");
+    }
+    if let Some(host) = request.header("Host") {
+        if !headers.contains("X-Forwarded-Host") {
+            headers
+                .insert("X-Forwarded-Host", host)
+                .expect("parsed headers are valid");
+        }
+    }
+    headers
+}
+
+// `headers` without the hop-by-hop ones, and without the ones Connection names as hop-by-hop
+fn end_to_end(headers: &HeaderMap) -> HeaderMap {
+    let listed: Vec<&str> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    let mut kept = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let hop_by_hop = HOP_BY_HOP.iter().chain(&listed);
+        if !hop_by_hop
+            .into_iter()
+            .any(|hop| hop.eq_ignore_ascii_case(name))
+        {
+            kept.append(name, value).expect("valid headers stay valid");
+        }
+    }
+    kept
+}
+
+// Unix reports a timeout as WouldBlock, Windows as TimedOut
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// The upstream's response body as chunks for Body::chunked
+struct Chunks {
+    body: Box<dyn Read + Send>,
+    upstream: SocketAddr,
+}
+
+impl Iterator for Chunks {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut chunk = vec![0; CHUNK];
+        loop {
+            match self.body.read(&mut chunk) {
+                Ok(0) => return None,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    return Some(chunk);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    eprintln!(
+                        "Upstream {} failed in the middle of a response: {}",
+                        self.upstream, e
+                    );
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+fn health_checks(shared: Weak<Shared>, path: &str, interval: Duration, timeout: Duration) {
+    // Holding on to the Arc only while checking, so the proxy can go away in between
+    while let Some(shared) = shared.upgrade() {
+        for upstream in &shared.upstreams {
+            let healthy = check(upstream.addr, path, timeout).unwrap_or(false);
+            if upstream.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+                let state = if healthy {
+                    "healthy again"
+                } else {
+                    "unhealthy"
+                };
+                eprintln!("Upstream {} is {}", upstream.addr, state);
+            }
+        }
+        drop(shared);
+        thread::sleep(interval);
+    }
+}
+
+fn check(addr: SocketAddr, path: &str, timeout: Duration) -> io::Result<bool> {
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut headers = HeaderMap::new();
+    headers
+        .insert("Host", &addr.to_string())
+        .expect("valid header");
+    headers.insert("Connection", "close").expect("valid header");
+    http::write_request(
+        &mut BufWriter::new(&stream),
+        "GET",
+        path,
+        &headers,
+        &Default::default(),
+    )?;
+
+    let (status, _) = http::read_response_head(&mut BufReader::new(stream))?;
+    Ok((200..400).contains(&status.as_u16()))
+}
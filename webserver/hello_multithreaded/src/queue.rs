@@ -0,0 +1,335 @@
+// A bounded job queue
+
+// mpsc::channel is unbounded, so during a traffic spike every incoming connection is queued and
+// memory grows without limit. mpsc::sync_channel would give us a bound, but it can only block or
+// fail when full, not drop the oldest job. So the queue is a VecDeque behind a Mutex, with two
+// Condvars: workers wait on not_empty, blocked producers wait on not_full.
+//
+// This is the scheduler's global queue (the "injector") that jobs from outside the pool go to.
+// Workers don't pop single jobs off it, they move a batch into their own deque, see scheduler.rs.
+//
+// Inside, there is one FIFO lane per Priority. Batches are taken from the most urgent lane
+// first, and high priority jobs are also handed out one at a time (take_high) to workers that
+// still have a backlog of their own, so a health check doesn't wait behind a batch of slow
+// requests. An atomic count of high priority jobs lets workers skip the lock when there are none.
+
+use crate::{ExecuteError, Job};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// What [`ThreadPool::execute`](crate::ThreadPool::execute) does when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until a worker takes a job off the queue.
+    Block,
+    /// Return [`ExecuteError::QueueFull`] right away, the new job is dropped.
+    Reject,
+    /// Drop the job that has waited longest to make room for the new one.
+    ///
+    /// Only jobs with the same or a lower [`Priority`] than the new job are dropped. If every
+    /// queued job is more urgent, the new job is rejected with [`ExecuteError::QueueFull`]
+    /// instead.
+    DropOldest,
+}
+
+/// How urgent a job is, see
+/// [`ThreadPool::execute_with_priority`](crate::ThreadPool::execute_with_priority).
+///
+/// Waiting jobs are started in priority order, and in submission order within one priority.
+/// A job that is already running is never interrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Jumps ahead of everything else, e.g. health checks.
+    High,
+    /// What [`ThreadPool::execute`](crate::ThreadPool::execute) uses.
+    #[default]
+    Normal,
+    /// Runs when nothing more urgent is waiting, e.g. slow or background work.
+    Low,
+}
+
+// Lane indices follow the declaration order above, most urgent first
+const LANES: usize = 3;
+
+// A job on its way to a worker. The timestamp feeds the queue wait histogram
+pub(crate) struct Task {
+    pub(crate) job: Job,
+    pub(crate) queued_at: Instant,
+    pub(crate) priority: Priority,
+}
+
+impl Task {
+    pub(crate) fn new(job: Job, priority: Priority) -> Task {
+        Task {
+            job,
+            queued_at: Instant::now(),
+            priority,
+        }
+    }
+}
+
+// Why JobQueue::park returned
+pub(crate) enum Park {
+    Woken,
+    TimedOut,
+    // Closed and empty, nothing left for the worker to do
+    Closed,
+}
+
+pub(crate) struct JobQueue {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    // None means unbounded, like the mpsc channel this replaces
+    capacity: Option<usize>,
+    overflow: OverflowPolicy,
+    // Mirrors the length of the high priority lane, read without the lock
+    high: AtomicUsize,
+}
+
+struct State {
+    lanes: [VecDeque<Task>; LANES],
+    // Jobs in all lanes together
+    len: usize,
+    closed: bool,
+    // Condvar::notify_one makes a syscall even when nobody waits, counting the waiters lets
+    // push and take_batch skip it in the common case
+    parked_workers: usize,
+    blocked_producers: usize,
+}
+
+impl JobQueue {
+    pub(crate) fn new(capacity: Option<usize>, overflow: OverflowPolicy) -> JobQueue {
+        JobQueue {
+            state: Mutex::new(State {
+                lanes: Default::default(),
+                len: 0,
+                closed: false,
+                parked_workers: 0,
+                blocked_producers: 0,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            overflow,
+            high: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn push(&self, task: Task) -> Result<(), ExecuteError> {
+        let mut state = self.lock();
+
+        // Dropped after the lock is released, a job's Drop may do real work (the webserver
+        // answers 503 when a connection is dropped)
+        let mut dropped = None;
+
+        loop {
+            if state.closed {
+                return Err(ExecuteError::ShuttingDown);
+            }
+            if !self.is_full(&state) {
+                break;
+            }
+            match self.overflow {
+                OverflowPolicy::Block => {
+                    state.blocked_producers += 1;
+                    state = self
+                        .not_full
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    state.blocked_producers -= 1;
+                }
+                OverflowPolicy::Reject => return Err(ExecuteError::QueueFull),
+                OverflowPolicy::DropOldest => {
+                    // The least urgent lane that has jobs, as long as it isn't more urgent than
+                    // the new job
+                    let lane = (task.priority as usize..LANES)
+                        .rev()
+                        .find(|&lane| !state.lanes[lane].is_empty())
+                        .ok_or(ExecuteError::QueueFull)?;
+                    dropped = self.pop(&mut state, lane);
+                    break;
+                }
+            }
+        }
+
+        self.push_lane(&mut state, task);
+        let wake = state.parked_workers > 0;
+        drop(state);
+        if wake {
+            self.not_empty.notify_one();
+        }
+        drop(dropped);
+
+        Ok(())
+    }
+
+    // For jobs that must not block or fail because the queue is full: jobs submitted from inside
+    // a worker and timers that came due. Returns the task if the queue is closed
+    pub(crate) fn push_unbounded(&self, task: Task) -> Result<(), Task> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(task);
+        }
+
+        self.push_lane(&mut state, task);
+        let wake = state.parked_workers > 0;
+        drop(state);
+        if wake {
+            self.not_empty.notify_one();
+        }
+        Ok(())
+    }
+
+    // Move up to `max` jobs into a worker's deque without blocking, most urgent first. Returns
+    // how many were moved
+    pub(crate) fn take_batch(&self, max: usize, into: &mut VecDeque<Task>) -> usize {
+        let mut state = self.lock();
+        let mut count = 0;
+        for lane in 0..LANES {
+            while count < max {
+                match self.pop(&mut state, lane) {
+                    Some(task) => into.push_back(task),
+                    None => break,
+                }
+                count += 1;
+            }
+        }
+        self.wake_producers(state, count)
+    }
+
+    // The most urgent job, for workers of a bounded pool. They take jobs one at a time, see
+    // Scheduler::next_job
+    pub(crate) fn take(&self) -> Option<Task> {
+        let mut state = self.lock();
+        let task = (0..LANES).find_map(|lane| self.pop(&mut state, lane));
+        self.wake_producers(state, task.is_some() as usize);
+        task
+    }
+
+    // One high priority job, if there is any. Checked by workers before their own deque
+    pub(crate) fn take_high(&self) -> Option<Task> {
+        if self.high.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        let mut state = self.lock();
+        let task = self.pop(&mut state, Priority::High as usize);
+        self.wake_producers(state, task.is_some() as usize);
+        task
+    }
+
+    // Put the calling worker to sleep until a job is pushed, someone calls notify or `timeout`
+    // passes. `has_work` is checked while the lock is held, so a notify that comes right after
+    // it can't be missed
+    pub(crate) fn park(&self, has_work: impl Fn() -> bool, timeout: Option<Duration>) -> Park {
+        let mut state = self.lock();
+
+        if state.len > 0 || has_work() {
+            return Park::Woken;
+        }
+        if state.closed {
+            return Park::Closed;
+        }
+
+        // A single wait, the caller loops and looks for work again either way
+        state.parked_workers += 1;
+        let (mut state, timed_out) = match timeout {
+            Some(timeout) => {
+                let (state, result) = self
+                    .not_empty
+                    .wait_timeout(state, timeout)
+                    .unwrap_or_else(PoisonError::into_inner);
+                (state, result.timed_out())
+            }
+            None => {
+                let state = self
+                    .not_empty
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                (state, false)
+            }
+        };
+        state.parked_workers -= 1;
+
+        if timed_out {
+            Park::TimedOut
+        } else {
+            Park::Woken
+        }
+    }
+
+    // Wake parked workers, taking the lock so the wake-up can't slip in between a worker's
+    // has_work check and its wait
+    pub(crate) fn notify(&self, count: usize) {
+        let state = self.lock();
+        for _ in 0..count.min(state.parked_workers) {
+            self.not_empty.notify_one();
+        }
+    }
+
+    pub(crate) fn notify_all(&self) {
+        let _state = self.lock();
+        self.not_empty.notify_all();
+    }
+
+    // Takes the place of the Terminate message: no new jobs are accepted, workers finish what
+    // is queued and then see Park::Closed
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lock().len
+    }
+
+    pub(crate) fn is_bounded(&self) -> bool {
+        self.capacity.is_some()
+    }
+
+    fn is_full(&self, state: &State) -> bool {
+        self.capacity.is_some_and(|capacity| state.len >= capacity)
+    }
+
+    fn push_lane(&self, state: &mut State, task: Task) {
+        if task.priority == Priority::High {
+            self.high.fetch_add(1, Ordering::SeqCst);
+        }
+        state.lanes[task.priority as usize].push_back(task);
+        state.len += 1;
+    }
+
+    fn pop(&self, state: &mut State, lane: usize) -> Option<Task> {
+        let task = state.lanes[lane].pop_front()?;
+        if task.priority == Priority::High {
+            self.high.fetch_sub(1, Ordering::SeqCst);
+        }
+        state.len -= 1;
+        Some(task)
+    }
+
+    // Room was made, producers blocked on a full queue can try again
+    fn wake_producers(&self, state: MutexGuard<'_, State>, taken: usize) -> usize {
+        let wake = taken > 0 && state.blocked_producers > 0;
+        drop(state);
+
+        if wake {
+            self.not_full.notify_all();
+        }
+        taken
+    }
+
+    // Jobs never run while the lock is held, so a poisoned lock still guards a consistent
+    // queue and into_inner recovers it instead of making every worker panic
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,342 @@
+// HTTP requests
+
+// The book never parses a request, it checks whether the buffer starts with
+// "GET / HTTP/1.1\r\n". That's enough for two routes, but middleware needs to look at headers
+// (Accept-Encoding, Host, Authorization, ...). Request is the parsed request head: request line
+// and headers, plus what the server knows about the connection. The server reads the body too
+// (see body.rs), and the helpers at the bottom of the impl turn it into form fields, uploaded
+// files or JSON.
+
+use crate::response::{HeaderMap, StatusCode};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+mod body;
+mod form;
+mod multipart;
+mod path;
+
+pub(crate) use body::BodyReader;
+pub use body::{Body, BodyError};
+pub(crate) use form::percent_decode;
+pub use form::Form;
+pub use multipart::{Multipart, UploadedFile};
+pub(crate) use path::PathPrefix;
+
+/// The request method.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    /// Any other method, e.g. a WebDAV one.
+    Other(String),
+}
+
+/// A parsed request: the head, and the body once the server has read it.
+#[derive(Debug, Clone)]
+pub struct Request {
+    method: Method,
+    target: String,
+    version: String,
+    headers: HeaderMap,
+    peer_addr: Option<SocketAddr>,
+    deadline: Option<Instant>,
+    principal: Option<String>,
+    body: Body,
+}
+
+/// Why a request head couldn't be parsed, answered with `400 Bad Request`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    reason: &'static str,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Other(method) => method,
+        }
+    }
+
+    // Methods are case-sensitive, "get" is not GET
+    fn parse(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            other => Method::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Request {
+    /// Parse a request head: the request line, headers and the blank line that ends them.
+    /// Anything after the blank line is ignored. An HTTP/1.1 request needs exactly one `Host`
+    /// header, HTTP/1.0 at most one.
+    pub fn parse(head: &[u8]) -> Result<Request, ParseError> {
+        let end = head
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or(ParseError::new("request head is incomplete"))?;
+        let head = std::str::from_utf8(&head[..end])
+            .map_err(|_| ParseError::new("request head is not UTF-8"))?;
+
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+
+        // Method SP Request-URI SP HTTP-Version, exactly three parts
+        let mut parts = request_line.split(' ');
+        let (method, target, version) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(method), Some(target), Some(version), None) => (method, target, version),
+                _ => return Err(ParseError::new("malformed request line")),
+            };
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+            return Err(ParseError::new("malformed method"));
+        }
+        if !target.starts_with('/') && target != "*" {
+            return Err(ParseError::new("malformed request target"));
+        }
+        if !version.starts_with("HTTP/1.") {
+            return Err(ParseError::new("unsupported HTTP version"));
+        }
+        // Before anything routes on it, see path.rs
+        let target = path::normalize(target, true)?;
+
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(ParseError::new("malformed header"))?;
+            // No whitespace between name and colon, RFC 9112 5.1. HeaderMap rejects it anyway,
+            // the check is for the error message
+            if name.ends_with([' ', '\t']) {
+                return Err(ParseError::new("whitespace before colon in header"));
+            }
+            headers
+                .append(name, value.trim_matches([' ', '\t']))
+                .map_err(|_| ParseError::new("malformed header"))?;
+        }
+        // HTTP/1.1 made Host mandatory, and a request with two can't be routed without guessing
+        // which one the client meant (RFC 9112 3.2). HTTP/1.0 clients may not know about it
+        match headers.get_all("Host").count() {
+            0 if version != "HTTP/1.0" => return Err(ParseError::new("missing Host header")),
+            0 | 1 => {}
+            _ => return Err(ParseError::new("more than one Host header")),
+        }
+
+        Ok(Request {
+            method: Method::parse(method),
+            target,
+            version: version.to_string(),
+            headers,
+            peer_addr: None,
+            deadline: None,
+            principal: None,
+            body: Body::default(),
+        })
+    }
+
+    /// A request built in code, e.g. for testing a handler without a server.
+    ///
+    /// The path is normalized like one from a client: `//` is `/`, escaped letters and digits
+    /// are decoded, and `.` and `..` segments are resolved the way a browser does before it
+    /// sends a request. An escaped slash, which the server refuses from a client, stays a part
+    /// of its segment.
+    pub fn new(method: Method, target: &str) -> Request {
+        Request {
+            method,
+            target: path::normalize(target, false).expect("dot segments are resolved"),
+            version: "HTTP/1.1".to_string(),
+            headers: HeaderMap::new(),
+            peer_addr: None,
+            deadline: None,
+            principal: None,
+            body: Body::default(),
+        }
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// The request target, path and query string. The path is normalized, so
+    /// `/static//a%2Dfile.txt` from the client is `/static/a-file.txt` here.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The target without the query string.
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(self.target.as_str(), |(path, _)| path)
+    }
+
+    /// The part after `?`, if there is one.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// "HTTP/1.1" or "HTTP/1.0".
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// The first value of header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The client's address, None for requests that didn't come in over a socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// When the handler should give up, see
+    /// [`Server::handler_timeout`](crate::server::Server::handler_timeout). None means no limit.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Who sent the request: the user or token name once [`Auth`](crate::middleware::Auth)
+    /// has checked the credentials, None before that or on routes it doesn't protect.
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_deref()
+    }
+
+    pub fn with_peer_addr(mut self, peer_addr: SocketAddr) -> Request {
+        self.peer_addr = Some(peer_addr);
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Request {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_principal(mut self, principal: &str) -> Request {
+        self.principal = Some(principal.to_string());
+        self
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// The media type of the body, the Content-Type without parameters, lowercase.
+    pub fn content_type(&self) -> Option<String> {
+        let content_type = self.header("Content-Type")?;
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        Some(media_type.trim().to_ascii_lowercase())
+    }
+
+    /// The body as `application/x-www-form-urlencoded` fields.
+    pub fn form(&self) -> Result<Form, BodyError> {
+        self.expect_content_type("application/x-www-form-urlencoded", |media_type| {
+            media_type == "application/x-www-form-urlencoded"
+        })?;
+        let bytes = self.body.bytes().map_err(BodyError::io)?;
+        let text =
+            std::str::from_utf8(&bytes).map_err(|_| BodyError::bad_request("form is not UTF-8"))?;
+        Ok(Form::parse(text))
+    }
+
+    /// The body as `multipart/form-data`: text fields, and uploaded files in temporary files.
+    pub fn multipart(&self) -> Result<Multipart, BodyError> {
+        self.expect_content_type("multipart/form-data", |media_type| {
+            media_type == "multipart/form-data"
+        })?;
+        let content_type = self.header("Content-Type").unwrap_or_default();
+        let params = content_type
+            .split_once(';')
+            .map_or("", |(_, params)| params);
+        let boundary = multipart::param(params, "boundary")
+            .filter(|boundary| !boundary.is_empty())
+            .ok_or_else(|| BodyError::bad_request("multipart body without a boundary"))?;
+        Multipart::parse(&self.body, boundary)
+    }
+
+    /// The body as JSON, deserialized into any `T` that implements serde's `Deserialize`.
+    /// Malformed JSON and JSON that doesn't fit `T` are a `400 Bad Request`.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, BodyError> {
+        // application/json, or a more specific type like application/problem+json
+        self.expect_content_type("application/json", |media_type| {
+            media_type == "application/json" || media_type.ends_with("+json")
+        })?;
+        let reader = self.body.reader().map_err(BodyError::io)?;
+        serde_json::from_reader(reader).map_err(|e| {
+            if e.is_io() {
+                BodyError::io(e.into())
+            } else {
+                BodyError::bad_request(format!("invalid JSON: {}", e))
+            }
+        })
+    }
+
+    // 415 unless the Content-Type is accepted
+    fn expect_content_type(
+        &self,
+        expected: &str,
+        accepts: impl Fn(&str) -> bool,
+    ) -> Result<(), BodyError> {
+        match self.content_type() {
+            Some(media_type) if accepts(&media_type) => Ok(()),
+            _ => Err(BodyError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("expected Content-Type: {}", expected),
+            )),
+        }
+    }
+}
+
+impl ParseError {
+    fn new(reason: &'static str) -> ParseError {
+        ParseError { reason }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad request: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ParseError {}
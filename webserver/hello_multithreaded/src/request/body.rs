@@ -0,0 +1,468 @@
+// Request bodies
+//
+// The server reads the whole body before the handler runs, the same way the event loop collects
+// a response before sending it. A body announces its size with Content-Length, or comes in
+// chunks (Transfer-Encoding: chunked) that each say how big they are and end with an empty one:
+//
+//     4\r\n
+//     Wiki\r\n
+//     5\r\n
+//     pedia\r\n
+//     0\r\n
+//     \r\n
+//
+// BodyReader takes the bytes as they arrive, in whatever pieces the socket hands out, so both
+// backends can feed it: the thread backend from blocking reads, the event loop whenever the
+// socket is readable. Small bodies stay in memory, bigger ones go to a temporary file as they
+// come in, an upload of a few megabytes doesn't have to fit into memory twice.
+
+use crate::response::{HeaderMap, Response, StatusCode};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Bodies up to this size are kept in memory
+const MEMORY_LIMIT: usize = 64 * 1024;
+// A chunk size line with extensions, or a trailer line
+const MAX_LINE: usize = 1024;
+const MAX_TRAILERS: usize = 8 * 1024;
+
+/// A request body, read completely by the server before the handler runs.
+///
+/// Small bodies are kept in memory, bigger ones in a temporary file that is deleted with the
+/// last clone of the body. See [`Server::max_body_size`](crate::server::Server::max_body_size)
+/// for the limit.
+#[derive(Debug, Clone, Default)]
+pub struct Body {
+    inner: Inner,
+}
+
+#[derive(Debug, Clone, Default)]
+enum Inner {
+    #[default]
+    Empty,
+    Memory(Vec<u8>),
+    File(Arc<TempFile>, u64),
+}
+
+/// Why a request body couldn't be read or parsed.
+///
+/// Every error has the status to answer with: `400 Bad Request` for malformed bodies and
+/// missing fields, `413 Payload Too Large`, `415 Unsupported Media Type` for the wrong
+/// Content-Type. Turn it into that response with `Response::from`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BodyError {
+    status: StatusCode,
+    message: String,
+}
+
+// A file in the temp directory that is deleted when dropped
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+// Decodes a body as it arrives and collects it, see the top of the file
+pub(crate) struct BodyReader {
+    framing: Framing,
+    collector: Collector,
+    max_size: usize,
+}
+
+enum Framing {
+    Length(u64),
+    Chunked(Chunk),
+}
+
+// Where the chunked decoder is
+enum Chunk {
+    // Reading the size line, up to its \r\n
+    Size(Vec<u8>),
+    Data(u64),
+    // The \r\n after the data
+    DataEnd(Vec<u8>),
+    // Header lines after the last chunk, up to an empty one. Nobody looks at them, only their
+    // total size is kept
+    Trailers { line: Vec<u8>, size: usize },
+    Done,
+}
+
+enum Collector {
+    Memory(Vec<u8>),
+    File {
+        file: TempFile,
+        writer: BufWriter<File>,
+        len: u64,
+    },
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match &self.inner {
+            Inner::Empty => 0,
+            Inner::Memory(bytes) => bytes.len() as u64,
+            Inner::File(_, len) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The whole body. Borrowed if it is in memory, read from its file otherwise.
+    pub fn bytes(&self) -> io::Result<Cow<'_, [u8]>> {
+        match &self.inner {
+            Inner::Empty => Ok(Cow::Borrowed(&[])),
+            Inner::Memory(bytes) => Ok(Cow::Borrowed(bytes)),
+            Inner::File(file, _) => fs::read(&file.path).map(Cow::Owned),
+        }
+    }
+
+    /// Reads the body from the start, without loading all of it.
+    pub fn reader(&self) -> io::Result<Box<dyn Read + Send + '_>> {
+        match &self.inner {
+            Inner::Empty => Ok(Box::new(io::empty())),
+            Inner::Memory(bytes) => Ok(Box::new(bytes.as_slice())),
+            Inner::File(file, _) => Ok(Box::new(io::BufReader::new(File::open(&file.path)?))),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body {
+            inner: Inner::Memory(bytes),
+        }
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::from(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::from(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::from(text.as_bytes())
+    }
+}
+
+impl BodyError {
+    pub(crate) fn new(status: StatusCode, message: impl Into<String>) -> BodyError {
+        BodyError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn bad_request(message: impl Into<String>) -> BodyError {
+        BodyError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    // Our fault, not the client's: the temp file couldn't be written or read back. Logged
+    // here, the client only learns that it failed
+    pub(crate) fn io(error: io::Error) -> BodyError {
+        eprintln!("Failed to store request body: {}", error);
+        BodyError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to store the request body",
+        )
+    }
+
+    /// The status to answer with.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+impl From<BodyError> for Response {
+    fn from(error: BodyError) -> Response {
+        Response::new(error.status)
+            .header("Content-Type", "text/plain")
+            .body(format!("{}\n", error.message))
+    }
+}
+
+impl TempFile {
+    // A new, empty file that nobody else has opened
+    pub(crate) fn create() -> io::Result<(TempFile, File)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "hello_multithreaded-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut options = File::options();
+            options.write(true).create_new(true);
+            // The temp directory is shared with every user on the machine, the request body is
+            // only the server's business
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            // Left over from an earlier process with the same id, try the next name
+            match options.open(&path) {
+                Ok(file) => return Ok((TempFile { path }, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Move the file to `to`. Copied if it can't be renamed there (another file system), the
+    // original is deleted on drop either way
+    pub(crate) fn persist(self, to: &Path) -> io::Result<()> {
+        if fs::rename(&self.path, to).is_err() {
+            fs::copy(&self.path, to)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl BodyReader {
+    // A reader for the body the headers announce, None if there is none. Bodies that are too
+    // big by their Content-Length are refused before anything is read
+    pub(crate) fn new(
+        headers: &HeaderMap,
+        max_size: usize,
+    ) -> Result<Option<BodyReader>, BodyError> {
+        let chunked = match headers.get("Transfer-Encoding") {
+            None => false,
+            Some(coding) if coding.eq_ignore_ascii_case("chunked") => true,
+            Some(_) => {
+                return Err(BodyError::new(
+                    StatusCode::NOT_IMPLEMENTED,
+                    "unsupported transfer encoding",
+                ))
+            }
+        };
+        let length = headers.get("Content-Length");
+
+        let framing = match (chunked, length) {
+            // Both at once is how requests are smuggled past proxies, RFC 9112 6.3
+            (true, Some(_)) => {
+                return Err(BodyError::bad_request(
+                    "both Content-Length and Transfer-Encoding",
+                ))
+            }
+            (true, None) => Framing::Chunked(Chunk::Size(Vec::new())),
+            (false, Some(length)) => {
+                let length = length
+                    .parse::<u64>()
+                    .map_err(|_| BodyError::bad_request("malformed Content-Length"))?;
+                if length > max_size as u64 {
+                    return Err(too_large(max_size));
+                }
+                if length == 0 {
+                    return Ok(None);
+                }
+                Framing::Length(length)
+            }
+            (false, None) => return Ok(None),
+        };
+
+        Ok(Some(BodyReader {
+            framing,
+            collector: Collector::Memory(Vec::new()),
+            max_size,
+        }))
+    }
+
+    // Take the next bytes from the client, Ok(true) once the body is complete. Anything after
+    // the end of the body is ignored
+    pub(crate) fn feed(&mut self, mut bytes: &[u8]) -> Result<bool, BodyError> {
+        loop {
+            match &mut self.framing {
+                Framing::Length(left) => {
+                    let take = bytes.len().min(*left as usize);
+                    self.collector.push(&bytes[..take])?;
+                    *left -= take as u64;
+                    return Ok(*left == 0);
+                }
+                Framing::Chunked(Chunk::Done) => return Ok(true),
+                Framing::Chunked(_) if bytes.is_empty() => return Ok(false),
+                Framing::Chunked(chunk) => {
+                    let used = chunk.feed(bytes, &mut self.collector, self.max_size)?;
+                    bytes = &bytes[used..];
+                }
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<Body, BodyError> {
+        let inner = match self.collector {
+            Collector::Memory(bytes) if bytes.is_empty() => Inner::Empty,
+            Collector::Memory(bytes) => Inner::Memory(bytes),
+            Collector::File {
+                file,
+                mut writer,
+                len,
+            } => {
+                writer.flush().map_err(BodyError::io)?;
+                Inner::File(Arc::new(file), len)
+            }
+        };
+        Ok(Body { inner })
+    }
+}
+
+impl Chunk {
+    // Decode from the start of `bytes`, returns how many of them were used
+    fn feed(
+        &mut self,
+        bytes: &[u8],
+        collector: &mut Collector,
+        max_size: usize,
+    ) -> Result<usize, BodyError> {
+        match self {
+            Chunk::Size(line) => {
+                let Some(used) = take_line(line, bytes, MAX_LINE)? else {
+                    return Ok(bytes.len());
+                };
+                // Chunk extensions after a semicolon are allowed, and ignored
+                let size = line.split(|&b| b == b';').next().unwrap_or_default();
+                let size = std::str::from_utf8(size)
+                    .ok()
+                    .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+                    .ok_or_else(|| BodyError::bad_request("malformed chunk size"))?;
+                if collector.len().saturating_add(size) > max_size as u64 {
+                    return Err(too_large(max_size));
+                }
+                *self = match size {
+                    0 => Chunk::Trailers {
+                        line: Vec::new(),
+                        size: 0,
+                    },
+                    size => Chunk::Data(size),
+                };
+                Ok(used)
+            }
+            Chunk::Data(left) => {
+                let take = bytes.len().min(*left as usize);
+                collector.push(&bytes[..take])?;
+                *left -= take as u64;
+                if *left == 0 {
+                    *self = Chunk::DataEnd(Vec::new());
+                }
+                Ok(take)
+            }
+            Chunk::DataEnd(line) => {
+                let Some(used) = take_line(line, bytes, 2)? else {
+                    return Ok(bytes.len());
+                };
+                if !line.is_empty() {
+                    return Err(BodyError::bad_request("chunk is longer than its size"));
+                }
+                *self = Chunk::Size(Vec::new());
+                Ok(used)
+            }
+            Chunk::Trailers { line, size } => {
+                let Some(used) = take_line(line, bytes, MAX_TRAILERS)? else {
+                    return Ok(bytes.len());
+                };
+                // An empty line ends them: either there were none, or that was the last one
+                if line.is_empty() {
+                    *self = Chunk::Done;
+                } else {
+                    *size += line.len();
+                    if *size > MAX_TRAILERS {
+                        return Err(BodyError::bad_request("trailers are too large"));
+                    }
+                    line.clear();
+                }
+                Ok(used)
+            }
+            Chunk::Done => Ok(0),
+        }
+    }
+}
+
+// Add to `line` up to and including a \r\n. Some(used) once the line is complete, with the
+// \r\n taken off, None if `bytes` ran out first
+fn take_line(line: &mut Vec<u8>, bytes: &[u8], max: usize) -> Result<Option<usize>, BodyError> {
+    let (used, complete) = match bytes.iter().position(|&b| b == b'\n') {
+        Some(end) => (end + 1, true),
+        None => (bytes.len(), false),
+    };
+    line.extend_from_slice(&bytes[..used]);
+    if line.len() > max + 2 {
+        return Err(BodyError::bad_request("malformed chunked body"));
+    }
+    if !complete {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(BodyError::bad_request("malformed chunked body"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(Some(used))
+}
+
+fn too_large(max_size: usize) -> BodyError {
+    BodyError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("request body is larger than {} bytes", max_size),
+    )
+}
+
+impl Collector {
+    fn len(&self) -> u64 {
+        match self {
+            Collector::Memory(bytes) => bytes.len() as u64,
+            Collector::File { len, .. } => *len,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), BodyError> {
+        match self {
+            Collector::Memory(memory) if memory.len() + bytes.len() <= MEMORY_LIMIT => {
+                memory.extend_from_slice(bytes);
+            }
+            Collector::Memory(memory) => {
+                // Too big for memory from now on, move what we have to a file
+                let (file, handle) = TempFile::create().map_err(BodyError::io)?;
+                let mut writer = BufWriter::new(handle);
+                writer.write_all(memory).map_err(BodyError::io)?;
+                let len = memory.len() as u64;
+                *self = Collector::File { file, writer, len };
+                return self.push(bytes);
+            }
+            Collector::File { writer, len, .. } => {
+                writer.write_all(bytes).map_err(BodyError::io)?;
+                *len += bytes.len() as u64;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+// application/x-www-form-urlencoded, what an HTML form sends by default and what a query string
+// looks like: name=value pairs joined with &, spaces as + and everything else unusual as %XX.
+//
+//     name=Ferris+the+crab&age=7&tags=rust&tags=crab
+
+use super::BodyError;
+use std::fmt;
+use std::str::FromStr;
+
+/// Form fields in the order they were sent, from a urlencoded body, a query string or the text
+/// fields of a multipart body.
+///
+/// A name can appear more than once, e.g. for checkboxes. [`Form::get`] returns the first value,
+/// [`Form::get_all`] every one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    /// Decode a urlencoded string like `a=1&b=two+words`. Pairs without `=` get an empty value,
+    /// broken %-escapes are kept as they are.
+    pub fn parse(input: &str) -> Form {
+        let fields = input
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name, true), percent_decode(value, true))
+            })
+            .collect();
+        Form { fields }
+    }
+
+    pub(crate) fn push(&mut self, name: String, value: String) {
+        self.fields.push((name, value));
+    }
+
+    /// The first value of field `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of field `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Field `name` parsed as a `T`, e.g. `form.value::<u32>("age")`. A missing field or one
+    /// that doesn't parse is a `400 Bad Request`.
+    pub fn value<T>(&self, name: &str) -> Result<T, BodyError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.optional(name)?
+            .ok_or_else(|| BodyError::bad_request(format!("missing field `{}`", name)))
+    }
+
+    /// Like [`Form::value`], but a missing or empty field is `None` instead of an error.
+    pub fn optional<T>(&self, name: &str) -> Result<Option<T>, BodyError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.get(name) {
+            None | Some("") => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|e| BodyError::bad_request(format!("field `{}` is invalid: {}", name, e))),
+        }
+    }
+}
+
+// %XX is a byte, and in forms + is a space. The bytes are UTF-8 in every browser, anything else
+// is replaced
+pub(crate) fn percent_decode(input: &str, plus_is_space: bool) -> String {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut rest = input.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'+' if plus_is_space => bytes.push(b' '),
+            b'%' => {
+                let escaped = tail
+                    .get(..2)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(escaped) = escaped {
+                    bytes.push(escaped);
+                    rest = &tail[2..];
+                    continue;
+                }
+                bytes.push(b'%');
+            }
+            byte => bytes.push(byte),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,285 @@
+// multipart/form-data, what a form with enctype="multipart/form-data" sends, and the only way a
+// browser uploads files. Every field is a part with headers of its own. The parts are separated
+// by a boundary the client picked and announced in the Content-Type:
+//
+//     Content-Type: multipart/form-data; boundary=XyZ
+//
+//     --XyZ\r\n
+//     Content-Disposition: form-data; name="title"\r\n
+//     \r\n
+//     Holiday\r\n
+//     --XyZ\r\n
+//     Content-Disposition: form-data; name="photo"; filename="beach.jpg"\r\n
+//     Content-Type: image/jpeg\r\n
+//     \r\n
+//     ...the file...\r\n
+//     --XyZ--\r\n
+//
+// Text fields are collected into a Form. Files go from the body to a temporary file of their
+// own a few KiB at a time, they are never in memory as a whole.
+
+use super::body::{Body, BodyError, TempFile};
+use super::Form;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+const READ_CHUNK: usize = 8 * 1024;
+
+/// A parsed `multipart/form-data` body, see [`Request::multipart`](super::Request::multipart).
+#[derive(Debug)]
+pub struct Multipart {
+    fields: Form,
+    files: Vec<UploadedFile>,
+}
+
+/// A file from a multipart body, in a temporary file that is deleted when this is dropped.
+/// Keep it with [`UploadedFile::persist`].
+#[derive(Debug)]
+pub struct UploadedFile {
+    name: String,
+    filename: String,
+    content_type: Option<String>,
+    size: u64,
+    file: TempFile,
+}
+
+// Reads a body in chunks, with the bytes that have been read but not used yet in `buffer`
+struct Parts<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl Multipart {
+    pub(crate) fn parse(body: &Body, boundary: &str) -> Result<Multipart, BodyError> {
+        // Every boundary but the first follows a line break. With one in front of the body,
+        // the first one does too
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        let mut parts = Parts {
+            reader: body.reader().map_err(BodyError::io)?,
+            buffer: b"\r\n".to_vec(),
+        };
+        let mut multipart = Multipart {
+            fields: Form::default(),
+            files: Vec::new(),
+        };
+
+        // Anything before the first boundary is a preamble that nobody reads
+        parts.copy_until(&delimiter, &mut io::sink())?;
+        loop {
+            // -- after a boundary ends the body, a line break starts another part
+            match parts.take(2)?.as_slice() {
+                b"--" => return Ok(multipart),
+                b"\r\n" => {}
+                _ => return Err(BodyError::bad_request("malformed multipart boundary")),
+            }
+
+            let mut head = Vec::new();
+            parts.copy_until(b"\r\n\r\n", &mut head)?;
+            let head = String::from_utf8(head)
+                .map_err(|_| BodyError::bad_request("multipart headers are not UTF-8"))?;
+            let part = PartHead::parse(&head)?;
+
+            match part.filename {
+                Some(filename) => {
+                    let (file, handle) = TempFile::create().map_err(BodyError::io)?;
+                    let mut writer = BufWriter::new(handle);
+                    parts.copy_until(&delimiter, &mut writer)?;
+                    let size = writer
+                        .into_inner()
+                        .map_err(|e| BodyError::io(e.into_error()))?
+                        .metadata()
+                        .map_err(BodyError::io)?
+                        .len();
+                    // What a browser sends for a file input where no file was picked
+                    if filename.is_empty() && size == 0 {
+                        continue;
+                    }
+                    multipart.files.push(UploadedFile {
+                        name: part.name,
+                        filename,
+                        content_type: part.content_type,
+                        size,
+                        file,
+                    });
+                }
+                None => {
+                    let mut value = Vec::new();
+                    parts.copy_until(&delimiter, &mut value)?;
+                    let value = String::from_utf8(value).map_err(|_| {
+                        BodyError::bad_request(format!("field `{}` is not UTF-8", part.name))
+                    })?;
+                    multipart.fields.push(part.name, value);
+                }
+            }
+        }
+    }
+
+    /// The text fields.
+    pub fn fields(&self) -> &Form {
+        &self.fields
+    }
+
+    /// The first file uploaded as field `name`.
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    pub fn files(&self) -> &[UploadedFile] {
+        &self.files
+    }
+
+    /// Take the first file uploaded as field `name` out, e.g. to persist it.
+    pub fn take_file(&mut self, name: &str) -> Option<UploadedFile> {
+        let index = self.files.iter().position(|file| file.name == name)?;
+        Some(self.files.remove(index))
+    }
+}
+
+impl UploadedFile {
+    /// The form field it was uploaded as.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file name on the client's side, without any directories.
+    ///
+    /// It comes from the client, so it can be anything: don't use it as a path without
+    /// checking it first.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// The Content-Type the client sent for the file, if any.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Where the temporary file is.
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    pub fn open(&self) -> io::Result<File> {
+        File::open(self.file.path())
+    }
+
+    /// Move the file to `to`, where it stays after the request.
+    pub fn persist(self, to: impl AsRef<Path>) -> io::Result<()> {
+        self.file.persist(to.as_ref())
+    }
+}
+
+// What the headers of a part say
+struct PartHead {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+impl PartHead {
+    fn parse(head: &str) -> Result<PartHead, BodyError> {
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in head.split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(BodyError::bad_request("malformed multipart header"));
+            };
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                disposition = Some(value.trim());
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+
+        // form-data; name="photo"; filename="beach.jpg"
+        let disposition = disposition
+            .ok_or_else(|| BodyError::bad_request("multipart part without Content-Disposition"))?;
+        let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+        if !kind.trim().eq_ignore_ascii_case("form-data") {
+            return Err(BodyError::bad_request("multipart part is not form-data"));
+        }
+        let name = param(params, "name")
+            .ok_or_else(|| BodyError::bad_request("multipart part without a name"))?;
+        // Some old browsers sent the whole path, C:\Users\... included
+        let filename = param(params, "filename").map(|filename| {
+            filename
+                .rsplit(['/', '\\'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+
+        Ok(PartHead {
+            name: name.to_string(),
+            filename,
+            content_type,
+        })
+    }
+}
+
+// The value of parameter `name` in `a=1; b="two"`, the part of a header after the media type or
+// disposition. Quotes are taken off, browsers %-encode quotes inside the value
+pub(super) fn param<'a>(params: &'a str, name: &str) -> Option<&'a str> {
+    params.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let value = value.trim();
+        Some(
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value),
+        )
+    })
+}
+
+impl<R: Read> Parts<R> {
+    // Read more of the body, false at its end
+    fn fill(&mut self) -> Result<bool, BodyError> {
+        let mut chunk = [0; READ_CHUNK];
+        let read = self.reader.read(&mut chunk).map_err(BodyError::io)?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    // Everything up to `delimiter` goes to `out`, the delimiter itself is skipped
+    fn copy_until(&mut self, delimiter: &[u8], out: &mut impl Write) -> Result<(), BodyError> {
+        loop {
+            if let Some(at) = find(&self.buffer, delimiter) {
+                out.write_all(&self.buffer[..at]).map_err(BodyError::io)?;
+                self.buffer.drain(..at + delimiter.len());
+                return Ok(());
+            }
+            // The end of the buffer could be the start of the delimiter, it stays
+            let done = self.buffer.len().saturating_sub(delimiter.len() - 1);
+            out.write_all(&self.buffer[..done]).map_err(BodyError::io)?;
+            self.buffer.drain(..done);
+            if !self.fill()? {
+                return Err(BodyError::bad_request("multipart body ends early"));
+            }
+        }
+    }
+
+    // The next `len` bytes
+    fn take(&mut self, len: usize) -> Result<Vec<u8>, BodyError> {
+        while self.buffer.len() < len {
+            if !self.fill()? {
+                return Err(BodyError::bad_request("multipart body ends early"));
+            }
+        }
+        Ok(self.buffer.drain(..len).collect())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
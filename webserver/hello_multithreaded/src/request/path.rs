@@ -0,0 +1,125 @@
+// Path normalization
+
+use super::ParseError;
+
+// One resource can be spelled many ways: /static/private/s.txt, /static//private/s.txt and
+// /static/%70rivate/s.txt are all the same file to Files, which decodes the path and skips empty
+// segments. Routing and middleware compare paths as strings, so unless everyone sees the same
+// spelling, a rule for /static/private (Auth, a rate limit) can be walked around with an extra
+// slash or an escaped letter. The request target is put into one normal form before anyone looks
+// at it (RFC 3986 6.2.2):
+//
+// - escaped unreserved characters (letters, digits, - . _ ~) are decoded, they mean the same
+//   thing either way. Other escapes stay, but get uppercase hex
+// - empty segments are dropped, // is /
+// - . and .. segments are an error for a request from a client, which resolves them before
+//   sending. In a request built in code they are resolved here, like a browser would
+// - an escaped separator (%2F, %5C) is an error from a client too. It isn't a separator to
+//   the router, but it is one to anything that decodes the path, Files or a filesystem, and
+//   /static/private%2Fs.txt would be a way around a rule for /static/private. No browser
+//   sends one for a link to a file
+//
+// The query string is left alone, it's up to the handler.
+
+// The target with its path in normal form. A target from a client is an error if it has dot
+// segments or escaped separators, one built in code gets its dot segments resolved. Targets
+// that aren't a path (*, absolute URLs) are returned as they are
+pub(crate) fn normalize(target: &str, from_client: bool) -> Result<String, ParseError> {
+    if !target.starts_with('/') {
+        return Ok(target.to_string());
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+
+    let mut segments: Vec<String> = Vec::new();
+    // A directory keeps its trailing slash, /docs/ and /docs aren't the same to Files
+    let mut directory = false;
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = decode_unreserved(segment);
+        if from_client && (segment.contains("%2F") || segment.contains("%5C")) {
+            return Err(ParseError::new("escaped slash in request path"));
+        }
+        directory = matches!(segment.as_str(), "." | "..");
+        match segment.as_str() {
+            "." | ".." if from_client => {
+                return Err(ParseError::new("dot segment in request path"))
+            }
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    directory |= path.ends_with('/');
+
+    let mut normal = String::with_capacity(target.len());
+    for segment in &segments {
+        normal.push('/');
+        normal.push_str(segment);
+    }
+    if directory || segments.is_empty() {
+        normal.push('/');
+    }
+    if let Some(query) = query {
+        normal.push('?');
+        normal.push_str(query);
+    }
+    Ok(normal)
+}
+
+fn decode_unreserved(segment: &str) -> String {
+    let mut decoded = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while let Some(index) = rest.find('%') {
+        decoded.push_str(&rest[..index]);
+        let escape = &rest[index..];
+        let byte = escape
+            .get(1..3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(byte as char);
+            }
+            Some(_) => decoded.push_str(&escape[..3].to_ascii_uppercase()),
+            // A lone %, not an escape
+            None => {
+                decoded.push('%');
+                rest = &escape[1..];
+                continue;
+            }
+        }
+        rest = &escape[3..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// A path prefix from the configuration, for Router::nest, Files, the middleware routes and the
+// proxy's rewrite. It matches whole segments: /api matches /api and /api/users, not /apis. The
+// prefix is normalized like the requests it's compared with, so /%61pi/ is /api. Files splits
+// the path into the same segments before it decodes them, and a client can't send an escaped
+// separator, so a path this doesn't match is no path to a file below the prefix either
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PathPrefix(String);
+
+impl PathPrefix {
+    pub(crate) fn new(prefix: &str) -> PathPrefix {
+        let prefix = normalize(prefix, false).expect("dot segments are resolved");
+        PathPrefix(prefix.trim_end_matches('/').to_string())
+    }
+
+    // What's left of `path` below the prefix, empty or starting with a slash. `path` must be
+    // normalized, like Request::path is
+    pub(crate) fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(self.0.as_str())
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        self.strip(path).is_some()
+    }
+}
@@ -0,0 +1,635 @@
+// HTTP responses
+
+// The book builds responses with format!("{}\r\nContent-Length: {}\r\n\r\n{}", ...), which is
+// easy to get subtly wrong: a missing \r, a Content-Length that doesn't match the body, or a
+// header value with a line break in it that smuggles in headers of its own. Response holds the
+// parts instead, and Response::write_to is the one place that turns them into bytes:
+//
+// - the status line comes from a StatusCode, so the reason phrase always matches the code
+// - header names and values are checked when they are added, CR and LF can't get in
+// - framing (Content-Length or Transfer-Encoding: chunked) is always derived from the Body and
+//   can't be set by hand, so it can't disagree with what is actually sent
+
+use crate::server::Upgraded;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// An HTTP status code, e.g. [`StatusCode::NOT_FOUND`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u16);
+
+/// Request or response headers, in the order they were added.
+///
+/// Names are compared case-insensitively. A name can appear more than once (`Set-Cookie` does),
+/// [`HeaderMap::insert`] replaces every value, [`HeaderMap::append`] adds another one.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+/// A header name or value that can't be sent as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidHeader {
+    name: String,
+}
+
+/// What a response sends after its headers.
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Streamed from the file in chunks, never read into memory as a whole.
+    File {
+        file: File,
+        len: u64,
+    },
+    /// Sent with `Transfer-Encoding: chunked` as the iterator produces chunks, for bodies whose
+    /// length isn't known up front. The stream is flushed after every chunk.
+    Chunked(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+/// An HTTP response, built up and then sent with [`Response::write_to`].
+///
+/// ```
+/// use hello_multithreaded::response::{Cookie, Response, StatusCode};
+///
+/// let response = Response::new(StatusCode::OK)
+///     .header("Content-Type", "text/plain")
+///     .cookie(Cookie::new("session", "abc123").http_only(true))
+///     .body("Hello!");
+///
+/// let mut out = Vec::new();
+/// response.write_to(&mut out).unwrap();
+/// assert!(out.starts_with(b"HTTP/1.1 200 OK\r\n"));
+/// ```
+pub struct Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Body,
+    on_upgrade: Option<OnUpgrade>,
+    // The body comes from on_upgrade, see Response::stream_body
+    streamed: bool,
+}
+
+// What takes over the connection after a 101, see Response::on_upgrade
+pub(crate) type OnUpgrade = Box<dyn FnOnce(Upgraded) + Send>;
+
+/// A `Set-Cookie` header, see [`Response::cookie`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl StatusCode {
+    pub const CONTINUE: StatusCode = StatusCode(100);
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const TOO_MANY_REQUESTS: StatusCode = StatusCode(429);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const BAD_GATEWAY: StatusCode = StatusCode(502);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const GATEWAY_TIMEOUT: StatusCode = StatusCode(504);
+
+    /// Any three digit code, for the ones without a constant. None outside 100 to 999.
+    pub fn from_u16(code: u16) -> Option<StatusCode> {
+        (100..1000).contains(&code).then_some(StatusCode(code))
+    }
+
+    pub fn as_u16(self) -> u16 {
+        self.0
+    }
+
+    /// The standard reason phrase, or an empty one for codes without a standard phrase (it's
+    /// optional in HTTP/1.1).
+    pub fn reason(self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "",
+        }
+    }
+
+    // 1xx, 204 and 304 responses never have a body, not even an empty one with a length
+    pub(crate) fn allows_body(self) -> bool {
+        !(self.0 < 200 || self.0 == 204 || self.0 == 304)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// Set `name` to `value`, replacing the values it had.
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<(), InvalidHeader> {
+        check_header(name, value)?;
+        self.remove(name);
+        self.entries.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Add another value for `name`, keeping the ones it had.
+    pub fn append(&mut self, name: &str, value: &str) -> Result<(), InvalidHeader> {
+        check_header(name, value)?;
+        self.entries.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// The first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries
+            .retain(|(other, _)| !other.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+// Names are tokens (RFC 9110 5.6.2), values visible characters, spaces and tabs. Anything else,
+// CR and LF in particular, would change the meaning of the bytes around it
+fn check_header(name: &str, value: &str) -> Result<(), InvalidHeader> {
+    let name_ok = is_header_name(name);
+    let value_ok = value
+        .bytes()
+        .all(|b| b == b'\t' || b == b' ' || (0x21..=0x7e).contains(&b) || b >= 0x80);
+
+    if name_ok && value_ok {
+        Ok(())
+    } else {
+        Err(InvalidHeader {
+            name: name.to_string(),
+        })
+    }
+}
+
+pub(crate) fn is_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(is_token)
+}
+
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid header {:?}", self.name)
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+impl Body {
+    /// Open `path` to stream it as the body.
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Body> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Body::File { file, len })
+    }
+
+    /// Send every item of `chunks` as one chunk.
+    pub fn chunked<I>(chunks: I) -> Body
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        Body::Chunked(Box::new(chunks.into_iter()))
+    }
+
+    // None for chunked bodies, their length isn't known until the last chunk
+    fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Chunked(_) => None,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::File { len, .. } => write!(f, "File({} bytes)", len),
+            Body::Chunked(_) => write!(f, "Chunked"),
+        }
+    }
+}
+
+impl Response {
+    /// An empty response with the given status.
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+            on_upgrade: None,
+            streamed: false,
+        }
+    }
+
+    /// A `text/html` response with the contents of `path`, streamed from disk.
+    pub fn html_file<P: AsRef<Path>>(status: StatusCode, path: P) -> io::Result<Response> {
+        Ok(Response::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::file(path)?))
+    }
+
+    /// Set a header, replacing earlier values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name isn't a valid header name or the value contains control characters
+    /// like CR or LF. Use [`Response::headers_mut`] and [`HeaderMap::insert`] for values that
+    /// come from a client.
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        if let Err(e) = self.headers.insert(name, value) {
+            panic!("{}", e);
+        }
+        self
+    }
+
+    /// Add a `Set-Cookie` header. Several cookies can be set on one response.
+    pub fn cookie(mut self, cookie: Cookie) -> Response {
+        // Cookie::new checked the name and value, and the attributes can't contain CR or LF
+        // either, so this can't fail
+        self.headers
+            .append("Set-Cookie", &cookie.to_string())
+            .expect("cookie is a valid header value");
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn body_ref(&self) -> &Body {
+        &self.body
+    }
+
+    /// Take the body out, leaving [`Body::Empty`] in its place. For code that transforms the
+    /// body of an existing response.
+    pub fn take_body(&mut self) -> Body {
+        std::mem::replace(&mut self.body, Body::Empty)
+    }
+
+    /// Replace the body, e.g. with a transformed version of what [`Response::take_body`]
+    /// returned.
+    pub fn set_body(&mut self, body: impl Into<Body>) {
+        self.body = body.into();
+    }
+
+    /// Take over the connection once this response has been sent, for protocols like
+    /// WebSocket that start out as an HTTP request.
+    ///
+    /// Only `101 Switching Protocols` responses are upgraded, for any other status `f` is
+    /// dropped and the connection closed as usual. `f` runs on a thread of its own, so it can
+    /// keep the connection for as long as it likes without holding a worker.
+    pub fn on_upgrade(mut self, f: impl FnOnce(Upgraded) + Send + 'static) -> Response {
+        self.on_upgrade = Some(Box::new(f));
+        self
+    }
+
+    // Send only the head, with Transfer-Encoding: chunked, and let `f` write the chunks from a
+    // thread of its own like an upgraded connection. For bodies that go on for hours, which
+    // would hold a worker all that time, or the event loop forever since it renders the whole
+    // response before it sends any of it. See sse.rs
+    pub(crate) fn stream_body(mut self, f: impl FnOnce(Upgraded) + Send + 'static) -> Response {
+        self.body = Body::Empty;
+        self.on_upgrade = Some(Box::new(f));
+        self.streamed = true;
+        self
+    }
+
+    // For the server, which has to hold on to it while write_to consumes the response
+    pub(crate) fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        match self.status {
+            StatusCode::SWITCHING_PROTOCOLS => self.on_upgrade.take(),
+            _ if self.streamed => self.on_upgrade.take(),
+            _ => None,
+        }
+    }
+
+    /// Serialize the response onto `out`: status line, headers, framing and body.
+    ///
+    /// `Content-Length` and `Transfer-Encoding` headers set by hand are replaced by the ones
+    /// that match the body. Returns the number of body bytes sent, without the head and the
+    /// chunk framing.
+    pub fn write_to<W: Write>(self, out: &mut W) -> io::Result<u64> {
+        let Response {
+            status,
+            mut headers,
+            body,
+            on_upgrade: _,
+            streamed,
+        } = self;
+
+        headers.remove("Content-Length");
+        headers.remove("Transfer-Encoding");
+        // A body on a status that can't have one would be taken for the next response
+        let body = if status.allows_body() {
+            match body.len() {
+                Some(_) if streamed => headers.insert("Transfer-Encoding", "chunked"),
+                Some(len) => headers.insert("Content-Length", &len.to_string()),
+                None => headers.insert("Transfer-Encoding", "chunked"),
+            }
+            .expect("framing headers are valid");
+            body
+        } else {
+            Body::Empty
+        };
+
+        // The head is many small writes, buffer it so it goes out in one piece
+        let mut head = BufWriter::new(&mut *out);
+        write!(head, "HTTP/1.1 {}\r\n", status)?;
+        for (name, value) in headers.iter() {
+            write!(head, "{}: {}\r\n", name, value)?;
+        }
+        head.write_all(b"\r\n")?;
+        head.flush()?;
+        drop(head);
+
+        let sent = match body {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => {
+                out.write_all(&bytes)?;
+                bytes.len() as u64
+            }
+            Body::File { file, len } => {
+                // take(len): a file that grew since we looked must not overrun the length
+                // we announced
+                let copied = io::copy(&mut file.take(len), out)?;
+                if copied < len {
+                    // Shrunk instead, the client would wait for bytes that never come. Failing
+                    // makes the caller drop the connection
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while it was being sent",
+                    ));
+                }
+                copied
+            }
+            Body::Chunked(chunks) => {
+                let mut sent = 0;
+                for chunk in chunks {
+                    // An empty chunk would end the body early
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    write!(out, "{:x}\r\n", chunk.len())?;
+                    out.write_all(&chunk)?;
+                    out.write_all(b"\r\n")?;
+                    out.flush()?;
+                    sent += chunk.len() as u64;
+                }
+                out.write_all(b"0\r\n\r\n")?;
+                sent
+            }
+        };
+        out.flush()?;
+        Ok(sent)
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("on_upgrade", &self.on_upgrade.is_some())
+            .finish()
+    }
+}
+
+impl Cookie {
+    /// # Panics
+    ///
+    /// Panics if the name isn't a token or the value contains characters cookies can't carry
+    /// (control characters, spaces, `"`, `,`, `;` or `\`). Encode such values first.
+    pub fn new(name: &str, value: &str) -> Cookie {
+        assert!(
+            !name.is_empty() && name.bytes().all(is_token),
+            "invalid cookie name {:?}",
+            name
+        );
+        assert!(
+            value.bytes().all(is_cookie_octet),
+            "invalid cookie value {:?}",
+            value
+        );
+
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that tells the browser to delete `name` right away.
+    pub fn removal(name: &str) -> Cookie {
+        Cookie::new(name, "").max_age(Duration::ZERO)
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(attribute(path));
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(attribute(domain));
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+// RFC 6265 4.1.1
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+// Attribute values end at the next ";", and control characters have no business in them
+fn attribute(value: &str) -> String {
+    assert!(
+        value.bytes().all(|b| b != b';' && !b.is_ascii_control()),
+        "invalid cookie attribute {:?}",
+        value
+    );
+    value.to_string()
+}
+
+// The Set-Cookie value
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            let same_site = match same_site {
+                SameSite::Strict => "Strict",
+                SameSite::Lax => "Lax",
+                SameSite::None => "None",
+            };
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+// Work-stealing scheduler
+
+// With a single shared queue every worker takes the same lock for every job it runs, so with
+// many tiny jobs the workers spend their time waiting on each other instead of working. Here
+// each worker has its own deque:
+//
+// 1. A worker runs jobs from the front of its own deque. Nobody else touches it most of the
+//    time, so its lock is practically free.
+// 2. When its deque is empty, it moves a batch of jobs from the global queue (the injector) into
+//    it, one lock for many jobs.
+// 3. When the injector is empty too, it steals half of another worker's deque, from the back.
+// 4. Only when there is no work anywhere it parks on the injector until a job shows up.
+//
+// Jobs submitted from outside go to the injector, which is where the queue capacity and the
+// overflow policy apply. Jobs submitted by a job that is running on a worker go straight into
+// that worker's deque, so fanning out work from inside the pool never touches the global lock.
+//
+// A bounded pool doesn't move batches (step 2). A job in a worker's deque no longer counts
+// towards the capacity and can't be dropped by OverflowPolicy::DropOldest, so batches would let
+// the pool hold up to 32 jobs per worker more than it was asked to. Its workers take one job at
+// a time from the injector instead, right when they start it.
+//
+// High priority jobs in the injector come before all of that: a worker looks for one before
+// taking the next job from its own deque, so they don't wait behind a batch a worker has already
+// claimed.
+//
+// The scheduler also keeps track of how many workers are alive, so an elastic pool knows when to
+// spawn another one and idle workers know when to retire. And it owns the pool's timers, which
+// workers check on their way to the next job (see timer.rs).
+
+use crate::queue::{JobQueue, Park, Priority, Task};
+use crate::timer::{PeriodicHandle, Timers};
+use crate::{ExecuteError, Job};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+// How many jobs a worker moves from the injector into its own deque at once
+const BATCH_SIZE: usize = 32;
+
+thread_local! {
+    // Set on worker threads: which scheduler the worker belongs to (its address) and its deque
+    static CURRENT_WORKER: RefCell<Option<(usize, Arc<Slot>)>> = const { RefCell::new(None) };
+}
+
+pub(crate) struct Scheduler {
+    injector: JobQueue,
+    timers: Timers,
+    // One slot per worker. Slots of retired workers are reused by the next worker that spawns,
+    // so the list only grows up to the largest number of workers alive at once
+    slots: RwLock<Vec<Arc<Slot>>>,
+    // Jobs sitting in any worker's deque, so parking workers know whether stealing is worth it
+    local_jobs: AtomicUsize,
+    sleeping: AtomicUsize,
+    live: AtomicUsize,
+    min: AtomicUsize,
+    max: AtomicUsize,
+    keep_alive: Duration,
+}
+
+// A worker's own deque
+pub(crate) struct Slot {
+    jobs: Mutex<VecDeque<Task>>,
+    in_use: AtomicBool,
+}
+
+// Why a worker should stop asking for jobs
+pub(crate) enum Stop {
+    Shutdown,
+    Retire,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        injector: JobQueue,
+        min: usize,
+        max: usize,
+        keep_alive: Duration,
+    ) -> Scheduler {
+        Scheduler {
+            injector,
+            timers: Timers::new(),
+            slots: RwLock::new(Vec::new()),
+            local_jobs: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            min: AtomicUsize::new(min),
+            max: AtomicUsize::new(max),
+            keep_alive,
+        }
+    }
+
+    // Count a new worker in and hand it a free slot. Fails if the pool already runs `limit`
+    // workers, the check and the increment are one atomic step so concurrent callers can't
+    // overshoot
+    pub(crate) fn add_worker(&self, limit: usize) -> Option<Arc<Slot>> {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live < limit).then_some(live + 1)
+            })
+            .ok()?;
+
+        let mut slots = self.slots.write().unwrap_or_else(PoisonError::into_inner);
+        let free = slots
+            .iter()
+            .find(|slot| {
+                slot.in_use
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            })
+            .cloned();
+
+        Some(free.unwrap_or_else(|| {
+            let slot = Arc::new(Slot {
+                jobs: Mutex::new(VecDeque::new()),
+                in_use: AtomicBool::new(true),
+            });
+            slots.push(Arc::clone(&slot));
+            slot
+        }))
+    }
+
+    // Undo add_worker when the thread couldn't be spawned
+    pub(crate) fn remove_worker(&self, slot: &Slot) {
+        self.live.fetch_sub(1, Ordering::SeqCst);
+        self.release_slot(slot);
+    }
+
+    // A retired worker is already counted out by try_retire, its slot is free for the next one
+    pub(crate) fn release_slot(&self, slot: &Slot) {
+        slot.in_use.store(false, Ordering::SeqCst);
+    }
+
+    // Called once on each worker thread before it asks for jobs
+    pub(crate) fn register_worker(&self, slot: &Arc<Slot>) {
+        CURRENT_WORKER.with(|current| {
+            *current.borrow_mut() = Some((self.address(), Arc::clone(slot)));
+        });
+    }
+
+    // Only normal priority jobs go to the worker's own deque, which is strictly first in first
+    // out. The others need the injector's lanes to be ordered, but like local jobs they skip
+    // the capacity check
+    pub(crate) fn submit(&self, task: Task) -> Result<(), ExecuteError> {
+        // The injector checks for itself, the worker's deque doesn't know. A job pushed there
+        // once the workers are on their way out might never run
+        if self.is_closed() {
+            return Err(ExecuteError::ShuttingDown);
+        }
+        match self.current_worker() {
+            Some(_) if task.priority != Priority::Normal => self
+                .injector
+                .push_unbounded(task)
+                .map_err(|_| ExecuteError::ShuttingDown),
+            Some(slot) => {
+                slot.lock().push_back(task);
+                self.local_jobs.fetch_add(1, Ordering::SeqCst);
+                // The submitting worker is busy running a job, let an idle one steal this
+                if self.sleeping.load(Ordering::SeqCst) > 0 {
+                    self.injector.notify(1);
+                }
+                Ok(())
+            }
+            None => self.injector.push(task),
+        }
+    }
+
+    // Returns Err if the pool is shutting down
+    pub(crate) fn schedule_once(&self, at: Instant, job: Job) -> Result<(), ExecuteError> {
+        if self.is_closed() {
+            return Err(ExecuteError::ShuttingDown);
+        }
+        if self.timers.add_once(at, job) {
+            self.wake_for_timer();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn schedule_every(
+        &self,
+        interval: Duration,
+        job: Box<dyn Fn() + Send + Sync + 'static>,
+    ) -> Result<PeriodicHandle, ExecuteError> {
+        if self.is_closed() {
+            return Err(ExecuteError::ShuttingDown);
+        }
+        let (handle, earliest) = self.timers.add_every(interval, job);
+        if earliest {
+            self.wake_for_timer();
+        }
+        Ok(handle)
+    }
+
+    // Blocks until there is a job for the worker owning `slot`, or until the worker should stop
+    pub(crate) fn next_job(&self, slot: &Slot) -> Result<Task, Stop> {
+        // When this worker ran out of work, for the keep-alive. Waking up because a timer fired
+        // or a job was stolen by someone else doesn't restart the clock
+        let mut idle_since = None;
+
+        loop {
+            self.fire_timers();
+
+            if let Some(job) = self.injector.take_high() {
+                return Ok(job);
+            }
+            if let Some(job) = self.pop_local(slot) {
+                return Ok(job);
+            }
+            if self.injector.is_bounded() {
+                if let Some(job) = self.injector.take() {
+                    return Ok(job);
+                }
+            } else if self.refill(slot) {
+                continue;
+            }
+            if self.steal(slot) {
+                continue;
+            }
+
+            // Idle with nothing to steal: the right moment to leave if resize asked for fewer
+            // workers. The deque is empty, so no job gets stranded
+            if self.try_retire(self.max.load(Ordering::SeqCst)) {
+                return Err(Stop::Retire);
+            }
+
+            let now = Instant::now();
+            let idle_since = *idle_since.get_or_insert(now);
+
+            // Only workers above the minimum have a keep-alive, the rest wait for work forever.
+            // Either way nobody sleeps past the next timer
+            let keep_alive = (self.live.load(Ordering::SeqCst) > self.min.load(Ordering::SeqCst))
+                .then(|| self.keep_alive.saturating_sub(now - idle_since));
+            let next_timer = self.timers.next();
+            let timeout = match (keep_alive, self.timers.until_next(now)) {
+                (Some(keep_alive), Some(timer)) => Some(keep_alive.min(timer)),
+                (keep_alive, timer) => keep_alive.or(timer),
+            };
+
+            // A timer added after we looked is work too, it may be due before our timeout
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            let park = self.injector.park(
+                || self.local_jobs.load(Ordering::SeqCst) > 0 || self.timers.next() != next_timer,
+                timeout,
+            );
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+
+            match park {
+                Park::Closed => return Err(Stop::Shutdown),
+                Park::TimedOut
+                    if idle_since.elapsed() >= self.keep_alive
+                        && self.try_retire(self.min.load(Ordering::SeqCst)) =>
+                {
+                    return Err(Stop::Retire)
+                }
+                Park::Woken | Park::TimedOut => {}
+            }
+        }
+    }
+
+    // An elastic pool wants another worker when jobs are waiting and nobody is idle to take them
+    pub(crate) fn queued(&self) -> usize {
+        self.local_jobs.load(Ordering::SeqCst) + self.injector.len()
+    }
+
+    pub(crate) fn is_backed_up(&self) -> bool {
+        if self.live.load(Ordering::SeqCst) >= self.max.load(Ordering::SeqCst)
+            || self.sleeping.load(Ordering::SeqCst) > 0
+        {
+            return false;
+        }
+        self.local_jobs.load(Ordering::SeqCst) > 0 || self.injector.len() > 0
+    }
+
+    pub(crate) fn max(&self) -> usize {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn live(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    // Pin the pool to `size` workers. Parked workers are woken so extra ones can retire, busy
+    // ones retire when they run out of work
+    pub(crate) fn set_size(&self, size: usize) {
+        self.min.store(size, Ordering::SeqCst);
+        self.max.store(size, Ordering::SeqCst);
+        self.injector.notify_all();
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.injector.is_closed()
+    }
+
+    pub(crate) fn close(&self) {
+        self.injector.close();
+        self.timers.clear();
+    }
+
+    // Queue the jobs of timers that are due. They go through the injector so any idle worker can
+    // pick them up, not just the one that noticed
+    fn fire_timers(&self) {
+        for task in self.timers.take_due(Instant::now()) {
+            // Closed in the meantime, the job is dropped like any other queued job on shutdown
+            let _ = self.injector.push_unbounded(task);
+        }
+    }
+
+    // A timer became the earliest one, parked workers recompute their timeouts. All of them,
+    // the first one to wake up may find a job and be busy when the timer is due. Adding timers
+    // is rare enough that the extra wake-ups don't matter
+    fn wake_for_timer(&self) {
+        let sleeping = self.sleeping.load(Ordering::SeqCst);
+        if sleeping > 0 {
+            self.injector.notify(sleeping);
+        }
+    }
+
+    // Leave if more than `limit` workers are alive. Like add_worker, check and decrement are one
+    // atomic step, otherwise two idle workers could both retire and drop the pool below `limit`
+    fn try_retire(&self, limit: usize) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > limit).then_some(live - 1)
+            })
+            .is_ok()
+    }
+
+    fn pop_local(&self, slot: &Slot) -> Option<Task> {
+        let job = slot.lock().pop_front();
+        if job.is_some() {
+            self.local_jobs.fetch_sub(1, Ordering::SeqCst);
+        }
+        job
+    }
+
+    // Move a batch from the injector into the worker's own deque. Taking a share instead of the
+    // whole batch keeps one worker from hoarding jobs the others could start on right away
+    fn refill(&self, slot: &Slot) -> bool {
+        let workers = self.live.load(Ordering::SeqCst).max(1);
+        let share = BATCH_SIZE.min(1 + self.injector.len() / workers);
+
+        let mut local = slot.lock();
+        let moved = self.injector.take_batch(share, &mut local);
+        drop(local);
+
+        if moved == 0 {
+            return false;
+        }
+        self.local_jobs.fetch_add(moved, Ordering::SeqCst);
+
+        // This worker will run one of them now, idle workers can steal the rest
+        let sleeping = self.sleeping.load(Ordering::SeqCst);
+        if moved > 1 && sleeping > 0 {
+            self.injector.notify(sleeping.min(moved - 1));
+        }
+        true
+    }
+
+    // Take half of the first non-empty deque, looking at the other slots in turn starting after
+    // our own, so thieves spread out instead of all hitting the first worker
+    fn steal(&self, slot: &Slot) -> bool {
+        if self.local_jobs.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+
+        let slots = self.slots.read().unwrap_or_else(PoisonError::into_inner);
+        let start = slots
+            .iter()
+            .position(|other| std::ptr::eq(&**other, slot))
+            .unwrap_or(0);
+
+        for offset in 1..slots.len() {
+            let victim = &slots[(start + offset) % slots.len()];
+
+            let stolen = {
+                let mut victim = victim.lock();
+                let len = victim.len();
+                victim.split_off(len / 2)
+            };
+
+            if !stolen.is_empty() {
+                slot.lock().extend(stolen);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn current_worker(&self) -> Option<Arc<Slot>> {
+        CURRENT_WORKER.with(|current| match &*current.borrow() {
+            Some((scheduler, slot)) if *scheduler == self.address() => Some(Arc::clone(slot)),
+            _ => None,
+        })
+    }
+
+    fn address(&self) -> usize {
+        self as *const Scheduler as usize
+    }
+}
+
+impl Slot {
+    // Same reasoning as JobQueue::lock, jobs never run while a deque is locked
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Task>> {
+        self.jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,1093 @@
+// Docs: https://doc.rust-lang.org/stable/book/ch20-03-graceful-shutdown-and-cleanup.html
+
+// The book stops the server after two requests with listener.incoming().take(2). Here the accept
+// loop runs until someone asks it to stop, either a signal (Ctrl-C, SIGTERM) or a ShutdownHandle
+// held by the caller, e.g. an integration test.
+//
+// The book's handle_connection does a single blocking read without a timeout, so a client that
+// connects and sends nothing holds a worker forever, and four of them take down a server with
+// four workers (slowloris). Every connection now has limits: how long a single read may block,
+// how long the whole request head may take to arrive, how big it may be, how long a write may
+// block and how long the handler may run.
+//
+// A server can listen on more than one address, and with the tls feature for HTTPS next to
+// plain HTTP (see tls.rs). Every listener gets its own accept loop, all of them feed the same
+// pool and handler. Sites that share a server are told apart by their Host header instead, see
+// vhost.rs.
+//
+// On Linux the plain HTTP listeners can be served by an event loop instead (see
+// event_loop.rs): one thread does the socket I/O for every connection, and the pool only runs
+// handlers.
+//
+// What a request gets back is up to the server's Handler, default_router unless the caller
+// brings their own. The server only reads the request (head and body, see request/body.rs) and
+// writes the response, and logs the result to the access log if there is one.
+//
+// A response can take the connection over once it has been sent, for WebSocket (see
+// Response::on_upgrade), or once its head has, for server-sent events (see sse.rs). The
+// connection then leaves the server for a thread of its own. It still counts towards its
+// client's max_connections_per_ip, and there is a limit on upgraded connections overall (see
+// upgrades.rs).
+
+use crate::access_log::{AccessLog, Record};
+use crate::handler::{Handler, Router};
+use crate::middleware::Compression;
+use crate::request::{BodyError, BodyReader, ParseError, Request};
+use crate::response::{OnUpgrade, Response, StatusCode};
+use crate::{Priority, StatsHandle, ThreadPool};
+use per_ip::{PerIp, Slot};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use upgrades::{Permit, Upgrades};
+
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod per_ip;
+#[cfg(feature = "tls")]
+mod tls;
+mod upgrades;
+
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+
+pub struct Server {
+    // The plain HTTP listener from bind or with_pool comes first, then the ones from listen and
+    // listen_tls in the order they were added
+    listeners: Vec<Listener>,
+    pool: ThreadPool,
+    app: Arc<dyn Handler>,
+    access_log: Option<AccessLog>,
+    backend: Backend,
+    shutdown: ShutdownHandle,
+    deadline: Duration,
+    limits: Limits,
+    per_ip: Option<Arc<PerIp>>,
+    upgrades: Arc<Upgrades>,
+    #[cfg(feature = "tls")]
+    redirect_to_https: bool,
+}
+
+struct Listener {
+    socket: TcpListener,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+// Per-connection limits, copied into every Connection
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    read_timeout: Duration,
+    write_timeout: Duration,
+    request_timeout: Duration,
+    handler_timeout: Duration,
+    max_header_size: usize,
+    max_body_size: usize,
+}
+
+/// A connection after a `101 Switching Protocols` response, see [`Response::on_upgrade`].
+///
+/// Reads and writes go straight to the client, through TLS on HTTPS connections. The server's
+/// timeouts no longer apply, except for writes, and shutdown doesn't wait for upgraded
+/// connections: check [`Upgraded::shutdown_requested`] now and then to say goodbye in time.
+///
+/// The connection counts towards [`Server::max_connections_per_ip`] and
+/// [`Server::max_upgraded_connections`] until the `Upgraded` is dropped.
+pub struct Upgraded {
+    stream: Stream,
+    shutdown: ShutdownHandle,
+    _slot: Slot,
+    _permit: Permit,
+}
+
+/// How a [`Server`] waits for clients, see [`Server::backend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// An accept loop hands every connection to a worker, which reads the request, runs the
+    /// handler and writes the response with blocking I/O. A slow client keeps its worker busy
+    /// for as long as the timeouts allow.
+    #[default]
+    Threads,
+    /// One thread multiplexes all connections with non-blocking sockets and epoll, and passes
+    /// complete requests to the pool. Workers only run handlers, slow clients cost a buffer
+    /// instead of a worker. Response bodies are collected in memory before they are sent.
+    #[cfg(target_os = "linux")]
+    EventLoop,
+}
+
+/// Stops a running [`Server`] from another thread.
+///
+/// Cloning is cheap, every clone stops the same server.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    // One per listener, each accept loop needs its own wake-up call
+    addrs: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl Server {
+    /// Bind to `addr` and create a pool with `size` workers.
+    ///
+    /// Bind to port 0 to let the OS pick a free port, [`Server::local_addr`] tells which one.
+    /// Pool creation errors (zero size, failed spawn) come back as `io::Error`s as well.
+    pub fn bind<A: ToSocketAddrs>(addr: A, size: usize) -> io::Result<Server> {
+        let pool = ThreadPool::build(size).map_err(io::Error::other)?;
+        Server::with_pool(addr, pool)
+    }
+
+    /// Bind to `addr` and serve connections on a pool configured by the caller, e.g. one with a
+    /// bounded queue.
+    ///
+    /// Connections the pool can't take (full queue, or pushed out by
+    /// [`OverflowPolicy::DropOldest`](crate::OverflowPolicy::DropOldest)) are answered with
+    /// `503 Service Unavailable` and a `Retry-After` header.
+    pub fn with_pool<A: ToSocketAddrs>(addr: A, pool: ThreadPool) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+        let shutdown = ShutdownHandle {
+            requested: Arc::new(AtomicBool::new(false)),
+            addrs: Arc::new(Mutex::new(vec![listener.local_addr()?])),
+        };
+
+        Ok(Server {
+            listeners: vec![Listener {
+                socket: listener,
+                #[cfg(feature = "tls")]
+                tls: None,
+            }],
+            app: Arc::new(default_router(pool.stats_handle())),
+            access_log: None,
+            backend: Backend::default(),
+            pool,
+            shutdown,
+            deadline: Duration::from_secs(30),
+            limits: Limits {
+                read_timeout: Duration::from_secs(5),
+                write_timeout: Duration::from_secs(5),
+                request_timeout: Duration::from_secs(10),
+                handler_timeout: Duration::from_secs(30),
+                max_header_size: 8 * 1024,
+                max_body_size: 10 * 1024 * 1024,
+            },
+            per_ip: None,
+            upgrades: Upgrades::new(1024),
+            #[cfg(feature = "tls")]
+            redirect_to_https: false,
+        })
+    }
+
+    /// Also accept plain HTTP connections on `addr`.
+    ///
+    /// Can be called more than once. Connections on every listener are served by the same pool
+    /// and handler, see [`VirtualHosts`](crate::vhost::VirtualHosts) for serving different
+    /// sites.
+    pub fn listen<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Server> {
+        let socket = TcpListener::bind(addr)?;
+        self.shutdown.add_addr(socket.local_addr()?);
+        self.listeners.push(Listener {
+            socket,
+            #[cfg(feature = "tls")]
+            tls: None,
+        });
+        Ok(self)
+    }
+
+    /// Also accept HTTPS connections on `addr`, next to the plain HTTP listener.
+    ///
+    /// Can be called more than once. Connections on every listener are served by the same pool.
+    #[cfg(feature = "tls")]
+    pub fn listen_tls<A: ToSocketAddrs>(
+        mut self,
+        addr: A,
+        config: TlsConfig,
+    ) -> io::Result<Server> {
+        let socket = TcpListener::bind(addr)?;
+        self.shutdown.add_addr(socket.local_addr()?);
+        self.listeners.push(Listener {
+            socket,
+            tls: Some(config),
+        });
+        Ok(self)
+    }
+
+    /// Answer every request on the plain HTTP listeners with `301 Moved Permanently` to the
+    /// same path on the first HTTPS listener, instead of serving it.
+    #[cfg(feature = "tls")]
+    pub fn redirect_to_https(mut self, redirect: bool) -> Server {
+        self.redirect_to_https = redirect;
+        self
+    }
+
+    /// Answer requests with `handler` instead of [`default_router`].
+    pub fn handler(mut self, handler: impl Handler) -> Server {
+        self.app = Arc::new(handler);
+        self
+    }
+
+    /// Log every response to `log`: client, request line, status, body size and duration.
+    ///
+    /// Requests the server answers itself (400, 408, 431) are logged too. Responses to
+    /// connections the pool turned away (503) and HTTPS redirects are not.
+    pub fn access_log(mut self, log: AccessLog) -> Server {
+        self.access_log = Some(log);
+        self
+    }
+
+    /// Serve plain HTTP with `backend`. HTTPS listeners always use [`Backend::Threads`].
+    pub fn backend(mut self, backend: Backend) -> Server {
+        self.backend = backend;
+        self
+    }
+
+    /// How long in-flight requests get to finish once shutdown starts. Defaults to 30 seconds.
+    pub fn shutdown_timeout(mut self, deadline: Duration) -> Server {
+        self.deadline = deadline;
+        self
+    }
+
+    /// How long a single read from the client may block. A client that sends nothing for this
+    /// long gets `408 Request Timeout`. Defaults to 5 seconds.
+    pub fn read_timeout(mut self, timeout: Duration) -> Server {
+        self.limits.read_timeout = timeout;
+        self
+    }
+
+    /// How long a single write to the client may block before the connection is dropped.
+    /// Defaults to 5 seconds.
+    pub fn write_timeout(mut self, timeout: Duration) -> Server {
+        self.limits.write_timeout = timeout;
+        self
+    }
+
+    /// How long the client has to send the whole request head, however busily it trickles in
+    /// bytes. Answered with `408 Request Timeout` as well. Defaults to 10 seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> Server {
+        self.limits.request_timeout = timeout;
+        self
+    }
+
+    /// How long a handler may take once the request has arrived. Defaults to 30 seconds.
+    ///
+    /// A thread can't be stopped from the outside, so this is a deadline handlers check
+    /// themselves: `/sleep` gives up when it passes and answers `503 Service Unavailable`.
+    pub fn handler_timeout(mut self, timeout: Duration) -> Server {
+        self.limits.handler_timeout = timeout;
+        self
+    }
+
+    /// The largest request head (request line and headers) in bytes. Bigger requests get
+    /// `431 Request Header Fields Too Large`. Defaults to 8 KiB.
+    pub fn max_header_size(mut self, size: usize) -> Server {
+        self.limits.max_header_size = size;
+        self
+    }
+
+    /// The largest request body in bytes. Bigger ones get `413 Payload Too Large`, as soon as
+    /// the Content-Length says so or once that many bytes have arrived. Defaults to 10 MiB.
+    ///
+    /// Only the read timeout applies to bodies, not the request timeout: a big upload over a
+    /// slow connection takes a while.
+    pub fn max_body_size(mut self, size: usize) -> Server {
+        self.limits.max_body_size = size;
+        self
+    }
+
+    /// How many connections a single client IP may have open at once. Further connections
+    /// are answered with `429 Too Many Requests` and a `Retry-After` header (HTTPS ones are
+    /// just closed) before they get near the pool, so one client can't keep every worker busy.
+    /// Unlimited by default.
+    ///
+    /// For limiting requests rather than connections, see
+    /// [`RateLimit`](crate::middleware::RateLimit).
+    pub fn max_connections_per_ip(mut self, max: usize) -> Server {
+        self.per_ip = Some(PerIp::new(max));
+        self
+    }
+
+    /// How many connections may be upgraded at once, WebSockets and
+    /// [event streams](crate::sse::EventStream) together. Each of them has a thread of its own.
+    /// A response that would upgrade one more is replaced by `503 Service Unavailable`.
+    /// Defaults to 1024.
+    pub fn max_upgraded_connections(mut self, max: usize) -> Server {
+        self.upgrades = Upgrades::new(max);
+        self
+    }
+
+    /// The address of the plain HTTP listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].socket.local_addr()
+    }
+
+    /// The addresses of every listener, plain HTTP and HTTPS, in the order they were added.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.socket.local_addr())
+            .collect()
+    }
+
+    /// The address of the first HTTPS listener, if there is one.
+    #[cfg(feature = "tls")]
+    pub fn tls_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.listeners
+            .iter()
+            .find(|listener| listener.tls.is_some())
+            .map(|listener| listener.socket.local_addr())
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accept connections until shutdown is requested, then wait for in-flight requests.
+    pub fn run(self) {
+        // One accept loop per listener, the first one on this thread. All of them return once
+        // shutdown is requested
+        thread::scope(|scope| {
+            #[cfg(target_os = "linux")]
+            if self.backend == Backend::EventLoop {
+                let (tls, plain): (Vec<_>, Vec<_>) = self
+                    .listeners
+                    .iter()
+                    .partition(|listener| listener.is_tls());
+                for listener in tls {
+                    scope.spawn(|| self.accept(listener));
+                }
+                if let Err(e) = event_loop::run(&self, &plain) {
+                    eprintln!("Event loop failed: {}", e);
+                    // Stop the HTTPS accept loops too, or the scope never ends
+                    self.shutdown.shutdown();
+                }
+                return;
+            }
+
+            for listener in &self.listeners[1..] {
+                scope.spawn(|| self.accept(listener));
+            }
+            self.accept(&self.listeners[0]);
+        });
+
+        println!("Shutting down");
+
+        // Stop accepting before waiting on the workers, new clients get connection refused
+        // instead of hanging in the backlog
+        let Server {
+            listeners,
+            pool,
+            deadline,
+            ..
+        } = self;
+        drop(listeners);
+
+        let abandoned = pool.shutdown_timeout(deadline);
+        if abandoned > 0 {
+            eprintln!(
+                "{} worker(s) were still busy after {:?}",
+                abandoned, deadline
+            );
+        }
+    }
+
+    fn accept(&self, listener: &Listener) {
+        #[cfg(feature = "tls")]
+        let redirect = self.redirect_port(listener);
+
+        for stream in listener.socket.incoming() {
+            // Checked after every accept, the wake-up connection from ShutdownHandle::shutdown
+            // makes sure we get here even when no client is connecting
+            if self.shutdown.is_requested() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let slot = match stream.peer_addr() {
+                Ok(peer_addr) => self.slot(peer_addr.ip()),
+                // Already gone, handle_connection will find out
+                Err(_) => Some(Slot::uncounted()),
+            };
+            let Some(slot) = slot else {
+                // Same as for a 503 from Connection::drop, TLS clients just see the close
+                if !listener.is_tls() {
+                    refuse(stream, too_many_connections());
+                }
+                continue;
+            };
+
+            let connection = Connection {
+                stream: Some(stream),
+                slot,
+                upgrades: Arc::clone(&self.upgrades),
+                app: Arc::clone(&self.app),
+                access_log: self.access_log.clone(),
+                limits: self.limits,
+                shutdown: self.shutdown.clone(),
+                #[cfg(feature = "tls")]
+                tls: listener.tls.clone(),
+                #[cfg(feature = "tls")]
+                redirect,
+            };
+            let priority = connection.priority();
+
+            // If the pool refuses the job, the closure and the connection inside it are dropped,
+            // which sends the 503
+            if let Err(e) = self.pool.execute_with_priority(priority, || {
+                connection.handle();
+            }) {
+                eprintln!("Rejecting connection: {}", e);
+            }
+        }
+    }
+
+    // Counts a connection towards its client's limit, None if the client is at the limit
+    fn slot(&self, client: IpAddr) -> Option<Slot> {
+        let slot = per_ip::acquire(self.per_ip.as_ref(), client);
+        if slot.is_none() {
+            eprintln!("Rejecting connection from {}: too many connections", client);
+        }
+        slot
+    }
+
+    // Where plain HTTP connections on `listener` are sent, if they are redirected
+    #[cfg(feature = "tls")]
+    fn redirect_port(&self, listener: &Listener) -> Option<u16> {
+        if !self.redirect_to_https || listener.tls.is_some() {
+            return None;
+        }
+        let https = self
+            .listeners
+            .iter()
+            .find(|listener| listener.tls.is_some())?;
+        https.socket.local_addr().ok().map(|addr| addr.port())
+    }
+}
+
+impl Listener {
+    fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+}
+
+impl ShutdownHandle {
+    /// Ask the server to stop accepting connections. Returns immediately, [`Server::run`]
+    /// returns once in-flight requests are done.
+    pub fn shutdown(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // accept() blocks, so connect once ourselves to wake each listener up. They then see
+        // the flag and leave their loops
+        let addrs = self
+            .addrs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for addr in addrs {
+            let _ = TcpStream::connect(wake_addr(addr));
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    fn add_addr(&self, addr: SocketAddr) {
+        self.addrs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(addr);
+    }
+}
+
+// A listener bound to 0.0.0.0 or [::] can't be connected to as is, use loopback instead
+fn wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    addr
+}
+
+// How long clients are asked to wait before retrying after a 503, or a 429 for too many
+// connections
+const RETRY_AFTER_SECS: u64 = 1;
+
+// Health checks and metrics scrapes are cheap, and a load balancer that doesn't get an answer
+// takes the server out of rotation, so they jump the queue. /sleep goes to the back.
+//
+// peek leaves the bytes in the socket for handle_connection to read. It doesn't wait: there is
+// one accept loop for every client, and a client that connects and sends nothing would hold it
+// up for everyone else. A request that hasn't arrived by the time its connection is accepted
+// gets normal priority. Usually it has, a client sends it right after the handshake, and by the
+// time the loop gets to a connection on a busy server it's long there
+fn classify(stream: &TcpStream) -> Priority {
+    let mut buffer = [0; 32];
+    if stream.set_nonblocking(true).is_err() {
+        return Priority::Normal;
+    }
+    // WouldBlock if nothing is there yet
+    let read = stream.peek(&mut buffer).unwrap_or(0);
+    if let Err(e) = stream.set_nonblocking(false) {
+        eprintln!("Failed to make connection blocking again: {}", e);
+    }
+
+    priority_of(&buffer[..read])
+}
+
+// The priority for a request that starts with `request`
+fn priority_of(request: &[u8]) -> Priority {
+    if request.starts_with(b"GET /health ") || request.starts_with(b"GET /metrics ") {
+        Priority::High
+    } else if request.starts_with(b"GET /sleep ") {
+        Priority::Low
+    } else {
+        Priority::Normal
+    }
+}
+
+// A connection that hasn't been handled yet. Queued jobs can be dropped without ever running
+// (full queue, drop-oldest, shutdown), so the 503 is sent from Drop: whichever way the job goes
+// away, the client gets an answer instead of a silently closed socket
+struct Connection {
+    stream: Option<TcpStream>,
+    // Counts towards max_connections_per_ip until the connection is done, or passed on to the
+    // Upgraded
+    slot: Slot,
+    upgrades: Arc<Upgrades>,
+    app: Arc<dyn Handler>,
+    access_log: Option<AccessLog>,
+    limits: Limits,
+    shutdown: ShutdownHandle,
+    // Set for connections on an HTTPS listener, the handshake happens on the worker
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    // Set for plain HTTP connections that are redirected to this HTTPS port
+    #[cfg(feature = "tls")]
+    redirect: Option<u16>,
+}
+
+// A connection after the TLS handshake, or without TLS
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+// Why read_request didn't return a request
+enum ReadError {
+    TimedOut,
+    TooLarge,
+    // The client hung up before finishing its request, there is nobody to answer
+    Closed,
+    Io(io::Error),
+}
+
+impl Connection {
+    fn handle(mut self) {
+        let Some(stream) = self.stream.take() else {
+            return;
+        };
+        let slot = mem::replace(&mut self.slot, Slot::uncounted());
+
+        #[cfg(feature = "tls")]
+        let stream = match &self.tls {
+            Some(tls) => match tls.accept(stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to start TLS session: {}", e);
+                    return;
+                }
+            },
+            None => Stream::Plain(stream),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream = Stream::Plain(stream);
+
+        #[cfg(feature = "tls")]
+        if let Some(port) = self.redirect {
+            tls::redirect(stream, &self.limits, port);
+            return;
+        }
+
+        handle_connection(
+            stream,
+            slot,
+            &*self.app,
+            &self.limits,
+            self.access_log.as_ref(),
+            &self.shutdown,
+            &self.upgrades,
+        );
+    }
+
+    // Encrypted requests can't be peeked at, HTTPS connections get normal priority
+    fn priority(&self) -> Priority {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return Priority::Normal;
+        }
+
+        self.stream.as_ref().map_or(Priority::Normal, classify)
+    }
+}
+
+impl Stream {
+    // The socket underneath, for timeouts
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // A plain text 503 is gibberish to a TLS client, and the handshake that would come
+        // first is too much work for the accept loop. HTTPS clients just see the connection close
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return;
+        }
+
+        if let Some(stream) = self.stream.take() {
+            refuse(stream, busy());
+        }
+    }
+}
+
+// Answer a connection the server won't serve and close it. This may happen on the accept loop's
+// thread, don't let a slow client stall it
+fn refuse(mut stream: TcpStream, response: Response) {
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+    let _ = response.write_to(&mut stream);
+}
+
+/// The routes the server answers by default: `/` and `/sleep` serve hello.html, `/health` and
+/// `/metrics` are for load balancers and monitoring, everything else gets 404.html. Responses
+/// are compressed for clients that accept it.
+///
+/// `stats` is what `/metrics` reports, usually the serving pool's
+/// [`ThreadPool::stats_handle`].
+pub fn default_router(stats: StatsHandle) -> Router {
+    Router::new()
+        .get("/", |_: Request| html_file(StatusCode::OK, "hello.html"))
+        .get("/sleep", |request: Request| {
+            // Simulating a slow request, one that keeps an eye on the handler deadline
+            let wanted = Duration::from_secs(5);
+            let allowed = request.deadline().map_or(wanted, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            thread::sleep(wanted.min(allowed));
+
+            if allowed < wanted {
+                return Response::new(StatusCode::SERVICE_UNAVAILABLE)
+                    .header("Connection", "close");
+            }
+            html_file(StatusCode::OK, "hello.html")
+        })
+        // For load balancers: if a worker got to this request, the server is up
+        .get("/health", |_: Request| {
+            Response::new(StatusCode::OK)
+                .header("Content-Type", "text/plain")
+                .body("OK")
+        })
+        // Pool stats in the Prometheus text format, generated instead of read from a file
+        .get("/metrics", move |_: Request| {
+            Response::new(StatusCode::OK)
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(stats.stats().to_prometheus("threadpool"))
+        })
+        .fallback(|_: Request| html_file(StatusCode::NOT_FOUND, "404.html"))
+        .layer(Compression::new())
+}
+
+// The book unwraps here, which takes the worker's job down with a panic when the file is
+// missing. The client deserves an answer either way
+fn html_file(status: StatusCode, filename: &str) -> Response {
+    Response::html_file(status, filename).unwrap_or_else(|e| {
+        eprintln!("Failed to open {}: {}", filename, e);
+        Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+    })
+}
+
+// For connections the pool turned away
+fn busy() -> Response {
+    Response::new(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", &RETRY_AFTER_SECS.to_string())
+        .header("Content-Type", "text/plain")
+        .body("Server is busy, please try again later.")
+}
+
+// For clients over max_connections_per_ip
+fn too_many_connections() -> Response {
+    Response::new(StatusCode::TOO_MANY_REQUESTS)
+        .header("Retry-After", &RETRY_AFTER_SECS.to_string())
+        .header("Connection", "close")
+        .header("Content-Type", "text/plain")
+        .body("Too many connections, please try again later.")
+}
+
+fn handle_connection(
+    mut stream: Stream,
+    slot: Slot,
+    app: &dyn Handler,
+    limits: &Limits,
+    access_log: Option<&AccessLog>,
+    shutdown: &ShutdownHandle,
+    upgrades: &Arc<Upgrades>,
+) {
+    let peer_addr = stream.tcp().peer_addr().ok();
+    let mut record = access_log.map(|_| Record::new(peer_addr));
+    let _ = stream.tcp().set_write_timeout(Some(limits.write_timeout));
+
+    let mut response = match receive(&mut stream, limits) {
+        Ok(buffer) => match Request::parse(&buffer) {
+            Ok(mut request) => {
+                if let Some(addr) = peer_addr {
+                    request = request.with_peer_addr(addr);
+                }
+                if let Some(record) = &mut record {
+                    record.request(&request);
+                }
+                match receive_body(&mut stream, request, &buffer, limits) {
+                    Ok(request) => {
+                        app.handle(request.with_deadline(Instant::now() + limits.handler_timeout))
+                    }
+                    Err(Some(response)) => response,
+                    Err(None) => return,
+                }
+            }
+            Err(e) => bad_request(e),
+        },
+        Err(Some(response)) => response,
+        Err(None) => return,
+    };
+
+    let upgrade_to = take_upgrade(&mut response, upgrades);
+    let status = response.status();
+    // The client may already be gone, nothing useful to do about it here except logging that
+    // no body made it
+    let sent = response.write_to(&mut stream).ok();
+
+    if let (Some(access_log), Some(mut record)) = (access_log, record) {
+        record.response(status, sent.unwrap_or(0));
+        access_log.record(record);
+    }
+    if let (Some(_), Some(upgrade_to)) = (sent, upgrade_to) {
+        upgrade(stream, upgrade_to, slot, limits, shutdown);
+    }
+}
+
+// A response's on_upgrade, with its permit
+struct Upgrade {
+    on_upgrade: OnUpgrade,
+    permit: Permit,
+}
+
+// The response's on_upgrade, if there is room for another upgraded connection. If there isn't,
+// the response is swapped for a 503, nothing of it has been sent yet
+fn take_upgrade(response: &mut Response, upgrades: &Arc<Upgrades>) -> Option<Upgrade> {
+    let on_upgrade = response.take_upgrade()?;
+    match upgrades::acquire(upgrades) {
+        Some(permit) => Some(Upgrade { on_upgrade, permit }),
+        None => {
+            eprintln!("Refusing upgrade: too many upgraded connections");
+            *response = busy();
+            None
+        }
+    }
+}
+
+// Hand the connection over after a 101. The new protocol may keep it open for hours, so it gets
+// a thread of its own and the worker (or the event loop) goes back to serving requests. The
+// client's slot and the permit go along and are given back when the thread is done
+fn upgrade(
+    stream: Stream,
+    upgrade: Upgrade,
+    slot: Slot,
+    limits: &Limits,
+    shutdown: &ShutdownHandle,
+) {
+    let tcp = stream.tcp();
+    if let Err(e) = tcp
+        .set_nonblocking(false)
+        .and_then(|()| tcp.set_read_timeout(None))
+        .and_then(|()| tcp.set_write_timeout(Some(limits.write_timeout)))
+    {
+        eprintln!("Failed to upgrade connection: {}", e);
+        return;
+    }
+
+    let Upgrade { on_upgrade, permit } = upgrade;
+    let upgraded = Upgraded {
+        stream,
+        shutdown: shutdown.clone(),
+        _slot: slot,
+        _permit: permit,
+    };
+    if let Err(e) = thread::Builder::new()
+        .name("upgraded".to_string())
+        .spawn(move || on_upgrade(upgraded))
+    {
+        eprintln!("Failed to spawn thread for upgraded connection: {}", e);
+    }
+}
+
+impl Upgraded {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.tcp().peer_addr()
+    }
+
+    /// Like [`TcpStream::set_read_timeout`]. There is none after the upgrade.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.tcp().set_read_timeout(timeout)
+    }
+
+    /// Like [`TcpStream::set_write_timeout`]. The server's write timeout is kept.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.tcp().set_write_timeout(timeout)
+    }
+
+    /// Whether the server that accepted this connection is shutting down.
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.is_requested()
+    }
+
+    // The socket underneath. Only for waiting on it (peek), reading from it directly would
+    // skip TLS
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        self.stream.tcp()
+    }
+
+    // Whether a read would return data without anything new arriving on the socket. TLS records
+    // are decrypted whole, a read that took only part of one leaves the rest in rustls
+    pub(crate) fn buffered(&mut self) -> bool {
+        match &mut self.stream {
+            Stream::Plain(_) => false,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream
+                .conn
+                .process_new_packets()
+                .map_or(true, |state| state.plaintext_bytes_to_read() > 0),
+        }
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn bad_request(error: ParseError) -> Response {
+    Response::new(StatusCode::BAD_REQUEST)
+        .header("Connection", "close")
+        .header("Content-Type", "text/plain")
+        .body(error.to_string())
+}
+
+// A body that is too big or malformed. The rest of it is never read, so the connection can't
+// be used for anything else
+fn body_error(error: BodyError) -> Response {
+    Response::from(error).header("Connection", "close")
+}
+
+// Whether the client waits for a 100 Continue before sending the body, as curl does for bigger
+// uploads. A client that gets the final response instead doesn't send the body at all
+fn expects_continue(request: &Request) -> bool {
+    request.version() == "HTTP/1.1"
+        && request
+            .header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+}
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+// Read the request head. If it takes too long or gets too big the error is the 408 or 431 to
+// answer with, None means there is nobody to answer
+fn receive(stream: &mut Stream, limits: &Limits) -> Result<Vec<u8>, Option<Response>> {
+    let status = match read_request(stream, limits) {
+        Ok(buffer) => return Ok(buffer),
+        Err(ReadError::TimedOut) => StatusCode::REQUEST_TIMEOUT,
+        Err(ReadError::TooLarge) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        Err(ReadError::Closed) => return Err(None),
+        Err(ReadError::Io(e)) => {
+            eprintln!("Failed to read request: {}", e);
+            return Err(None);
+        }
+    };
+
+    Err(Some(Response::new(status).header("Connection", "close")))
+}
+
+// Read until the blank line that ends the request head. Every read gets at most read_timeout and
+// never more than what is left of request_timeout, so neither a silent client nor one that
+// trickles in a byte at a time can keep the worker for longer than that
+fn read_request(stream: &mut Stream, limits: &Limits) -> Result<Vec<u8>, ReadError> {
+    let end = Instant::now() + limits.request_timeout;
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+
+    loop {
+        if head_complete(&request, limits.max_header_size)? {
+            return Ok(request);
+        }
+
+        let left = end.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ReadError::TimedOut);
+        }
+        // set_read_timeout(Some(0)) is an error, left is never zero here
+        let _ = stream
+            .tcp()
+            .set_read_timeout(Some(left.min(limits.read_timeout)));
+
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(ReadError::Closed),
+            Ok(read) => request.extend_from_slice(&chunk[..read]),
+            // Unix reports a read timeout as WouldBlock, Windows as TimedOut
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(ReadError::TimedOut)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(ReadError::Io(e)),
+        }
+    }
+}
+
+// Read the body the request head announces, if any, and add it to the request. `buffer` is
+// what receive read, the head and possibly the start of the body. Every read gets at most
+// read_timeout, but there is no limit for the whole body. Errors are like receive's
+fn receive_body(
+    stream: &mut Stream,
+    request: Request,
+    buffer: &[u8],
+    limits: &Limits,
+) -> Result<Request, Option<Response>> {
+    let mut reader = match BodyReader::new(request.headers(), limits.max_body_size) {
+        Ok(Some(reader)) => reader,
+        Ok(None) => return Ok(request),
+        Err(e) => return Err(Some(body_error(e))),
+    };
+    let start = head_len(buffer).unwrap_or(buffer.len());
+    let mut complete = reader
+        .feed(&buffer[start..])
+        .map_err(|e| Some(body_error(e)))?;
+
+    if !complete && expects_continue(&request) {
+        let _ = stream.write_all(CONTINUE).and_then(|()| stream.flush());
+    }
+    let _ = stream.tcp().set_read_timeout(Some(limits.read_timeout));
+
+    let mut chunk = [0; 8 * 1024];
+    while !complete {
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(None),
+            Ok(read) => {
+                complete = reader
+                    .feed(&chunk[..read])
+                    .map_err(|e| Some(body_error(e)))?
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(Some(
+                    Response::new(StatusCode::REQUEST_TIMEOUT).header("Connection", "close"),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                eprintln!("Failed to read request body: {}", e);
+                return Err(None);
+            }
+        }
+    }
+
+    let body = reader.finish().map_err(|e| Some(body_error(e)))?;
+    Ok(request.with_body(body))
+}
+
+// The length of the request head at the start of `request`, blank line included, if it is all
+// there
+fn head_len(request: &[u8]) -> Option<usize> {
+    request
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|end| end + 4)
+}
+
+// Whether `request` holds the whole request head, Err(TooLarge) once it is too big to ever be
+// complete
+fn head_complete(request: &[u8], max_header_size: usize) -> Result<bool, ReadError> {
+    // A body that follows the head doesn't count against the limit
+    match head_len(request) {
+        Some(len) if len <= max_header_size => Ok(true),
+        Some(_) => Err(ReadError::TooLarge),
+        None if request.len() > max_header_size => Err(ReadError::TooLarge),
+        None => Ok(false),
+    }
+}
@@ -0,0 +1,782 @@
+// The event loop backend
+
+// The thread backend spends a worker on a connection from accept to the last byte of the
+// response, and most of that time the worker just waits: for the request to trickle in, for the
+// client to take the response. Here a single thread does all the waiting. Sockets are
+// non-blocking, epoll says which of them are ready, and the pool only sees a connection once its
+// request (head and body) is complete. The worker runs the handler, renders the response into a buffer and
+// hands it back through a channel, waking the loop up with an eventfd. The loop then writes it
+// out as fast as the client takes it.
+//
+// This is the book's "single-threaded async I/O model", except that handlers still run on the
+// pool: they are ordinary blocking code (/sleep sleeps) and would stall every other connection
+// if the loop ran them itself.
+//
+// epoll is used level-triggered, the way poll(2) works: a socket is reported for as long as it
+// is readable (or writable), so nothing is lost if we don't drain it in one go.
+
+use super::per_ip::Slot;
+use super::upgrades::Upgrades;
+use super::{
+    bad_request, body_error, busy, expects_continue, head_complete, head_len, priority_of, refuse,
+    take_upgrade, too_many_connections, upgrade, Listener, Server, Stream, Upgrade, CONTINUE,
+};
+use crate::access_log::Record;
+use crate::request::{BodyReader, Request};
+use crate::response::{Response, StatusCode};
+use crate::Priority;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+// What the u64 in an epoll event stands for: listeners count up from 0, connections from
+// CONNECTION, their index in the slab added
+const WAKER: u64 = u64::MAX;
+const CONNECTION: u64 = 1 << 32;
+
+const MAX_EVENTS: usize = 256;
+const READ_CHUNK: usize = 4096;
+
+struct Epoll {
+    fd: OwnedFd,
+}
+
+// Workers write to it when a response is ready, which makes the loop's epoll_wait return
+struct Waker {
+    file: File,
+}
+
+// A response a worker rendered for the connection at `index`
+struct Reply {
+    index: usize,
+    bytes: Vec<u8>,
+    status: StatusCode,
+    body_len: u64,
+    upgrade: Option<Upgrade>,
+}
+
+// Travels with the job. If the job is dropped without running (full queue, drop-oldest,
+// shutdown) or the handler panics, Drop answers for it, the same way Connection::drop does in
+// the thread backend
+struct Responder {
+    index: usize,
+    sender: Sender<Reply>,
+    waker: Arc<Waker>,
+    upgrades: Arc<Upgrades>,
+    replied: bool,
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    // Counts towards max_connections_per_ip until the connection is closed, or upgraded and
+    // done
+    slot: Slot,
+    // Set for plain HTTP connections that are redirected to this HTTPS port
+    #[cfg(feature = "tls")]
+    redirect: Option<u16>,
+    record: Option<Record>,
+    state: State,
+}
+
+enum State {
+    Reading {
+        buffer: Vec<u8>,
+        started: Instant,
+        last_read: Instant,
+    },
+    // The head is in, the body is still coming
+    Body {
+        request: Box<Request>,
+        reader: BodyReader,
+        priority: Priority,
+        last_read: Instant,
+    },
+    // A worker has the request. The socket isn't watched in the meantime, a client that hangs
+    // up is noticed when the response is written
+    Handling,
+    Writing {
+        out: Vec<u8>,
+        written: usize,
+        last_write: Instant,
+        status: StatusCode,
+        body_len: u64,
+        // Takes the socket off the loop once the 101 is out
+        upgrade: Option<Upgrade>,
+    },
+}
+
+// What happened when a connection was ready
+enum Progress {
+    Pending,
+    // The request head is complete
+    Head(Vec<u8>),
+    // So is the body
+    Body,
+    // Answered by the loop itself, 431
+    Answer(Response),
+    // Written out completely (true) or failed (false)
+    Done(bool),
+}
+
+struct EventLoop<'a> {
+    server: &'a Server,
+    listeners: Vec<(&'a TcpListener, Option<u16>)>,
+    epoll: Epoll,
+    waker: Arc<Waker>,
+    sender: Sender<Reply>,
+    receiver: Receiver<Reply>,
+    // A slab: a closed connection leaves a None behind, its index is reused by the next one
+    connections: Vec<Option<Connection>>,
+    free: Vec<usize>,
+    open: usize,
+}
+
+// Serve `listeners` until shutdown is requested and the connections that are still open are
+// done, or the shutdown timeout has passed
+pub(super) fn run(server: &Server, listeners: &[&Listener]) -> io::Result<()> {
+    let epoll = Epoll::new()?;
+    let waker = Arc::new(Waker::new()?);
+    epoll.add(waker.file.as_raw_fd(), libc::EPOLLIN, WAKER)?;
+
+    for (token, listener) in listeners.iter().enumerate() {
+        listener.socket.set_nonblocking(true)?;
+        epoll.add(listener.socket.as_raw_fd(), libc::EPOLLIN, token as u64)?;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    EventLoop {
+        server,
+        listeners: listeners
+            .iter()
+            .map(|listener| (&listener.socket, redirect_port(server, listener)))
+            .collect(),
+        epoll,
+        waker,
+        sender,
+        receiver,
+        connections: Vec::new(),
+        free: Vec::new(),
+        open: 0,
+    }
+    .run()
+}
+
+#[cfg(feature = "tls")]
+fn redirect_port(server: &Server, listener: &Listener) -> Option<u16> {
+    server.redirect_port(listener)
+}
+
+#[cfg(not(feature = "tls"))]
+fn redirect_port(_server: &Server, _listener: &Listener) -> Option<u16> {
+    None
+}
+
+impl EventLoop<'_> {
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Vec::with_capacity(MAX_EVENTS);
+        let mut stop_at = None;
+
+        loop {
+            if stop_at.is_none() && self.server.shutdown.is_requested() {
+                // Stop accepting, connections that are already open get up to the shutdown
+                // timeout to finish
+                for (listener, _) in &self.listeners {
+                    self.epoll.delete(listener.as_raw_fd())?;
+                }
+                stop_at = Some(Instant::now() + self.server.deadline);
+            }
+            if let Some(stop_at) = stop_at {
+                if self.open == 0 || Instant::now() >= stop_at {
+                    return Ok(());
+                }
+            }
+
+            let timeout = self.next_deadline().into_iter().chain(stop_at).min();
+            self.epoll.wait(&mut events, timeout)?;
+
+            for event in &events {
+                // epoll_event is packed, copy the field out instead of matching on it in place
+                let token = event.u64;
+                match token {
+                    WAKER => self.replies(),
+                    token if token >= CONNECTION => self.ready((token - CONNECTION) as usize),
+                    token => self.accept(token as usize),
+                }
+            }
+            self.expire();
+        }
+    }
+
+    fn accept(&mut self, listener: usize) {
+        let (socket, redirect) = self.listeners[listener];
+        loop {
+            match socket.accept() {
+                Ok((stream, peer_addr)) => {
+                    if let Err(e) = self.add(stream, peer_addr, redirect) {
+                        eprintln!("Failed to register connection: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn add(
+        &mut self,
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        redirect: Option<u16>,
+    ) -> io::Result<()> {
+        let Some(slot) = self.server.slot(peer_addr.ip()) else {
+            // Still blocking, a short response fits into the socket's buffer anyway
+            refuse(stream, too_many_connections());
+            return Ok(());
+        };
+        stream.set_nonblocking(true)?;
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.connections.push(None);
+                self.connections.len() - 1
+            }
+        };
+        if let Err(e) = self
+            .epoll
+            .add(stream.as_raw_fd(), libc::EPOLLIN, token(index))
+        {
+            self.free.push(index);
+            return Err(e);
+        }
+
+        let now = Instant::now();
+        // Only HTTPS redirects need it
+        #[cfg(not(feature = "tls"))]
+        let _ = redirect;
+
+        self.connections[index] = Some(Connection {
+            stream,
+            peer_addr,
+            slot,
+            #[cfg(feature = "tls")]
+            redirect,
+            record: self
+                .server
+                .access_log
+                .as_ref()
+                .map(|_| Record::new(Some(peer_addr))),
+            state: State::Reading {
+                buffer: Vec::new(),
+                started: now,
+                last_read: now,
+            },
+        });
+        self.open += 1;
+        Ok(())
+    }
+
+    fn ready(&mut self, index: usize) {
+        let max_header_size = self.server.limits.max_header_size;
+        let Some(connection) = self.connections.get_mut(index).and_then(Option::as_mut) else {
+            return;
+        };
+
+        match connection.progress(max_header_size) {
+            Progress::Pending => {}
+            Progress::Head(head) => self.dispatch(index, head),
+            Progress::Body => self.body_complete(index),
+            Progress::Answer(response) => self.answer(index, response),
+            Progress::Done(sent) => self.close(index, sent),
+        }
+    }
+
+    // Parse a complete request head, then wait for the body or hand the request to the pool
+    fn dispatch(&mut self, index: usize, head: Vec<u8>) {
+        let limits = self.server.limits;
+        let Some(connection) = self.connections[index].as_mut() else {
+            return;
+        };
+
+        #[cfg(feature = "tls")]
+        if let Some(port) = connection.redirect {
+            let local_addr = connection.stream.local_addr().ok();
+            return self.answer(
+                index,
+                super::tls::redirect_response(&head, local_addr, port),
+            );
+        }
+
+        let request = match Request::parse(&head) {
+            Ok(request) => request.with_peer_addr(connection.peer_addr),
+            Err(e) => return self.answer(index, bad_request(e)),
+        };
+        if let Some(record) = &mut connection.record {
+            record.request(&request);
+        }
+        let priority = priority_of(&head);
+
+        let mut reader = match BodyReader::new(request.headers(), limits.max_body_size) {
+            Ok(Some(reader)) => reader,
+            Ok(None) => return self.handle(index, request, priority),
+            Err(e) => return self.answer(index, body_error(e)),
+        };
+        // What was read after the head is the start of the body
+        let start = head_len(&head).unwrap_or(head.len());
+        let complete = match reader.feed(&head[start..]) {
+            Ok(complete) => complete,
+            Err(e) => return self.answer(index, body_error(e)),
+        };
+        if !complete && expects_continue(&request) {
+            // A few bytes on a socket nothing has been written to, they fit into its buffer
+            let _ = connection.stream.write(CONTINUE);
+        }
+
+        // The socket stays watched for reading
+        connection.state = State::Body {
+            request: Box::new(request),
+            reader,
+            priority,
+            last_read: Instant::now(),
+        };
+        if complete {
+            self.body_complete(index);
+        }
+    }
+
+    fn body_complete(&mut self, index: usize) {
+        let Some(connection) = self.connections[index].as_mut() else {
+            return;
+        };
+        let State::Body {
+            request,
+            reader,
+            priority,
+            ..
+        } = std::mem::replace(&mut connection.state, State::Handling)
+        else {
+            return;
+        };
+        // unwatch only does this for connections that aren't Handling yet
+        let _ = self.epoll.delete(connection.stream.as_raw_fd());
+
+        match reader.finish() {
+            Ok(body) => self.handle(index, request.with_body(body), priority),
+            Err(e) => {
+                let reply = Reply::render(index, body_error(e), &self.server.upgrades);
+                self.start_writing(reply);
+            }
+        }
+    }
+
+    // Hand a complete request to the pool
+    fn handle(&mut self, index: usize, request: Request, priority: Priority) {
+        let request = request.with_deadline(Instant::now() + self.server.limits.handler_timeout);
+        self.unwatch(index);
+        let app = Arc::clone(&self.server.app);
+        let responder = Responder {
+            index,
+            sender: self.sender.clone(),
+            waker: Arc::clone(&self.waker),
+            upgrades: Arc::clone(&self.server.upgrades),
+            replied: false,
+        };
+
+        // If the pool refuses the job, dropping the responder queues the 503
+        if let Err(e) = self.server.pool.execute_with_priority(priority, move || {
+            responder.reply(app.handle(request));
+        }) {
+            eprintln!("Rejecting connection: {}", e);
+        }
+    }
+
+    // Answer from the loop, for responses that don't need a handler
+    fn answer(&mut self, index: usize, response: Response) {
+        self.unwatch(index);
+        let reply = Reply::render(index, response, &self.server.upgrades);
+        self.start_writing(reply);
+    }
+
+    // Responses from the workers
+    fn replies(&mut self) {
+        self.waker.reset();
+        while let Ok(reply) = self.receiver.try_recv() {
+            self.start_writing(reply);
+        }
+    }
+
+    fn start_writing(&mut self, reply: Reply) {
+        let index = reply.index;
+        let Some(connection) = self.connections.get_mut(index).and_then(Option::as_mut) else {
+            return;
+        };
+
+        connection.state = State::Writing {
+            out: reply.bytes,
+            written: 0,
+            last_write: Instant::now(),
+            status: reply.status,
+            body_len: reply.body_len,
+            upgrade: reply.upgrade,
+        };
+        let fd = connection.stream.as_raw_fd();
+        if let Err(e) = self.epoll.add(fd, libc::EPOLLOUT, token(index)) {
+            eprintln!("Failed to register connection: {}", e);
+            return self.close(index, false);
+        }
+
+        // Most responses fit into the socket's send buffer, no need to wait for epoll
+        self.ready(index);
+    }
+
+    // Stop watching a connection's socket, while a worker has it or before it is watched for
+    // writing instead
+    fn unwatch(&mut self, index: usize) {
+        if let Some(connection) = self.connections[index].as_mut() {
+            if !matches!(connection.state, State::Handling) {
+                let _ = self.epoll.delete(connection.stream.as_raw_fd());
+                connection.state = State::Handling;
+            }
+        }
+    }
+
+    // Closing the socket also removes it from epoll. `sent` says whether a response went out
+    // completely, for the access log
+    fn close(&mut self, index: usize, sent: bool) {
+        let Some(connection) = self.connections[index].take() else {
+            return;
+        };
+        self.free.push(index);
+        self.open -= 1;
+
+        let State::Writing {
+            status,
+            body_len,
+            upgrade: upgrade_to,
+            ..
+        } = connection.state
+        else {
+            return;
+        };
+        if let (Some(access_log), Some(mut record)) = (&self.server.access_log, connection.record) {
+            record.response(status, if sent { body_len } else { 0 });
+            access_log.record(record);
+        }
+
+        if let (true, Some(upgrade_to)) = (sent, upgrade_to) {
+            // The socket stays open this time, so it has to leave epoll by hand
+            let _ = self.epoll.delete(connection.stream.as_raw_fd());
+            let server = self.server;
+            upgrade(
+                Stream::Plain(connection.stream),
+                upgrade_to,
+                connection.slot,
+                &server.limits,
+                &server.shutdown,
+            );
+        }
+    }
+
+    // The earliest read, request or write timeout. A scan over every connection, which is
+    // cheap next to the system calls for a few thousand of them
+    fn next_deadline(&self) -> Option<Instant> {
+        let limits = &self.server.limits;
+        self.connections
+            .iter()
+            .flatten()
+            .filter_map(|connection| connection.deadline(limits))
+            .min()
+    }
+
+    // A client that is too slow sending the request gets a 408, one that is too slow taking
+    // the response is dropped
+    fn expire(&mut self) {
+        let now = Instant::now();
+        for index in 0..self.connections.len() {
+            let Some(connection) = &self.connections[index] else {
+                continue;
+            };
+            match connection.deadline(&self.server.limits) {
+                Some(deadline) if deadline <= now => {}
+                _ => continue,
+            }
+
+            match connection.state {
+                State::Reading { .. } | State::Body { .. } => self.answer(
+                    index,
+                    Response::new(StatusCode::REQUEST_TIMEOUT).header("Connection", "close"),
+                ),
+                _ => self.close(index, false),
+            }
+        }
+    }
+}
+
+impl Connection {
+    // Read or write as much as the socket takes right now
+    fn progress(&mut self, max_header_size: usize) -> Progress {
+        match &mut self.state {
+            State::Reading {
+                buffer, last_read, ..
+            } => {
+                let mut chunk = [0; READ_CHUNK];
+                loop {
+                    match self.stream.read(&mut chunk) {
+                        // Gone before finishing the request, there is nobody to answer
+                        Ok(0) => return Progress::Done(false),
+                        Ok(read) => {
+                            buffer.extend_from_slice(&chunk[..read]);
+                            *last_read = Instant::now();
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Progress::Pending
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            eprintln!("Failed to read request: {}", e);
+                            return Progress::Done(false);
+                        }
+                    }
+
+                    match head_complete(buffer, max_header_size) {
+                        Ok(true) => return Progress::Head(std::mem::take(buffer)),
+                        Ok(false) => {}
+                        Err(_) => {
+                            return Progress::Answer(
+                                Response::new(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+                                    .header("Connection", "close"),
+                            )
+                        }
+                    }
+                }
+            }
+            State::Body {
+                reader, last_read, ..
+            } => {
+                let mut chunk = [0; READ_CHUNK];
+                loop {
+                    match self.stream.read(&mut chunk) {
+                        Ok(0) => return Progress::Done(false),
+                        Ok(read) => {
+                            *last_read = Instant::now();
+                            match reader.feed(&chunk[..read]) {
+                                Ok(true) => return Progress::Body,
+                                Ok(false) => {}
+                                Err(e) => return Progress::Answer(body_error(e)),
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Progress::Pending
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            eprintln!("Failed to read request body: {}", e);
+                            return Progress::Done(false);
+                        }
+                    }
+                }
+            }
+            State::Writing {
+                out,
+                written,
+                last_write,
+                ..
+            } => loop {
+                if *written == out.len() {
+                    return Progress::Done(true);
+                }
+                match self.stream.write(&out[*written..]) {
+                    Ok(0) => return Progress::Done(false),
+                    Ok(n) => {
+                        *written += n;
+                        *last_write = Instant::now();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Progress::Pending,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    // The client may already be gone, nothing useful to do about it here
+                    Err(_) => return Progress::Done(false),
+                }
+            },
+            State::Handling => Progress::Pending,
+        }
+    }
+
+    // Same limits as the thread backend: every read within read_timeout, the whole head within
+    // request_timeout, every write within write_timeout. None while a worker has it
+    fn deadline(&self, limits: &super::Limits) -> Option<Instant> {
+        match &self.state {
+            State::Reading {
+                started, last_read, ..
+            } => Some((*last_read + limits.read_timeout).min(*started + limits.request_timeout)),
+            State::Body { last_read, .. } => Some(*last_read + limits.read_timeout),
+            State::Handling => None,
+            State::Writing { last_write, .. } => Some(*last_write + limits.write_timeout),
+        }
+    }
+}
+
+impl Reply {
+    fn render(index: usize, mut response: Response, upgrades: &Arc<Upgrades>) -> Reply {
+        let mut upgrade = take_upgrade(&mut response, upgrades);
+        let mut status = response.status();
+        let mut bytes = Vec::new();
+        let body_len = match response.write_to(&mut bytes) {
+            Ok(body_len) => body_len,
+            // A file body that couldn't be read. Nothing has been sent yet, so unlike the
+            // thread backend we can still answer properly
+            Err(e) => {
+                eprintln!("Failed to render response: {}", e);
+                bytes.clear();
+                status = StatusCode::INTERNAL_SERVER_ERROR;
+                upgrade = None;
+                Response::new(status)
+                    .write_to(&mut bytes)
+                    .expect("writing into a Vec")
+            }
+        };
+        Reply {
+            index,
+            bytes,
+            status,
+            body_len,
+            upgrade,
+        }
+    }
+}
+
+impl Responder {
+    fn reply(mut self, response: Response) {
+        self.send(response);
+    }
+
+    fn send(&mut self, response: Response) {
+        self.replied = true;
+        // The loop is gone once the server has shut down, the response has nowhere to go
+        if self
+            .sender
+            .send(Reply::render(self.index, response, &self.upgrades))
+            .is_ok()
+        {
+            self.waker.wake();
+        }
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if !self.replied {
+            let response = if thread::panicking() {
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR).header("Connection", "close")
+            } else {
+                busy()
+            };
+            self.send(response);
+        }
+    }
+}
+
+fn token(index: usize) -> u64 {
+    CONNECTION + index as u64
+}
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        // SAFETY: no pointers involved, the result is checked
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: a fresh descriptor that nothing else owns
+        Ok(Epoll {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn add(&self, fd: RawFd, interest: libc::c_int, token: u64) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, interest, token)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, interest: libc::c_int, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: interest as u32,
+            u64: token,
+        };
+        // SAFETY: event is a valid epoll_event that outlives the call
+        if unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Wait until something is ready or `until` has passed, None waits for as long as it takes
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, until: Option<Instant>) -> io::Result<()> {
+        // Rounded up, waking a little late is fine, waking early means spinning until the
+        // deadline has really passed
+        let timeout = until.map_or(-1, |until| {
+            let left = until.saturating_duration_since(Instant::now());
+            left.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+        });
+
+        events.clear();
+        // SAFETY: epoll_wait writes at most capacity events into the Vec's buffer, set_len
+        // only covers the ones it wrote
+        let ready = unsafe {
+            libc::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.capacity() as libc::c_int,
+                timeout,
+            )
+        };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            // A signal, e.g. the Ctrl-C that asks for shutdown. The loop checks and comes back
+            return if e.kind() == io::ErrorKind::Interrupted {
+                Ok(())
+            } else {
+                Err(e)
+            };
+        }
+        // SAFETY: see above
+        unsafe { events.set_len(ready as usize) };
+        Ok(())
+    }
+}
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        // SAFETY: no pointers involved, the result is checked
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: a fresh descriptor that nothing else owns
+        Ok(Waker {
+            file: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    // Adds one to the eventfd's counter, which makes it readable
+    fn wake(&self) {
+        let _ = (&self.file).write(&1u64.to_ne_bytes());
+    }
+
+    // Reading returns the counter and sets it back to zero
+    fn reset(&self) {
+        let mut counter = [0; 8];
+        let _ = (&self.file).read(&mut counter);
+    }
+}
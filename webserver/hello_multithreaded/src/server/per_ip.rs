@@ -0,0 +1,71 @@
+// Open connections per client IP, for Server::max_connections_per_ip
+//
+// Counted in the accept loop (or the event loop's add), before the pool sees the connection: a
+// client with four slow connections would otherwise hold all four workers of the thread backend,
+// and everyone else would wait behind it. A connection is counted from accept until it is
+// closed, whenever its Slot is dropped. An upgraded connection takes its Slot along (see
+// Upgraded), a WebSocket is a connection like any other.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[derive(Debug)]
+pub(super) struct PerIp {
+    max: usize,
+    // Clients without open connections are removed, the map only holds current clients
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// One open connection, given back on drop
+#[derive(Debug)]
+pub(super) struct Slot {
+    held: Option<(Arc<PerIp>, IpAddr)>,
+}
+
+impl PerIp {
+    pub(super) fn new(max: usize) -> Arc<PerIp> {
+        Arc::new(PerIp {
+            max,
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+// A slot for another connection from `client`, None if it has `max` open already. Without a
+// limit every connection gets one that doesn't count
+pub(super) fn acquire(limit: Option<&Arc<PerIp>>, client: IpAddr) -> Option<Slot> {
+    let Some(per_ip) = limit else {
+        return Some(Slot::uncounted());
+    };
+
+    let mut open = per_ip.open.lock().unwrap_or_else(PoisonError::into_inner);
+    if open.get(&client).copied().unwrap_or(0) >= per_ip.max {
+        return None;
+    }
+    *open.entry(client).or_insert(0) += 1;
+    Some(Slot {
+        held: Some((Arc::clone(per_ip), client)),
+    })
+}
+
+impl Slot {
+    pub(super) fn uncounted() -> Slot {
+        Slot { held: None }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some((per_ip, client)) = self.held.take() else {
+            return;
+        };
+        let mut open = per_ip.open.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = open.get_mut(&client) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&client);
+            }
+        }
+    }
+}
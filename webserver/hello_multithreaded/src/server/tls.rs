@@ -0,0 +1,167 @@
+// HTTPS
+
+// rustls does the TLS part. Its StreamOwned wraps a TcpStream and implements Read and Write, so
+// once a connection is wrapped, handle_connection doesn't know the difference. The handshake
+// happens lazily on the first read, on the worker, so a slow handshake never holds up the accept
+// loop, and the read and request timeouts cover it like any other read.
+
+use super::{receive, Limits, Stream};
+use crate::response::{Response, StatusCode};
+use rustls::pki_types::CertificateDer;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A certificate chain and private key for [`Server::listen_tls`](super::Server::listen_tls).
+///
+/// Cloning is cheap, clones share the same rustls configuration.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Load a PEM encoded certificate chain (leaf certificate first) and private key.
+    pub fn from_pem_files<C, K>(cert: C, key: K) -> io::Result<TlsConfig>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        TlsConfig::from_pem(&fs::read(cert)?, &fs::read(key)?)
+    }
+
+    /// Same as [`TlsConfig::from_pem_files`], for PEM data that is already in memory.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if there is no certificate or no key in the
+    /// data, and with [`io::ErrorKind::InvalidInput`] if the key doesn't fit the certificate.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> io::Result<TlsConfig> {
+        let certs =
+            rustls_pemfile::certs(&mut &*cert).collect::<Result<Vec<CertificateDer>, _>>()?;
+        if certs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no certificate found in PEM data",
+            ));
+        }
+        let key = rustls_pemfile::private_key(&mut &*key)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no private key found in PEM data",
+            )
+        })?;
+
+        // The provider is passed in rather than taken from the process wide default, which
+        // rustls can't pick on its own if another crate in the build enables a second one
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    pub(super) fn accept(&self, stream: TcpStream) -> io::Result<Stream> {
+        let connection =
+            ServerConnection::new(Arc::clone(&self.config)).map_err(io::Error::other)?;
+        Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+}
+
+// Written out because ServerConfig isn't Debug
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+// Without close_notify the client can't tell the end of the response from a cut connection,
+// rustls clients report it as an error
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Stream::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.conn.complete_io(&mut stream.sock);
+        }
+    }
+}
+
+// Send a plain HTTP client to the same host and path on the HTTPS port
+pub(super) fn redirect(mut stream: Stream, limits: &Limits, port: u16) {
+    let _ = stream.tcp().set_write_timeout(Some(limits.write_timeout));
+    let response = match receive(&mut stream, limits) {
+        Ok(request) => redirect_response(&request, stream.tcp().local_addr().ok(), port),
+        Err(Some(response)) => response,
+        Err(None) => return,
+    };
+    let _ = response.write_to(&mut stream);
+}
+
+// The 301 for `request`, which came in on `local_addr`
+pub(super) fn redirect_response(
+    request: &[u8],
+    local_addr: Option<SocketAddr>,
+    port: u16,
+) -> Response {
+    let request = String::from_utf8_lossy(request);
+
+    let path = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/");
+
+    // Without a Host header the best guess is the address the client connected to
+    let host = request
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("host").then(|| value.trim())
+        })
+        .map(|host| strip_port(host).to_string())
+        .or_else(|| {
+            let ip = local_addr?.ip();
+            Some(if ip.is_ipv6() {
+                format!("[{}]", ip)
+            } else {
+                ip.to_string()
+            })
+        })
+        .unwrap_or_else(|| "localhost".to_string());
+
+    let location = if port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, port, path)
+    };
+    // The Host header comes from the client, HeaderMap::insert rejects anything that would
+    // break the response
+    let mut response = Response::new(StatusCode::MOVED_PERMANENTLY);
+    if response
+        .headers_mut()
+        .insert("Location", &location)
+        .is_err()
+    {
+        response = Response::new(StatusCode::BAD_REQUEST);
+    }
+    response
+}
+
+// "example.com:80" -> "example.com", "[::1]:80" -> "[::1]"
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
@@ -0,0 +1,51 @@
+// Upgraded connections, for Server::max_upgraded_connections
+//
+// A WebSocket or an event stream leaves the server for a thread of its own (see upgrade in
+// server.rs), and keeps it for as long as the client stays, hours maybe. Without a limit a
+// client that opens them in a loop makes the server spawn threads until it runs out of memory.
+// A permit is taken when the response is rendered, before anything is sent, so a response that
+// would go over the limit can still be swapped for a 503. It is given back when the upgraded
+// connection's thread is done with it.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub(super) struct Upgrades {
+    max: usize,
+    open: AtomicUsize,
+}
+
+// One upgraded connection, given back on drop
+#[derive(Debug)]
+pub(super) struct Permit {
+    upgrades: Arc<Upgrades>,
+}
+
+impl Upgrades {
+    pub(super) fn new(max: usize) -> Arc<Upgrades> {
+        Arc::new(Upgrades {
+            max,
+            open: AtomicUsize::new(0),
+        })
+    }
+}
+
+// A permit for another upgraded connection, None if `max` are open already
+pub(super) fn acquire(upgrades: &Arc<Upgrades>) -> Option<Permit> {
+    upgrades
+        .open
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+            (open < upgrades.max).then_some(open + 1)
+        })
+        .ok()?;
+    Some(Permit {
+        upgrades: Arc::clone(upgrades),
+    })
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.upgrades.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,254 @@
+// Server-sent events
+//
+// A way for the server to push updates to a browser over a plain HTTP response that never
+// ends: the page opens it with `new EventSource("/progress")`, the server answers with
+// Content-Type: text/event-stream and then sends an event whenever it has one, as lines of text
+// ("data: 40%", an empty line ends the event). The browser reconnects by itself when the
+// response ends or the connection drops, with a Last-Event-ID header if the events had ids.
+//
+// Events come from an mpsc channel, like the messages in threads/message_passing: whatever
+// does the work gets the Sender and calls send() as it goes, the response holds the Receiver.
+// A stream can go on for hours, so like a WebSocket it doesn't keep a worker. The server sends
+// the head and hands the connection to a thread of its own, which waits on the channel and
+// writes the events as chunks. Like a WebSocket it still counts towards
+// Server::max_connections_per_ip while it's open, and towards Server::max_upgraded_connections:
+// a stream over that limit is answered with 503 instead.
+//
+// A stream that is quiet for a long time gets a comment line now and then (a heartbeat), or
+// proxies and load balancers in between take it for dead and cut it off. The thread also looks
+// at the connection every second, and when the client has gone away it drops the Receiver: the
+// next send() fails, which tells the sender to stop. When every Sender is dropped the response
+// ends.
+
+use crate::response::{Response, StatusCode};
+use crate::server::Upgraded;
+use std::io::{self, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+// How often the stream's thread looks up from the channel, to see if the client or the server
+// is gone
+const TICK: Duration = Duration::from_secs(1);
+
+/// One event for an [`EventStream`], what the browser's `EventSource` hands to its listeners.
+///
+/// ```
+/// use hello_multithreaded::sse::Event;
+///
+/// let event = Event::new("{\"done\": 40}").event("progress").id("7");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+}
+
+/// A response that streams the events from a channel until every sender is gone.
+///
+/// The handler keeps a [`Sender`](std::sync::mpsc::Sender) or passes it to whatever does the
+/// work, and returns the stream. A `send` that fails means the client has gone away.
+///
+/// ```
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::request::Request;
+/// use hello_multithreaded::response::Response;
+/// use hello_multithreaded::sse::{Event, EventStream};
+/// use std::sync::mpsc;
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let app = Router::new().get("/progress", |_: Request| -> Response {
+///     let (tx, rx) = mpsc::channel();
+///     thread::spawn(move || {
+///         for percent in (0..=100).step_by(10) {
+///             let event = Event::new(&percent.to_string()).event("progress");
+///             if tx.send(event).is_err() {
+///                 // Nobody is watching any more
+///                 return;
+///             }
+///             thread::sleep(Duration::from_millis(500));
+///         }
+///     });
+///     EventStream::new(rx).into()
+/// });
+/// ```
+#[derive(Debug)]
+pub struct EventStream {
+    receiver: Receiver<Event>,
+    heartbeat: Duration,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// An event with `data`, which may have more than one line. Without a name it's a
+    /// `message` event.
+    pub fn new(data: &str) -> Event {
+        Event {
+            data: data.to_string(),
+            event: None,
+            id: None,
+        }
+    }
+
+    /// The event's type, for `addEventListener` on the client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` contains a line break.
+    pub fn event(mut self, name: &str) -> Event {
+        assert!(!name.contains(['\r', '\n']), "event name with a line break");
+        self.event = Some(name.to_string());
+        self
+    }
+
+    /// The event's id. The browser sends the last one it saw as `Last-Event-ID` when it
+    /// reconnects, so the stream can pick up where it left off.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` contains a line break or a NUL.
+    pub fn id(mut self, id: &str) -> Event {
+        assert!(
+            !id.contains(['\r', '\n', '\0']),
+            "event id with a line break or NUL"
+        );
+        self.id = Some(id.to_string());
+        self
+    }
+
+    // In the text/event-stream format, with the empty line that ends it
+    fn encode(&self) -> Vec<u8> {
+        let mut out = String::new();
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", event));
+        }
+        // Every line gets its own data field, the browser joins them with \n again
+        for line in self.data.replace("\r\n", "\n").split(['\n', '\r']) {
+            out.push_str(&format!("data: {}\n", line));
+        }
+        out.push('\n');
+        out.into_bytes()
+    }
+}
+
+impl From<&str> for Event {
+    fn from(data: &str) -> Event {
+        Event::new(data)
+    }
+}
+
+impl From<String> for Event {
+    fn from(data: String) -> Event {
+        Event::new(&data)
+    }
+}
+
+impl EventStream {
+    /// Stream the events from `receiver`, with a heartbeat every 15 seconds.
+    pub fn new(receiver: Receiver<Event>) -> EventStream {
+        EventStream {
+            receiver,
+            heartbeat: Duration::from_secs(15),
+            retry: None,
+        }
+    }
+
+    /// How long the stream may be quiet before a heartbeat comment is sent. Shorter than the
+    /// idle timeout of anything between the server and the browser.
+    ///
+    /// Panics if `interval` is zero.
+    pub fn heartbeat(mut self, interval: Duration) -> EventStream {
+        assert!(!interval.is_zero(), "a heartbeat needs an interval");
+        self.heartbeat = interval;
+        self
+    }
+
+    /// How long the browser should wait before it reconnects, instead of its own default of
+    /// a few seconds.
+    pub fn retry(mut self, delay: Duration) -> EventStream {
+        self.retry = Some(delay);
+        self
+    }
+}
+
+impl From<EventStream> for Response {
+    fn from(stream: EventStream) -> Response {
+        Response::new(StatusCode::OK)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .header("Connection", "close")
+            .stream_body(move |upgraded| serve(upgraded, stream))
+    }
+}
+
+// Runs on the stream's own thread until the senders, the client or the server are gone
+fn serve(mut upgraded: Upgraded, stream: EventStream) {
+    // For peeking at the socket, see client_gone
+    if upgraded
+        .set_read_timeout(Some(Duration::from_millis(1)))
+        .is_err()
+    {
+        return;
+    }
+    let tick = stream.heartbeat.min(TICK);
+
+    if let Some(retry) = stream.retry {
+        let retry = format!("retry: {}\n\n", retry.as_millis());
+        if write_chunk(&mut upgraded, retry.as_bytes()).is_err() {
+            return;
+        }
+    }
+
+    let mut last_write = Instant::now();
+    loop {
+        let chunk = match stream.receiver.recv_timeout(tick) {
+            Ok(event) => event.encode(),
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                if upgraded.shutdown_requested() || client_gone(&upgraded) {
+                    break;
+                }
+                if last_write.elapsed() < stream.heartbeat {
+                    continue;
+                }
+                b": heartbeat\n\n".to_vec()
+            }
+        };
+        // The client is gone. Returning drops the receiver, the next send() fails
+        if write_chunk(&mut upgraded, &chunk).is_err() {
+            return;
+        }
+        last_write = Instant::now();
+    }
+
+    // The last chunk, the browser reconnects if it still wants events
+    let _ = upgraded
+        .write_all(b"0\r\n\r\n")
+        .and_then(|()| upgraded.flush());
+}
+
+fn write_chunk(upgraded: &mut Upgraded, chunk: &[u8]) -> io::Result<()> {
+    let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+    framed.extend_from_slice(chunk);
+    framed.extend_from_slice(b"\r\n");
+    upgraded.write_all(&framed)?;
+    upgraded.flush()
+}
+
+// Whether the client has closed the connection. It has nothing to say on an event stream, so
+// the only thing a read can find is the end of the stream. Writes notice too, but only once the
+// kernel has given up on the connection, usually the second write after the client left
+fn client_gone(upgraded: &Upgraded) -> bool {
+    match upgraded.tcp().peek(&mut [0; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+        ),
+    }
+}
@@ -0,0 +1,521 @@
+// HTML templates
+//
+// hello.html and 404.html are sent as they are on disk. Templates are for pages that change from
+// request to request: HTML with tags in it,
+//
+//   {% extends "layout.html" %}
+//   {% block content %}
+//     {% for post in posts %}
+//       <h2><a href="/posts/{{ post.slug }}">{{ post.title }}</a></h2>
+//     {% else %}
+//       <p>No posts yet.</p>
+//     {% endfor %}
+//   {% endblock %}
+//
+// that are filled in with values from a Context when rendered. Everything that is output gets
+// HTML escaped unless it goes through the raw filter, {{ body | raw }}, so a post title with a
+// <script> in it stays text.
+//
+// Templates are parsed once and then cached. With reload on, every render checks whether the
+// file changed and parses it again, for editing templates while the server runs.
+
+mod parse;
+
+use self::parse::{Node, Parsed, Path as ValuePath};
+use crate::response::{Response, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+// Includes and layouts deeper than this are taken for a loop
+const MAX_DEPTH: usize = 32;
+
+/// A directory of templates, parsed on first use and cached.
+///
+/// ```no_run
+/// use hello_multithreaded::template::{Context, Templates};
+///
+/// let templates = Templates::new("templates");
+/// let posts = vec![Context::new()
+///     .insert("title", "Hello!")
+///     .insert("slug", "hello")];
+/// let html = templates
+///     .render("posts.html", &Context::new().insert("posts", posts))
+///     .unwrap();
+/// ```
+pub struct Templates {
+    dir: PathBuf,
+    reload: bool,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+struct Cached {
+    template: Arc<Parsed>,
+    // Only looked at with reload on
+    modified: Option<SystemTime>,
+}
+
+/// The values a template is rendered with, by name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    values: BTreeMap<String, Value>,
+}
+
+/// A value in a [`Context`]. Usually made with `into()` from strings, numbers, bools, vectors
+/// and other contexts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Outputs nothing, is false and an empty list.
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+    /// A value with fields, `{{ post.title }}`.
+    Map(Context),
+}
+
+/// A template that couldn't be read, parsed or rendered.
+#[derive(Debug)]
+pub struct TemplateError {
+    template: String,
+    // 0 when the error isn't about a line
+    line: usize,
+    message: String,
+    source: Option<io::Error>,
+}
+
+// Renders one template, with everything it includes or extends
+struct Renderer<'a> {
+    templates: &'a Templates,
+    context: &'a Context,
+    // Loop variables, innermost last
+    scopes: Vec<(String, Value)>,
+    depth: usize,
+    out: String,
+}
+
+// The blocks a template and the layouts it extends define, the most specific one for each name,
+// with the name of the template it came from
+type Blocks<'a> = HashMap<&'a str, (&'a str, &'a [Node])>;
+
+impl Templates {
+    /// Templates from the files in `dir`, e.g. `templates.render("posts.html", ...)` reads
+    /// `dir/posts.html`. Nothing is read until a template is first rendered.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Templates {
+        Templates {
+            dir: dir.into(),
+            reload: false,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a template's file changed before every render, and parse it again if it
+    /// did. Off by default, it's for development.
+    pub fn reload(mut self, reload: bool) -> Templates {
+        self.reload = reload;
+        self
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let mut renderer = Renderer {
+            templates: self,
+            context,
+            scopes: Vec::new(),
+            depth: 0,
+            out: String::new(),
+        };
+        renderer.template(name)?;
+        Ok(renderer.out)
+    }
+
+    /// A `text/html` response with the rendered template. A template that doesn't render is
+    /// logged and answered with 500, the same as a missing static page.
+    pub fn response(&self, status: StatusCode, name: &str, context: &Context) -> Response {
+        match self.render(name, context) {
+            Ok(html) => Response::new(status)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(html),
+            Err(e) => {
+                eprintln!("Failed to render {}", e);
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<Parsed>, TemplateError> {
+        // Names come from templates and handlers, but they still shouldn't reach outside dir
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(TemplateError::io(
+                name,
+                io::Error::new(io::ErrorKind::InvalidInput, "not a relative path"),
+            ));
+        }
+        let path = self.dir.join(relative);
+
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let modified = if self.reload {
+            fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        } else {
+            None
+        };
+        if let Some(cached) = cache.get(name) {
+            if !self.reload || cached.modified == modified {
+                return Ok(Arc::clone(&cached.template));
+            }
+        }
+
+        let source = fs::read_to_string(&path).map_err(|e| TemplateError::io(name, e))?;
+        let template = Arc::new(parse::parse(name, &source)?);
+        cache.insert(
+            name.to_string(),
+            Cached {
+                template: Arc::clone(&template),
+                modified,
+            },
+        );
+        Ok(template)
+    }
+}
+
+impl fmt::Debug for Templates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Templates")
+            .field("dir", &self.dir)
+            .field("reload", &self.reload)
+            .finish()
+    }
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Add a value, replacing one with the same name.
+    pub fn insert(mut self, name: &str, value: impl Into<Value>) -> Context {
+        self.values.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+}
+
+impl Value {
+    // What {% if %} goes by
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(value) => *value,
+            Value::Int(value) => *value != 0,
+            Value::Float(value) => *value != 0.0,
+            Value::String(value) => !value.is_empty(),
+            Value::List(values) => !values.is_empty(),
+            Value::Map(context) => !context.values.is_empty(),
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Map(context) => context.get(name),
+            // posts.0 for the first post
+            Value::List(values) => values.get(name.parse::<usize>().ok()?),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Value {
+        Value::Int(value.into())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Int(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Value {
+        Value::Int(value.into())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Int(i64::try_from(value).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Float(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(value)
+    }
+}
+
+impl From<&String> for Value {
+    fn from(value: &String) -> Value {
+        Value::String(value.clone())
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Value {
+        Value::Map(context)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl TemplateError {
+    fn new(template: &str, line: usize, message: String) -> TemplateError {
+        TemplateError {
+            template: template.to_string(),
+            line,
+            message,
+            source: None,
+        }
+    }
+
+    fn io(template: &str, error: io::Error) -> TemplateError {
+        TemplateError {
+            template: template.to_string(),
+            line: 0,
+            message: error.to_string(),
+            source: Some(error),
+        }
+    }
+
+    /// The template the error is in, which may be one that the rendered template included or
+    /// extended.
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        Some(self.line).filter(|&line| line > 0)
+    }
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line() {
+            Some(line) => write!(f, "{}:{}: {}", self.template, line, self.message),
+            None => write!(f, "{}: {}", self.template, self.message),
+        }
+    }
+}
+
+impl Error for TemplateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_ref().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+impl Renderer<'_> {
+    fn template(&mut self, name: &str) -> Result<(), TemplateError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(TemplateError::new(
+                name,
+                0,
+                "includes or extends itself".to_string(),
+            ));
+        }
+
+        // The template and the layouts above it. The page at the top is the one that's output,
+        // with the blocks filled in from further down
+        let mut chain = vec![(name.to_string(), self.templates.get(name)?)];
+        while let Some(layout) = chain[chain.len() - 1].1.extends.clone() {
+            if chain.len() > MAX_DEPTH {
+                return Err(TemplateError::new(name, 0, "extends itself".to_string()));
+            }
+            let template = self.templates.get(&layout)?;
+            chain.push((layout, template));
+        }
+
+        let mut blocks = Blocks::new();
+        for (name, template) in &chain {
+            collect_blocks(name, &template.nodes, &mut blocks);
+        }
+        let (page, template) = &chain[chain.len() - 1];
+        self.nodes(page, &template.nodes, &blocks)?;
+
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn nodes(
+        &mut self,
+        template: &str,
+        nodes: &[Node],
+        blocks: &Blocks,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.out.push_str(text),
+                Node::Output { path, raw, line } => {
+                    let text = match self.lookup(template, path, *line)? {
+                        Value::Null => String::new(),
+                        Value::Bool(value) => value.to_string(),
+                        Value::Int(value) => value.to_string(),
+                        Value::Float(value) => value.to_string(),
+                        Value::String(value) => value.clone(),
+                        Value::List(_) | Value::Map(_) => {
+                            return Err(TemplateError::new(
+                                template,
+                                *line,
+                                format!("`{}` is a list or map, not text", path.join(".")),
+                            ))
+                        }
+                    };
+                    if *raw {
+                        self.out.push_str(&text);
+                    } else {
+                        escape_into(&mut self.out, &text);
+                    }
+                }
+                Node::If {
+                    negate,
+                    path,
+                    then,
+                    otherwise,
+                } => {
+                    // Undefined is false, for optional values
+                    let truthy = self.find(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.nodes(template, branch, blocks)?;
+                }
+                Node::For {
+                    item,
+                    path,
+                    body,
+                    empty,
+                    line,
+                } => {
+                    let items = match self.lookup(template, path, *line)? {
+                        Value::List(items) => items.clone(),
+                        Value::Null => Vec::new(),
+                        _ => {
+                            return Err(TemplateError::new(
+                                template,
+                                *line,
+                                format!("`{}` is not a list", path.join(".")),
+                            ))
+                        }
+                    };
+                    if items.is_empty() {
+                        self.nodes(template, empty, blocks)?;
+                    }
+                    let count = items.len();
+                    for (index, value) in items.into_iter().enumerate() {
+                        let meta = Context::new()
+                            .insert("index", index + 1)
+                            .insert("first", index == 0)
+                            .insert("last", index + 1 == count);
+                        self.scopes.push(("loop".to_string(), meta.into()));
+                        self.scopes.push((item.clone(), value));
+                        let result = self.nodes(template, body, blocks);
+                        self.scopes.truncate(self.scopes.len() - 2);
+                        result?;
+                    }
+                }
+                Node::Include { name } => self.template(name)?,
+                Node::Block { name, body } => match blocks.get(name.as_str()) {
+                    Some((from, body)) => self.nodes(from, body, blocks)?,
+                    None => self.nodes(template, body, blocks)?,
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Loop variables first, then the context
+    fn find(&self, path: &ValuePath) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let value = match self.scopes.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.context.get(first)?,
+        };
+        rest.iter()
+            .try_fold(value, |value, field| value.field(field))
+    }
+
+    fn lookup(
+        &self,
+        template: &str,
+        path: &ValuePath,
+        line: usize,
+    ) -> Result<&Value, TemplateError> {
+        self.find(path).ok_or_else(|| {
+            TemplateError::new(template, line, format!("`{}` is undefined", path.join(".")))
+        })
+    }
+}
+
+// Blocks found earlier in the chain win, they are from the more specific template
+fn collect_blocks<'a>(template: &'a str, nodes: &'a [Node], blocks: &mut Blocks<'a>) {
+    for node in nodes {
+        if let Node::Block { name, body } = node {
+            blocks.entry(name).or_insert((template, body));
+            collect_blocks(template, body, blocks);
+        }
+    }
+}
+
+fn escape_into(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
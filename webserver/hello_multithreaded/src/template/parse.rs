@@ -0,0 +1,282 @@
+// Template source to a tree of nodes
+//
+// There are three kinds of tags in the text: {{ value }} for output, {% ... %} for everything
+// with a body or an effect (if, for, include, extends, block) and {# ... #} for comments. The
+// source is split into text and tags first, then the tags are matched up: every {% if %} needs
+// its {% endif %} and so on.
+
+use super::TemplateError;
+
+// A dotted name like post.author.name
+pub(super) type Path = Vec<String>;
+
+#[derive(Debug)]
+pub(super) enum Node {
+    Text(String),
+    Output {
+        path: Path,
+        // The raw filter, no escaping
+        raw: bool,
+        line: usize,
+    },
+    If {
+        negate: bool,
+        path: Path,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        item: String,
+        path: Path,
+        body: Vec<Node>,
+        // {% else %}, for empty lists
+        empty: Vec<Node>,
+        line: usize,
+    },
+    Include {
+        name: String,
+    },
+    Block {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+#[derive(Debug)]
+pub(super) struct Parsed {
+    pub(super) extends: Option<String>,
+    pub(super) nodes: Vec<Node>,
+}
+
+// The tag that ended a run of nodes, and its line
+type End<'a> = (&'a str, usize);
+
+enum Token<'a> {
+    Text(String),
+    Output(&'a str, usize),
+    Tag(&'a str, usize),
+}
+
+struct Parser<'a> {
+    name: &'a str,
+    tokens: std::vec::IntoIter<Token<'a>>,
+}
+
+pub(super) fn parse(name: &str, source: &str) -> Result<Parsed, TemplateError> {
+    let mut tokens = tokenize(name, source)?;
+
+    // {% extends %} has to be the first tag, with nothing but whitespace before it
+    let mut extends = None;
+    let first = tokens
+        .iter()
+        .position(|token| !matches!(token, Token::Text(text) if text.trim().is_empty()));
+    if let Some(first) = first {
+        if let Token::Tag(content, line) = tokens[first] {
+            if let Some(layout) = content.strip_prefix("extends ") {
+                extends = Some(quoted(name, layout.trim(), line)?);
+                tokens.drain(..=first);
+            }
+        }
+    }
+
+    let mut parser = Parser {
+        name,
+        tokens: tokens.into_iter(),
+    };
+    let (nodes, _) = parser.nodes(&[], 0)?;
+    Ok(Parsed { extends, nodes })
+}
+
+// A quoted template name
+fn quoted(template: &str, quoted: &str, line: usize) -> Result<String, TemplateError> {
+    match quoted
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    {
+        Some(name) if !name.is_empty() => Ok(name.to_string()),
+        _ => Err(TemplateError::new(
+            template,
+            line,
+            format!("expected a quoted name, got {}", quoted),
+        )),
+    }
+}
+
+fn tokenize<'a>(name: &str, source: &'a str) -> Result<Vec<Token<'a>>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut rest = source;
+    let mut line = 1;
+
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        line += rest[..start].matches('\n').count();
+        rest = &rest[start..];
+
+        let close = match rest.get(..2) {
+            Some("{{") => "}}",
+            Some("{%") => "%}",
+            Some("{#") => "#}",
+            // A brace on its own, in CSS or JavaScript
+            _ => {
+                text.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let Some(end) = rest[2..].find(close) else {
+            return Err(TemplateError::new(
+                name,
+                line,
+                format!("{} is never closed", &rest[..2]),
+            ));
+        };
+        let content = &rest[2..2 + end];
+
+        if !text.is_empty() {
+            tokens.push(Token::Text(std::mem::take(&mut text)));
+        }
+        match close {
+            "}}" => tokens.push(Token::Output(content.trim(), line)),
+            "%}" => tokens.push(Token::Tag(content.trim(), line)),
+            _ => {}
+        }
+        line += content.matches('\n').count();
+        rest = &rest[2 + end + 2..];
+    }
+
+    text.push_str(rest);
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    Ok(tokens)
+}
+
+impl<'a> Parser<'a> {
+    // Nodes up to one of the `ends` tags, which is returned along with them as (tag, line). At
+    // the top level `ends` is empty and the nodes go on to the end of the template. `opened` is
+    // the line of the tag that needs the end, for the error if it's missing
+    fn nodes(
+        &mut self,
+        ends: &[&str],
+        opened: usize,
+    ) -> Result<(Vec<Node>, Option<End<'a>>), TemplateError> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text)),
+                Token::Output(content, line) => nodes.push(self.output(content, line)?),
+                Token::Tag(content, line) => {
+                    let keyword = content.split_whitespace().next().unwrap_or_default();
+                    if ends.contains(&keyword) {
+                        return Ok((nodes, Some((content, line))));
+                    }
+                    nodes.push(self.tag(content, line)?);
+                }
+            }
+        }
+
+        match ends.last() {
+            Some(end) => Err(self.error(opened, format!("missing {{% {} %}}", end))),
+            None => Ok((nodes, None)),
+        }
+    }
+
+    // {{ path }} or {{ path | raw }}
+    fn output(&self, content: &str, line: usize) -> Result<Node, TemplateError> {
+        let (path, raw) = match content.split_once('|') {
+            Some((path, filter)) if filter.trim() == "raw" => (path.trim(), true),
+            Some((_, filter)) => {
+                return Err(self.error(line, format!("unknown filter `{}`", filter.trim())))
+            }
+            None => (content, false),
+        };
+        Ok(Node::Output {
+            path: self.path(path, line)?,
+            raw,
+            line,
+        })
+    }
+
+    fn tag(&mut self, content: &str, line: usize) -> Result<Node, TemplateError> {
+        let mut words = content.split_whitespace();
+        match (words.next(), words.collect::<Vec<_>>().as_slice()) {
+            (Some("if"), condition) => self.if_tag(condition, line),
+            (Some("for"), [item, "in", path]) => {
+                let path = self.path(path, line)?;
+                let (body, end) = self.nodes(&["else", "endfor"], line)?;
+                let empty = match end {
+                    Some(("else", _)) => self.nodes(&["endfor"], line)?.0,
+                    _ => Vec::new(),
+                };
+                Ok(Node::For {
+                    item: item.to_string(),
+                    path,
+                    body,
+                    empty,
+                    line,
+                })
+            }
+            (Some("include"), [name]) => Ok(Node::Include {
+                name: quoted(self.name, name, line)?,
+            }),
+            (Some("block"), [name]) => {
+                let (body, _) = self.nodes(&["endblock"], line)?;
+                Ok(Node::Block {
+                    name: name.to_string(),
+                    body,
+                })
+            }
+            (Some("extends"), _) => Err(self.error(line, "extends must be the first tag".into())),
+            (Some(end), _) if ["elif", "else", "endif", "endfor", "endblock"].contains(&end) => {
+                Err(self.error(line, format!("unexpected {{% {} %}}", content)))
+            }
+            _ => Err(self.error(line, format!("unknown tag {{% {} %}}", content))),
+        }
+    }
+
+    // {% if [not] path %} ... [{% elif [not] path %} ...] [{% else %} ...] {% endif %}
+    fn if_tag(&mut self, condition: &[&str], line: usize) -> Result<Node, TemplateError> {
+        let (negate, path) = match condition {
+            ["not", path] => (true, path),
+            [path] => (false, path),
+            _ => return Err(self.error(line, "expected {% if [not] name %}".into())),
+        };
+        let path = self.path(path, line)?;
+
+        let (then, end) = self.nodes(&["elif", "else", "endif"], line)?;
+        let otherwise = match end {
+            // The rest of the chain, it shares this one's endif
+            Some((elif, line)) if elif.starts_with("elif") => {
+                let condition: Vec<_> = elif.split_whitespace().skip(1).collect();
+                vec![self.if_tag(&condition, line)?]
+            }
+            Some(("else", _)) => self.nodes(&["endif"], line)?.0,
+            _ => Vec::new(),
+        };
+
+        Ok(Node::If {
+            negate,
+            path,
+            then,
+            otherwise,
+        })
+    }
+
+    fn path(&self, path: &str, line: usize) -> Result<Path, TemplateError> {
+        let valid = |part: &str| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !path.split('.').all(valid) {
+            return Err(self.error(line, format!("`{}` is not a name", path)));
+        }
+        Ok(path.split('.').map(str::to_string).collect())
+    }
+
+    fn error(&self, line: usize, message: String) -> TemplateError {
+        TemplateError::new(self.name, line, message)
+    }
+}
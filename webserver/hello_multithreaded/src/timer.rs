@@ -0,0 +1,233 @@
+// Delayed and periodic jobs
+
+// A thread per timer that sleeps and then calls execute would be the obvious way, but a server
+// with a few hundred timers would carry a few hundred sleeping threads. Instead timers wait in a
+// heap ordered by deadline, and the pool's own workers look after it: a parked worker sleeps no
+// longer than until the next deadline, and whichever worker comes by first after a deadline
+// passed moves the timer's job into the queue like any other job.
+//
+// So timers fire on time as long as a worker is idle. When every worker is busy, a timer fires
+// as soon as one of them finishes its job.
+
+use crate::queue::{Priority, Task};
+use crate::Job;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+// Stored in Timers::next when there is no timer at all
+const NONE: u64 = u64::MAX;
+
+/// Stops a job started with
+/// [`ThreadPool::execute_every`](crate::ThreadPool::execute_every).
+///
+/// Dropping the handle doesn't stop the job, it keeps running until the pool shuts down.
+#[derive(Clone)]
+pub struct PeriodicHandle {
+    periodic: Arc<Periodic>,
+}
+
+pub(crate) struct Timers {
+    heap: Mutex<BinaryHeap<Timer>>,
+    // Deadline of the earliest timer as nanoseconds since `epoch`, so workers can check whether
+    // anything is due without taking the lock
+    next: AtomicU64,
+    epoch: Instant,
+    // Tie breaker for timers with the same deadline, they fire in the order they were added
+    seq: AtomicU64,
+}
+
+struct Timer {
+    at: Instant,
+    seq: u64,
+    kind: Kind,
+}
+
+enum Kind {
+    Once(Job),
+    Every(Arc<Periodic>),
+}
+
+struct Periodic {
+    job: Box<dyn Fn() + Send + Sync + 'static>,
+    interval: Duration,
+    cancelled: AtomicBool,
+    // A run that takes longer than the interval would otherwise pile up runs in the queue, the
+    // ticks that come while it's still running are skipped
+    running: AtomicBool,
+}
+
+// Clears Periodic::running when a run ends, panicked or not
+struct Running(Arc<Periodic>);
+
+impl Timers {
+    pub(crate) fn new() -> Timers {
+        Timers {
+            heap: Mutex::new(BinaryHeap::new()),
+            next: AtomicU64::new(NONE),
+            epoch: Instant::now(),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    // Returns true if the new timer is now the earliest one, parked workers have to be woken
+    // to shorten their sleep
+    pub(crate) fn add_once(&self, at: Instant, job: Job) -> bool {
+        self.add(at, Kind::Once(job))
+    }
+
+    pub(crate) fn add_every(
+        &self,
+        interval: Duration,
+        job: Box<dyn Fn() + Send + Sync + 'static>,
+    ) -> (PeriodicHandle, bool) {
+        let periodic = Arc::new(Periodic {
+            job,
+            interval,
+            cancelled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+        });
+        let earliest = self.add(
+            Instant::now() + interval,
+            Kind::Every(Arc::clone(&periodic)),
+        );
+        (PeriodicHandle { periodic }, earliest)
+    }
+
+    // The deadline of the earliest timer. Only compared against other values of itself, to
+    // notice that a timer was added while a worker was getting ready to park
+    pub(crate) fn next(&self) -> u64 {
+        self.next.load(Ordering::SeqCst)
+    }
+
+    // How long until the earliest timer is due, None if there are no timers
+    pub(crate) fn until_next(&self, now: Instant) -> Option<Duration> {
+        match self.next() {
+            NONE => None,
+            next => Some((self.epoch + Duration::from_nanos(next)).saturating_duration_since(now)),
+        }
+    }
+
+    // Take the jobs of every timer that is due. Periodic timers are put back for their next
+    // tick right away
+    pub(crate) fn take_due(&self, now: Instant) -> Vec<Task> {
+        let mut due = Vec::new();
+        if self.next() > self.nanos(now) {
+            return due;
+        }
+
+        let mut heap = self.lock();
+        while heap.peek().is_some_and(|timer| timer.at <= now) {
+            let timer = heap.pop().unwrap();
+            match timer.kind {
+                Kind::Once(job) => due.push(Task::new(job, Priority::Normal)),
+                Kind::Every(periodic) => {
+                    if periodic.cancelled.load(Ordering::SeqCst) {
+                        continue;
+                    }
+
+                    // Fixed rate: the next tick is one interval after this one was due, not
+                    // after it fired. If we're so late that it has passed already, skip ahead
+                    let mut at = timer.at + periodic.interval;
+                    if at <= now {
+                        at = now + periodic.interval;
+                    }
+
+                    if !periodic.running.swap(true, Ordering::SeqCst) {
+                        let running = Running(Arc::clone(&periodic));
+                        due.push(Task::new(Box::new(move || running.run()), Priority::Normal));
+                    }
+
+                    heap.push(Timer {
+                        at,
+                        seq: self.seq.fetch_add(1, Ordering::SeqCst),
+                        kind: Kind::Every(periodic),
+                    });
+                }
+            }
+        }
+        self.update_next(&heap);
+
+        due
+    }
+
+    // On shutdown: timers that haven't fired are dropped, along with whatever their jobs hold
+    pub(crate) fn clear(&self) {
+        let timers = std::mem::take(&mut *self.lock());
+        self.next.store(NONE, Ordering::SeqCst);
+        drop(timers);
+    }
+
+    fn add(&self, at: Instant, kind: Kind) -> bool {
+        let mut heap = self.lock();
+        heap.push(Timer {
+            at,
+            seq: self.seq.fetch_add(1, Ordering::SeqCst),
+            kind,
+        });
+
+        let before = self.next();
+        self.update_next(&heap);
+        self.next() < before
+    }
+
+    fn update_next(&self, heap: &BinaryHeap<Timer>) {
+        let next = heap.peek().map_or(NONE, |timer| self.nanos(timer.at));
+        self.next.store(next, Ordering::SeqCst);
+    }
+
+    fn nanos(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.epoch).as_nanos() as u64
+    }
+
+    // Timer jobs never run while the lock is held, same reasoning as JobQueue::lock
+    fn lock(&self) -> MutexGuard<'_, BinaryHeap<Timer>> {
+        self.heap.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PeriodicHandle {
+    /// Stop the job. A run that already started finishes, no new runs start.
+    pub fn cancel(&self) {
+        self.periodic.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.periodic.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Running {
+    fn run(self) {
+        (self.0.job)()
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::SeqCst);
+    }
+}
+
+// BinaryHeap is a max-heap, so the order is reversed: the earliest deadline is the greatest
+impl Ord for Timer {
+    fn cmp(&self, other: &Timer) -> CmpOrdering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Timer {}
@@ -0,0 +1,117 @@
+// Virtual hosts
+
+// Several sites behind one server, told apart by the Host header the client sends: the name
+// from the URL, with the port if it isn't the default one. Every site gets a handler of its own,
+// a Router or the Files of its document root, and they all share the server's pool. Requests
+// for names nobody registered go to the default host.
+//
+// HTTP/1.1 made the Host header mandatory for exactly this, a request without one can't be
+// routed and gets 400 (RFC 9112 3.2). HTTP/1.0 clients may not know about it, they get the
+// default host.
+
+use crate::handler::Handler;
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+
+/// Routes requests by their `Host` header.
+///
+/// Names are matched without the port and case-insensitively. `*.example.com` matches every
+/// subdomain of example.com, exact names are tried first. Everything else goes to the default
+/// host.
+///
+/// ```no_run
+/// use hello_multithreaded::files::Files;
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::vhost::VirtualHosts;
+///
+/// let hosts = VirtualHosts::new(Files::new("sites/default"))
+///     .host("docs.internal", Files::new("sites/docs"))
+///     .host("*.wiki.internal", Router::new());
+/// ```
+pub struct VirtualHosts {
+    // Lowercase names, wildcards with their leading *
+    hosts: Vec<(String, Box<dyn Handler>)>,
+    default: Box<dyn Handler>,
+}
+
+impl VirtualHosts {
+    /// Send requests for names without a host of their own to `default`.
+    pub fn new(default: impl Handler) -> VirtualHosts {
+        VirtualHosts {
+            hosts: Vec::new(),
+            default: Box::new(default),
+        }
+    }
+
+    /// Send requests for `name` to `handler`. A name added later for the same host replaces
+    /// the earlier one.
+    pub fn host(mut self, name: &str, handler: impl Handler) -> VirtualHosts {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.retain(|(known, _)| *known != name);
+        self.hosts.push((name, Box::new(handler)));
+        self
+    }
+
+    fn find(&self, host: &str) -> Option<&dyn Handler> {
+        let exact = self.hosts.iter().find(|(name, _)| name == host);
+        let wildcard = || {
+            self.hosts.iter().find(|(name, _)| {
+                name.strip_prefix('*').is_some_and(|domain| {
+                    host.strip_suffix(domain)
+                        .is_some_and(|subdomain| !subdomain.is_empty())
+                })
+            })
+        };
+        exact.or_else(wildcard).map(|(_, handler)| handler.as_ref())
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn handle(&self, request: Request) -> Response {
+        let host = match host_of(&request) {
+            Ok(host) => host,
+            Err(response) => return response,
+        };
+        match host.and_then(|host| self.find(&host)) {
+            Some(handler) => handler.handle(request),
+            None => self.default.handle(request),
+        }
+    }
+}
+
+// The name of the host the request is for, None from an HTTP/1.0 client that didn't say
+fn host_of(request: &Request) -> Result<Option<String>, Response> {
+    let mut hosts = request.headers().get_all("Host");
+    match (hosts.next(), hosts.next()) {
+        (Some(host), None) => match host_name(host) {
+            Some(name) => Ok(Some(name)),
+            None => Err(bad_request("malformed Host header")),
+        },
+        // Two hosts and we'd have to guess which one the client meant
+        (Some(_), Some(_)) => Err(bad_request("more than one Host header")),
+        (None, _) if request.version() == "HTTP/1.0" => Ok(None),
+        (None, _) => Err(bad_request("missing Host header")),
+    }
+}
+
+// "Example.com:8080" -> "example.com", "[::1]:8080" -> "[::1]". A trailing dot (example.com.)
+// names the same host. None for an IPv6 address without brackets: in ::1:8080 there's no
+// telling whether 8080 is the port or the last group of the address
+fn host_name(host: &str) -> Option<String> {
+    let host = host.trim();
+    let name = match host.rfind(':') {
+        // The colons inside an IPv6 address don't count
+        Some(colon) if host[colon..].contains(']') => host,
+        Some(colon) if !host.starts_with('[') && host[..colon].contains(':') => return None,
+        Some(colon) => &host[..colon],
+        None => host,
+    };
+    Some(name.trim_end_matches('.').to_ascii_lowercase())
+}
+
+fn bad_request(message: &str) -> Response {
+    Response::new(StatusCode::BAD_REQUEST)
+        .header("Content-Type", "text/plain")
+        .header("Connection", "close")
+        .body(message.to_string())
+}
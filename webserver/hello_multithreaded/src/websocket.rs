@@ -0,0 +1,594 @@
+// WebSocket connections, RFC 6455
+//
+// A WebSocket starts out as a GET with `Upgrade: websocket`. Endpoint answers it with 101
+// Switching Protocols, and through Response::on_upgrade the server hands the connection to a
+// thread of its own once the 101 is out. A conversation can go on for hours, with a worker per
+// connection a handful of chat clients would be enough to take the whole pool. The connection's
+// thread reads frames, answers pings, puts fragmented messages back together and calls the
+// WebSocketHandler for every complete message.
+//
+// Messages can be sent from any thread through the WebSocket handle. Reading and writing share
+// the connection behind a mutex, and the reader waits for data with peek on the socket
+// underneath, so a quiet client doesn't keep the lock from the writers.
+
+mod frame;
+
+use self::frame::{Frame, OpCode};
+use crate::handler::Handler;
+use crate::request::{Method, Request};
+use crate::response::{Response, StatusCode};
+use crate::server::Upgraded;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+// Every server appends this to the client's key, see accept_key
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// How often the reader looks up from waiting for data, to send pings and notice shutdown
+const TICK: Duration = Duration::from_secs(1);
+// How long the client gets to answer our Close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_CHUNK: usize = 4096;
+
+/// A complete message, put back together if the client sent it in fragments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// How a WebSocket connection ended: the code and reason of a Close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Called for what happens on a WebSocket connection, see [`Endpoint`].
+///
+/// The calls for one connection come from its own thread, one at a time and in order. A
+/// closure `Fn(&WebSocket, Message)` is a handler that only cares about messages.
+pub trait WebSocketHandler: Send + Sync + 'static {
+    /// The handshake is done. Clone `socket` to send messages later, from anywhere.
+    fn on_open(&self, _socket: &WebSocket) {}
+
+    fn on_message(&self, socket: &WebSocket, message: Message);
+
+    /// The connection is closed and nothing more can be sent. `frame` is the client's Close,
+    /// or [`CloseFrame::ABNORMAL`] if the connection ended without one: the client went away,
+    /// stopped answering pings or broke the protocol.
+    fn on_close(&self, _socket: &WebSocket, _frame: CloseFrame) {}
+}
+
+/// A route that upgrades to WebSocket:
+///
+/// ```no_run
+/// use hello_multithreaded::handler::Router;
+/// use hello_multithreaded::websocket::{Endpoint, Message, WebSocket};
+///
+/// let app = Router::new().get(
+///     "/echo",
+///     Endpoint::new(|socket: &WebSocket, message: Message| {
+///         let _ = socket.send(message);
+///     }),
+/// );
+/// ```
+///
+/// Requests without the handshake headers get `426 Upgrade Required`.
+pub struct Endpoint {
+    handler: Arc<dyn WebSocketHandler>,
+    settings: Settings,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    max_message_size: usize,
+    ping_interval: Duration,
+}
+
+/// Sends to one WebSocket connection, from any thread.
+///
+/// Cloning is cheap, every clone sends on the same connection.
+#[derive(Clone)]
+pub struct WebSocket {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    peer_addr: Option<SocketAddr>,
+    connection: Mutex<Connection>,
+}
+
+struct Connection {
+    upgraded: Upgraded,
+    // Nothing may follow a Close
+    close_sent: bool,
+}
+
+// Reads from the client, on the connection's thread
+struct Session<'a> {
+    socket: WebSocket,
+    handler: &'a dyn WebSocketHandler,
+    settings: Settings,
+    // A handle on the socket to wait on without the lock
+    probe: TcpStream,
+    buffer: Vec<u8>,
+    // The first fragments of a message that isn't complete yet
+    partial: Option<(OpCode, Vec<u8>)>,
+    last_seen: Instant,
+    ping_sent: bool,
+    // When we noticed our Close went out
+    closing: Option<Instant>,
+}
+
+impl CloseFrame {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    /// The client's Close had no code. Never sent.
+    pub const NO_STATUS: u16 = 1005;
+    /// The connection ended without a Close. Never sent.
+    pub const ABNORMAL: u16 = 1006;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    fn new(code: u16) -> CloseFrame {
+        CloseFrame {
+            code,
+            reason: String::new(),
+        }
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(bytes: Vec<u8>) -> Message {
+        Message::Binary(bytes)
+    }
+}
+
+impl From<&[u8]> for Message {
+    fn from(bytes: &[u8]) -> Message {
+        Message::Binary(bytes.to_vec())
+    }
+}
+
+impl<F> WebSocketHandler for F
+where
+    F: Fn(&WebSocket, Message) + Send + Sync + 'static,
+{
+    fn on_message(&self, socket: &WebSocket, message: Message) {
+        self(socket, message)
+    }
+}
+
+impl Endpoint {
+    pub fn new(handler: impl WebSocketHandler) -> Endpoint {
+        Endpoint {
+            handler: Arc::new(handler),
+            settings: Settings {
+                max_message_size: 16 * 1024 * 1024,
+                ping_interval: Duration::from_secs(30),
+            },
+        }
+    }
+
+    /// Messages bigger than this, all fragments together, close the connection with
+    /// [`CloseFrame::TOO_BIG`]. 16 MiB by default.
+    pub fn max_message_size(mut self, size: usize) -> Endpoint {
+        self.settings.max_message_size = size;
+        self
+    }
+
+    /// How long a connection can be quiet before the client is pinged. If it doesn't answer
+    /// within another interval the connection is dropped. 30 seconds by default.
+    pub fn ping_interval(mut self, interval: Duration) -> Endpoint {
+        self.settings.ping_interval = interval;
+        self
+    }
+}
+
+impl Handler for Endpoint {
+    fn handle(&self, request: Request) -> Response {
+        let key = match handshake(&request) {
+            Ok(key) => key,
+            Err(response) => return response,
+        };
+
+        let handler = Arc::clone(&self.handler);
+        let settings = self.settings;
+        Response::new(StatusCode::SWITCHING_PROTOCOLS)
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", &accept_key(key))
+            .on_upgrade(move |upgraded| serve(upgraded, &*handler, settings))
+    }
+}
+
+// The client's half of the handshake, section 4.2.1. Returns the client's key, or the response
+// that refuses the upgrade
+fn handshake(request: &Request) -> Result<&str, Response> {
+    if *request.method() != Method::Get {
+        return Err(Response::new(StatusCode::METHOD_NOT_ALLOWED).header("Allow", "GET"));
+    }
+    if !has_token(request, "Upgrade", "websocket") || !has_token(request, "Connection", "upgrade") {
+        return Err(Response::new(StatusCode::UPGRADE_REQUIRED)
+            .header("Upgrade", "websocket")
+            .header("Content-Type", "text/plain")
+            .body("This is a WebSocket endpoint."));
+    }
+    // 13 is the only version there is, the RFC says to tell other clients so
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::new(StatusCode::UPGRADE_REQUIRED)
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13"));
+    }
+    match request.header("Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16) => Ok(key),
+        _ => Err(Response::new(StatusCode::BAD_REQUEST)
+            .header("Content-Type", "text/plain")
+            .body("bad request: missing or invalid Sec-WebSocket-Key")),
+    }
+}
+
+// Whether a comma separated header like `Connection: keep-alive, Upgrade` has `token` in it
+fn has_token(request: &Request, name: &str, token: &str) -> bool {
+    request
+        .headers()
+        .get_all(name)
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+// Proof that the server read the handshake, so a cache or a confused server can't fake one
+fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+// Runs on the upgraded connection's thread for as long as the connection is open
+fn serve(upgraded: Upgraded, handler: &dyn WebSocketHandler, settings: Settings) {
+    let probe = match upgraded.tcp().try_clone().and_then(|probe| {
+        // A zero timeout would mean no timeout at all
+        let tick = TICK.min(settings.ping_interval);
+        probe.set_read_timeout(Some(tick.max(Duration::from_millis(1))))?;
+        Ok(probe)
+    }) {
+        Ok(probe) => probe,
+        Err(e) => {
+            eprintln!("Failed to start WebSocket connection: {}", e);
+            return;
+        }
+    };
+
+    let socket = WebSocket {
+        shared: Arc::new(Shared {
+            peer_addr: upgraded.peer_addr().ok(),
+            connection: Mutex::new(Connection {
+                upgraded,
+                close_sent: false,
+            }),
+        }),
+    };
+    let mut session = Session {
+        socket: socket.clone(),
+        handler,
+        settings,
+        probe,
+        buffer: Vec::new(),
+        partial: None,
+        last_seen: Instant::now(),
+        ping_sent: false,
+        closing: None,
+    };
+    let frame = session.run();
+
+    // The server closes the TCP connection first, so TIME_WAIT is on our side and not the
+    // client's. Clones of the handle kept by the handler fail to send from now on
+    socket.lock().close_sent = true;
+    let _ = session.probe.shutdown(Shutdown::Both);
+    if panic::catch_unwind(AssertUnwindSafe(|| handler.on_close(&socket, frame))).is_err() {
+        eprintln!("WebSocket handler panicked in on_close");
+    }
+}
+
+impl WebSocket {
+    pub fn send(&self, message: impl Into<Message>) -> io::Result<()> {
+        match message.into() {
+            Message::Text(text) => self.lock().write(OpCode::Text, text.as_bytes()),
+            Message::Binary(bytes) => self.lock().write(OpCode::Binary, &bytes),
+        }
+    }
+
+    /// Start the closing handshake. Messages can't be sent after this, the handler's
+    /// `on_close` is called once the client has answered or gave up. Closing twice does
+    /// nothing.
+    ///
+    /// Reasons longer than the 123 bytes a Close frame has room for are cut short.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.lock().close(code, reason)
+    }
+
+    /// Whether a Close went out, after which sending fails.
+    pub fn is_closed(&self) -> bool {
+        self.lock().close_sent
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.shared.peer_addr
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.shared
+            .connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("peer_addr", &self.shared.peer_addr)
+            .finish()
+    }
+}
+
+impl Connection {
+    fn write(&mut self, opcode: OpCode, payload: &[u8]) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "WebSocket is closed",
+            ));
+        }
+        let result = self
+            .upgraded
+            .write_all(&frame::encode(opcode, payload))
+            .and_then(|()| self.upgraded.flush());
+
+        // Half a frame can't be taken back, nothing sent after it would make sense to the
+        // client. Hanging up lets the reader see the end as well
+        if result.is_err() {
+            self.close_sent = true;
+            let _ = self.upgraded.tcp().shutdown(Shutdown::Both);
+        }
+        result
+    }
+
+    fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+
+        let mut payload = Vec::new();
+        if code != CloseFrame::NO_STATUS {
+            let mut end = reason.len().min(123);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.extend_from_slice(&code.to_be_bytes());
+            payload.extend_from_slice(&reason.as_bytes()[..end]);
+        }
+        let result = self.write(OpCode::Close, &payload);
+        self.close_sent = true;
+        result
+    }
+}
+
+impl Session<'_> {
+    // Read frames until the connection ends, and return how it ended
+    fn run(&mut self) -> CloseFrame {
+        let (handler, socket) = (self.handler, self.socket.clone());
+        if let Err(frame) = self.call(|| handler.on_open(&socket)) {
+            return frame;
+        }
+
+        loop {
+            let result = match frame::parse(&self.buffer, self.settings.max_message_size) {
+                Ok(Some((frame, used))) => {
+                    self.buffer.drain(..used);
+                    self.frame(frame)
+                }
+                Ok(None) => self.wait(),
+                Err(code) => Err(self.fail(code)),
+            };
+            if let Err(frame) = result {
+                return frame;
+            }
+        }
+    }
+
+    // Here and below, Err is the end of the connection
+    fn frame(&mut self, frame: Frame) -> Result<(), CloseFrame> {
+        match frame.opcode {
+            OpCode::Ping => {
+                // Fails once we sent a Close, there's no need to answer then
+                let _ = self.socket.lock().write(OpCode::Pong, &frame.payload);
+                Ok(())
+            }
+            OpCode::Pong => Ok(()),
+            OpCode::Close => Err(self.close_received(&frame.payload)),
+            OpCode::Text | OpCode::Binary => {
+                if self.partial.is_some() {
+                    return Err(self.fail(CloseFrame::PROTOCOL_ERROR));
+                }
+                if frame.fin {
+                    self.deliver(frame.opcode, frame.payload)
+                } else {
+                    self.partial = Some((frame.opcode, frame.payload));
+                    Ok(())
+                }
+            }
+            OpCode::Continuation => {
+                let Some((opcode, mut payload)) = self.partial.take() else {
+                    return Err(self.fail(CloseFrame::PROTOCOL_ERROR));
+                };
+                if payload.len() + frame.payload.len() > self.settings.max_message_size {
+                    return Err(self.fail(CloseFrame::TOO_BIG));
+                }
+                payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.deliver(opcode, payload)
+                } else {
+                    self.partial = Some((opcode, payload));
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, opcode: OpCode, payload: Vec<u8>) -> Result<(), CloseFrame> {
+        let message = match opcode {
+            OpCode::Text => match String::from_utf8(payload) {
+                Ok(text) => Message::Text(text),
+                Err(_) => return Err(self.fail(CloseFrame::INVALID_DATA)),
+            },
+            _ => Message::Binary(payload),
+        };
+        // Messages that were on their way when the handler closed are dropped, it's done
+        if self.socket.is_closed() {
+            return Ok(());
+        }
+
+        let (handler, socket) = (self.handler, self.socket.clone());
+        self.call(|| handler.on_message(&socket, message))
+    }
+
+    // A handler that panics takes its connection down with 1011, and nothing else
+    fn call(&mut self, f: impl FnOnce()) -> Result<(), CloseFrame> {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                eprintln!("WebSocket handler panicked");
+                Err(self.fail(CloseFrame::INTERNAL_ERROR))
+            }
+        }
+    }
+
+    // The client's Close, answered with the same code unless ours went out first
+    fn close_received(&mut self, payload: &[u8]) -> CloseFrame {
+        let frame = match payload {
+            [] => CloseFrame::new(CloseFrame::NO_STATUS),
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                match std::str::from_utf8(reason) {
+                    Ok(reason) if valid_close_code(code) => CloseFrame {
+                        code,
+                        reason: reason.to_string(),
+                    },
+                    Ok(_) => return self.fail(CloseFrame::PROTOCOL_ERROR),
+                    Err(_) => return self.fail(CloseFrame::INVALID_DATA),
+                }
+            }
+            [_] => return self.fail(CloseFrame::PROTOCOL_ERROR),
+        };
+        let _ = self.socket.close(frame.code, "");
+        frame
+    }
+
+    // Give up on a client that broke the protocol. It's told why, but not waited for
+    fn fail(&mut self, code: u16) -> CloseFrame {
+        let _ = self.socket.close(code, "");
+        CloseFrame::new(CloseFrame::ABNORMAL)
+    }
+
+    // Wait for more from the client, up to a TICK
+    fn wait(&mut self) -> Result<(), CloseFrame> {
+        let shutdown_requested = {
+            let mut connection = self.socket.lock();
+            // TLS may have decrypted more than the last read took, the socket won't tell
+            if connection.upgraded.buffered() {
+                drop(connection);
+                return self.read();
+            }
+            connection.upgraded.shutdown_requested()
+        };
+        if shutdown_requested {
+            let _ = self
+                .socket
+                .close(CloseFrame::GOING_AWAY, "server shutting down");
+        }
+        if self.closing.is_none() && self.socket.is_closed() {
+            self.closing = Some(Instant::now());
+        }
+
+        match self.probe.peek(&mut [0; 1]) {
+            Ok(0) => return Err(CloseFrame::new(CloseFrame::ABNORMAL)),
+            Ok(_) => return self.read(),
+            Err(e) if is_timeout(&e) => {}
+            Err(_) => return Err(CloseFrame::new(CloseFrame::ABNORMAL)),
+        }
+
+        // Nothing came in
+        if self
+            .closing
+            .is_some_and(|closing| closing.elapsed() >= CLOSE_TIMEOUT)
+        {
+            return Err(CloseFrame::new(CloseFrame::ABNORMAL));
+        }
+        let quiet = self.last_seen.elapsed();
+        if quiet >= self.settings.ping_interval.saturating_mul(2) {
+            return Err(CloseFrame::new(CloseFrame::ABNORMAL));
+        }
+        if quiet >= self.settings.ping_interval && !self.ping_sent {
+            self.ping_sent = true;
+            let _ = self.socket.lock().write(OpCode::Ping, b"");
+        }
+        Ok(())
+    }
+
+    // Data is waiting. The read happens under the lock: on a plain socket it returns right
+    // away, over TLS the bytes may be half a record and rustls waits up to a TICK for the rest
+    fn read(&mut self) -> Result<(), CloseFrame> {
+        let mut chunk = [0; READ_CHUNK];
+        let read = self.socket.lock().upgraded.read(&mut chunk);
+        match read {
+            Ok(0) => Err(CloseFrame::new(CloseFrame::ABNORMAL)),
+            Ok(read) => {
+                self.buffer.extend_from_slice(&chunk[..read]);
+                self.last_seen = Instant::now();
+                self.ping_sent = false;
+                Ok(())
+            }
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(_) => Err(CloseFrame::new(CloseFrame::ABNORMAL)),
+        }
+    }
+}
+
+// Codes a client may send, section 7.4. 1004 to 1006 and 1015 are reserved, the rest up to
+// 2999 is for later versions of the protocol
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// A read timeout is WouldBlock on Unix and TimedOut on Windows
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
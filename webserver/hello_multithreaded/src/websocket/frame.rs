@@ -0,0 +1,150 @@
+// WebSocket frames, RFC 6455 section 5
+//
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-------+-+-------------+-------------------------------+
+// |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
+// |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
+// |N|V|V|V|       |S|             |   (if payload len==126/127)   |
+// | |1|2|3|       |K|             |                               |
+// +-+-+-+-+-------+-+-------------+ - - - - - - - - - - - - - - - +
+// |     Extended payload length continued, if payload len == 127  |
+// + - - - - - - - - - - - - - - - +-------------------------------+
+// |                               | Masking-key, if MASK set to 1 |
+// +-------------------------------+-------------------------------+
+// | Masking-key (continued)       |          Payload Data         |
+// +-------------------------------- - - - - - - - - - - - - - - - +
+//
+// Clients mask every frame they send, servers never do.
+
+use super::CloseFrame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+#[derive(Debug)]
+pub(super) struct Frame {
+    pub(super) fin: bool,
+    pub(super) opcode: OpCode,
+    pub(super) payload: Vec<u8>,
+}
+
+impl OpCode {
+    fn from_u8(opcode: u8) -> Option<OpCode> {
+        Some(match opcode {
+            0x0 => OpCode::Continuation,
+            0x1 => OpCode::Text,
+            0x2 => OpCode::Binary,
+            0x8 => OpCode::Close,
+            0x9 => OpCode::Ping,
+            0xA => OpCode::Pong,
+            _ => return None,
+        })
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    // Control frames can't be fragmented and carry at most 125 bytes
+    pub(super) fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+// Parse the client frame at the start of `buffer`. Ok(None) means it isn't complete yet, the
+// usize is how many bytes of the buffer it took up. Errors are the close code to fail the
+// connection with.
+//
+// A payload longer than `max_payload` is refused as soon as its header is in, instead of
+// buffering it first
+pub(super) fn parse(buffer: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, u16> {
+    let [first, second, ..] = *buffer else {
+        return Ok(None);
+    };
+
+    let fin = first & 0x80 != 0;
+    // No extensions are negotiated, so the reserved bits must be 0
+    if first & 0x70 != 0 {
+        return Err(CloseFrame::PROTOCOL_ERROR);
+    }
+    let opcode = OpCode::from_u8(first & 0x0F).ok_or(CloseFrame::PROTOCOL_ERROR)?;
+    if second & 0x80 == 0 {
+        return Err(CloseFrame::PROTOCOL_ERROR);
+    }
+
+    let (len, mut at) = match second & 0x7F {
+        126 => match buffer.get(2..4) {
+            Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buffer.get(2..10) {
+            Some(bytes) => (u64::from_be_bytes(bytes.try_into().expect("8 bytes")), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(CloseFrame::PROTOCOL_ERROR);
+    }
+    if len > max_payload as u64 {
+        return Err(CloseFrame::TOO_BIG);
+    }
+    let len = len as usize;
+
+    let Some(mask) = buffer.get(at..at + 4) else {
+        return Ok(None);
+    };
+    let mask: [u8; 4] = mask.try_into().expect("4 bytes");
+    at += 4;
+    let Some(payload) = buffer.get(at..at + len) else {
+        return Ok(None);
+    };
+
+    let payload = payload
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        at + len,
+    )))
+}
+
+// A complete, unmasked server frame
+pub(super) fn encode(opcode: OpCode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode.as_u8());
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
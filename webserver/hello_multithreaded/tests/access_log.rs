@@ -0,0 +1,130 @@
+use hello_multithreaded::access_log::{AccessLog, LogFormat};
+use hello_multithreaded::server::Server;
+use std::path::Path;
+
+mod common;
+use common::{send, TempDir, TestServer};
+
+// Sends each request on its own connection, then shuts down. Once run has returned the log is
+// dropped, which waits for the writer to finish the file
+fn serve(log: AccessLog, requests: &[&[u8]]) {
+    let server = TestServer::start(Server::bind("127.0.0.1:0", 2).unwrap().access_log(log));
+    for request in requests {
+        send(server.addr(), request);
+    }
+    server.stop();
+}
+
+fn lines(path: &Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn logs_combined_format() {
+    let dir = TempDir::new("access-log-combined");
+    let path = dir.join("access.log");
+
+    serve(
+        AccessLog::open(&path, LogFormat::Combined).unwrap(),
+        &[
+            b"GET /health HTTP/1.1\r\nHost: localhost\r\nUser-Agent: probe/1.0\r\n\r\n",
+            b"GET /missing?q=\"x\" HTTP/1.1\r\nHost: localhost\r\nReferer: http://example.com/\r\n\r\n",
+            b"GET /\r\n\r\n",
+        ],
+    );
+
+    let lines = lines(&path);
+    assert_eq!(lines.len(), 3, "{:?}", lines);
+
+    // 127.0.0.1 - - [19/Oct/2026:10:00:00 +0000] "GET /health HTTP/1.1" 200 2 "-" "probe/1.0" 0.000
+    let health = &lines[0];
+    assert!(health.starts_with("127.0.0.1 - - ["), "{}", health);
+    assert!(health.contains(" +0000] \"GET /health HTTP/1.1\" 200 2 \"-\" \"probe/1.0\" "));
+
+    let missing = &lines[1];
+    assert!(missing.contains("\"GET /missing?q=\\\"x\\\" HTTP/1.1\" 404 "));
+    assert!(missing.contains(" \"http://example.com/\" \"-\" "));
+
+    // Not a request the server could parse
+    assert!(lines[2].contains("] \"-\" 400 "), "{}", lines[2]);
+}
+
+#[test]
+fn logs_json() {
+    let dir = TempDir::new("access-log-json");
+    let path = dir.join("access.log");
+
+    serve(
+        AccessLog::open(&path, LogFormat::Json).unwrap(),
+        &[b"GET /health?verbose=1 HTTP/1.1\r\nHost: localhost\r\n\r\n"],
+    );
+
+    let lines = lines(&path);
+    assert_eq!(lines.len(), 1);
+    let line = &lines[0];
+    assert!(
+        line.starts_with("{\"time\":\"") && line.ends_with('}'),
+        "{}",
+        line
+    );
+    assert!(line.contains(
+        "\"client\":\"127.0.0.1\",\"method\":\"GET\",\"path\":\"/health\",\
+         \"query\":\"verbose=1\",\"protocol\":\"HTTP/1.1\",\"status\":200,\"bytes\":2,"
+    ));
+    assert!(line.contains("\"duration_ms\":"));
+    assert!(line.contains("\"referer\":null,\"user_agent\":null"));
+}
+
+#[test]
+fn rotates_by_size() {
+    let dir = TempDir::new("access-log-rotate");
+    let path = dir.join("access.log");
+
+    // Every line is about 80 bytes, two fit into a file
+    let log = AccessLog::builder(&path)
+        .format(LogFormat::Common)
+        .max_size(200)
+        .keep(2)
+        .build()
+        .unwrap();
+    serve(
+        log,
+        &[&b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]; 7],
+    );
+
+    assert_eq!(lines(&path).len(), 1);
+    assert_eq!(lines(&dir.join("access.log.1")).len(), 2);
+    assert_eq!(lines(&dir.join("access.log.2")).len(), 2);
+    // The oldest lines are gone
+    assert!(!dir.join("access.log.3").exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_logs_too() {
+    use hello_multithreaded::server::Backend;
+
+    let dir = TempDir::new("access-log-event-loop");
+    let path = dir.join("access.log");
+
+    let server = Server::bind("127.0.0.1:0", 1)
+        .unwrap()
+        .backend(Backend::EventLoop)
+        .access_log(AccessLog::open(&path, LogFormat::Common).unwrap());
+    let server = TestServer::start(server);
+    let addr = server.addr();
+    send(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    server.stop();
+
+    let lines = lines(&path);
+    assert_eq!(lines.len(), 1);
+    assert!(
+        lines[0].contains("] \"GET /health HTTP/1.1\" 200 2 "),
+        "{}",
+        lines[0]
+    );
+}
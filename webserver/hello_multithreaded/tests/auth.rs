@@ -0,0 +1,216 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hello_multithreaded::files::Files;
+use hello_multithreaded::handler::{Handler, Router};
+use hello_multithreaded::middleware::{Auth, Htpasswd, Tokens};
+use hello_multithreaded::request::{Method, Request};
+use hello_multithreaded::response::{Body, Response, StatusCode};
+use hello_multithreaded::server::Server;
+
+mod common;
+use common::{send, TempDir, TestServer};
+
+fn whoami(request: Request) -> Response {
+    let principal = request.principal().unwrap_or("nobody").to_string();
+    Response::new(StatusCode::OK).body(principal)
+}
+
+fn get(app: &Router, path: &str, authorization: Option<&str>) -> Response {
+    let mut request = Request::new(Method::Get, path);
+    if let Some(authorization) = authorization {
+        request
+            .headers_mut()
+            .insert("Authorization", authorization)
+            .unwrap();
+    }
+    app.handle(request)
+}
+
+fn basic(user: &str, password: &str) -> String {
+    format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)))
+}
+
+fn text(mut response: Response) -> String {
+    match response.take_body() {
+        Body::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+        _ => String::new(),
+    }
+}
+
+#[test]
+fn basic_and_bearer_on_protected_routes() {
+    // Low cost, the real thing would take a while per test
+    let htpasswd = format!(
+        "# admins\nalice:{}\n\nbob:{}\n",
+        bcrypt::hash("wonderland", 4).unwrap(),
+        bcrypt::hash("builder", 4).unwrap()
+    );
+    let dir = TempDir::new("htpasswd");
+    let users = Htpasswd::open(dir.write(".htpasswd", htpasswd)).unwrap();
+    let tokens = Tokens::parse("deploy:s3cr3t-t0ken\nci:another-token\n").unwrap();
+
+    let app = Router::new()
+        .get("/", whoami)
+        .get("/admin", whoami)
+        .get("/admin/users", whoami)
+        .get("/administrator", whoami)
+        .layer(
+            Auth::new("the \"admin\" area")
+                .basic(users)
+                .bearer(tokens)
+                .protect("/admin/"),
+        );
+
+    // Unprotected, including paths that only start with the same letters
+    assert_eq!(text(get(&app, "/", None)), "nobody");
+    assert_eq!(text(get(&app, "/administrator", None)), "nobody");
+
+    let response = get(&app, "/admin/users", None);
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let challenges: Vec<&str> = response.headers().get_all("WWW-Authenticate").collect();
+    assert_eq!(
+        challenges,
+        [
+            "Basic realm=\"the \\\"admin\\\" area\", charset=\"UTF-8\"",
+            "Bearer realm=\"the \\\"admin\\\" area\"",
+        ]
+    );
+
+    let response = get(&app, "/admin", Some(&basic("alice", "wonderland")));
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(text(response), "alice");
+    let response = get(&app, "/admin/users", Some("bearer s3cr3t-t0ken"));
+    assert_eq!(text(response), "deploy");
+
+    for authorization in [
+        basic("alice", "builder"),
+        basic("mallory", "wonderland"),
+        "Basic not-base64!".to_string(),
+        "Digest username=\"alice\"".to_string(),
+        "Bearer".to_string(),
+    ] {
+        let response = get(&app, "/admin", Some(&authorization));
+        assert_eq!(
+            response.status(),
+            StatusCode::UNAUTHORIZED,
+            "{}",
+            authorization
+        );
+        assert!(!response
+            .headers()
+            .get_all("WWW-Authenticate")
+            .any(|challenge| challenge.contains("invalid_token")));
+    }
+    let response = get(&app, "/admin", Some("Bearer s3cr3t-t0keN"));
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response
+        .headers()
+        .get_all("WWW-Authenticate")
+        .any(|challenge| challenge.ends_with("error=\"invalid_token\"")));
+}
+
+#[test]
+fn rejects_files_it_cannot_check() {
+    // Apache's default MD5 and SHA1 formats
+    for contents in [
+        "alice:$apr1$qV8Xz2mQ$3a8RsKxVd3mZ0l9u0yX2C/",
+        "ok:$2y$04$012345678901234567890uQ0p7l1i8c7hL6m7dPq3yq0lq6qv8h1C\nalice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+    ] {
+        let error = Htpasswd::parse(contents).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+    assert!(
+        Htpasswd::parse("alice:$2y$04$012345678901234567890uQ0p7l1i8c7hL6m7dPq3yq0lq6qv8h1C")
+            .is_ok()
+    );
+    assert!(Tokens::parse("deploy\n").is_err());
+    assert!(Tokens::parse("deploy: has spaces\n").is_err());
+
+    // Everything is protected without a prefix, and with no users nobody gets in
+    let app = Router::new()
+        .get("/", whoami)
+        .layer(Auth::new("x").basic(Htpasswd::parse("").unwrap()));
+    let response = get(&app, "/", Some(&basic("alice", "")));
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers().get_all("WWW-Authenticate").count(), 1);
+}
+
+#[test]
+#[should_panic(expected = "realm can't contain control characters")]
+fn realm_with_a_line_break_panics() {
+    Auth::new("admin\r\narea").bearer(Tokens::parse("deploy:t0ken\n").unwrap());
+}
+
+#[test]
+fn protects_a_path_however_it_is_spelled() {
+    let dir = TempDir::with_files(
+        "auth-files",
+        &[("private/s.txt", "secret"), ("public.txt", "public")],
+    );
+    let app = || {
+        Router::new()
+            .nest("/static", Files::new(dir.path()).strip_prefix("/static"))
+            .layer(
+                Auth::new("x")
+                    .bearer(Tokens::parse("deploy:t0ken\n").unwrap())
+                    .protect("/static/private"),
+            )
+    };
+
+    // Files finds the same file for all of them
+    let spellings = [
+        "/static/private/s.txt",
+        "/static//private/s.txt",
+        "//static/private//s.txt",
+        "/static/%70rivate/s.txt",
+        "/static/%70%72%69%76%61%74%65/s.txt",
+        "/static/public/../private/s.txt",
+    ];
+    let router = app();
+    for target in spellings {
+        let response = get(&router, target, None);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", target);
+        let response = get(&router, target, Some("Bearer t0ken"));
+        assert_eq!(response.status(), StatusCode::OK, "{}", target);
+    }
+    // Built in code an escaped slash stays in its segment, where Auth and Files agree it's no
+    // path to the private directory
+    for target in ["/static/private%2Fs.txt", "/static/private%5Cs.txt"] {
+        let response = get(&router, target, None);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", target);
+    }
+
+    let server = TestServer::start(Server::bind("127.0.0.1:0", 2).unwrap().handler(app()));
+    let fetch = |target: &str| {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+        send(server.addr(), request.as_bytes())
+    };
+    for target in &spellings[..5] {
+        let response = fetch(target);
+        assert!(
+            response.starts_with("HTTP/1.1 401 "),
+            "{}: {}",
+            target,
+            response
+        );
+    }
+    assert!(fetch("/static/public.txt").starts_with("HTTP/1.1 200 "));
+    // A client resolves dot segments itself, one that doesn't is up to something. So is one
+    // that escapes a slash, which would be a separator again once Files decodes it
+    for target in [
+        "/static/public/../private/s.txt",
+        "/static/./private/s.txt",
+        "/static/private%2Fs.txt",
+        "/static/private%2fs.txt",
+        "/static/private%5Cs.txt",
+    ] {
+        let response = fetch(target);
+        assert!(
+            response.starts_with("HTTP/1.1 400 "),
+            "{}: {}",
+            target,
+            response
+        );
+    }
+    server.stop();
+}
@@ -0,0 +1,313 @@
+use hello_multithreaded::handler::Router;
+use hello_multithreaded::request::{Form, Method, Request};
+use hello_multithreaded::response::{Response, StatusCode};
+use hello_multithreaded::server::{Backend, Server};
+use serde::Deserialize;
+use std::fs;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+use common::{backends, send, TempDir, TestServer};
+
+fn post(content_type: &str, body: impl Into<Vec<u8>>) -> Request {
+    let mut request = Request::new(Method::Post, "/").with_body(body.into());
+    request
+        .headers_mut()
+        .insert("Content-Type", content_type)
+        .unwrap();
+    request
+}
+
+// Echoes what it got: the size of the raw body, and the `name` field of a form or JSON body
+fn start(backend: Backend) -> TestServer {
+    let app = Router::new()
+        .post("/size", |request: Request| {
+            let bytes = request.body().bytes().unwrap();
+            let sum: u64 = bytes.iter().map(|&b| b as u64).sum();
+            Response::new(StatusCode::OK).body(format!("{} {}", bytes.len(), sum))
+        })
+        .post("/form", |request: Request| match request.form() {
+            Ok(form) => {
+                Response::new(StatusCode::OK).body(form.get("name").unwrap_or_default().to_string())
+            }
+            Err(e) => e.into(),
+        });
+    let server = Server::bind("127.0.0.1:0", 2)
+        .unwrap()
+        .backend(backend)
+        .max_body_size(200 * 1024)
+        .handler(app);
+    TestServer::start(server)
+}
+
+fn body_of(response: &str) -> &str {
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+#[test]
+fn forms_and_typed_fields() {
+    let form =
+        Form::parse("name=Ferris+the+crab&age=7&tag=rust&tag=%F0%9F%A6%80&empty=&broken=%zz");
+    assert_eq!(form.get("name"), Some("Ferris the crab"));
+    assert_eq!(form.value::<u32>("age"), Ok(7));
+    assert_eq!(form.get_all("tag").collect::<Vec<_>>(), ["rust", "🦀"]);
+    assert_eq!(form.get("broken"), Some("%zz"));
+    assert_eq!(form.optional::<u32>("empty"), Ok(None));
+    assert_eq!(form.optional::<u32>("missing"), Ok(None));
+
+    let error = form.value::<u32>("missing").unwrap_err();
+    assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error.to_string(), "missing field `missing`");
+    let error = form.value::<u32>("name").unwrap_err();
+    assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    assert!(error.to_string().starts_with("field `name` is invalid: "));
+
+    let request = post(
+        "application/x-www-form-urlencoded; charset=UTF-8",
+        "name=Ferris&age=7",
+    );
+    assert_eq!(request.form().unwrap().value::<u8>("age"), Ok(7));
+
+    let request = post("text/plain", "name=Ferris");
+    let error = request.form().unwrap_err();
+    assert_eq!(error.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let response = Response::from(error);
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[test]
+fn json_bodies() {
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Crab {
+        name: String,
+        legs: u8,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    let request = post(
+        "application/json",
+        r#"{"name": "Ferris", "legs": 10, "tags": ["rust"]}"#,
+    );
+    assert_eq!(
+        request.json::<Crab>().unwrap(),
+        Crab {
+            name: "Ferris".to_string(),
+            legs: 10,
+            tags: vec!["rust".to_string()],
+        }
+    );
+    let request = post(
+        "application/merge-patch+json",
+        r#"{"name": "Ferris", "legs": 10}"#,
+    );
+    assert!(request.json::<Crab>().is_ok());
+
+    let error = post("application/json", r#"{"name": "Ferris""#)
+        .json::<Crab>()
+        .unwrap_err();
+    assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    assert!(error.to_string().starts_with("invalid JSON: "), "{}", error);
+    let error = post("application/json", r#"{"name": "Ferris", "legs": 300}"#)
+        .json::<Crab>()
+        .unwrap_err();
+    assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    let error = Request::new(Method::Post, "/").json::<Crab>().unwrap_err();
+    assert_eq!(error.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[test]
+fn multipart_uploads_go_to_temporary_files() {
+    let photo: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        b"preamble\r\n\
+          --XyZ\r\n\
+          Content-Disposition: form-data; name=\"title\"\r\n\
+          \r\n\
+          Holiday\r\n\
+          --XyZ\r\n\
+          Content-Disposition: form-data; name=\"photo\"; filename=\"C:\\Users\\ferris\\beach.jpg\"\r\n\
+          Content-Type: image/jpeg\r\n\
+          \r\n",
+    );
+    body.extend_from_slice(&photo);
+    body.extend_from_slice(
+        b"\r\n--XyZ\r\n\
+          Content-Disposition: form-data; name=\"notes\"; filename=\"\"\r\n\
+          \r\n\
+          \r\n--XyZ--\r\n",
+    );
+
+    let request = post("multipart/form-data; boundary=\"XyZ\"", body);
+    let mut multipart = request.multipart().unwrap();
+    assert_eq!(multipart.fields().get("title"), Some("Holiday"));
+    // The empty file input is left out
+    assert_eq!(multipart.files().len(), 1);
+
+    let file = multipart.file("photo").unwrap();
+    assert_eq!(file.filename(), "beach.jpg");
+    assert_eq!(file.content_type(), Some("image/jpeg"));
+    assert_eq!(file.size(), photo.len() as u64);
+    assert_eq!(fs::read(file.path()).unwrap(), photo);
+    // Only the server's user can read it
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(file.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // Dropping it deletes the temporary file, persisting keeps the contents elsewhere
+    let temporary = file.path().to_path_buf();
+    let dir = TempDir::new("kept");
+    let kept = dir.join("beach.jpg");
+    multipart
+        .take_file("photo")
+        .unwrap()
+        .persist(&kept)
+        .unwrap();
+    assert!(!temporary.exists());
+    assert_eq!(fs::read(&kept).unwrap(), photo);
+
+    let request = post("multipart/form-data", "");
+    assert_eq!(
+        request.multipart().unwrap_err().to_string(),
+        "multipart body without a boundary"
+    );
+    let request = post(
+        "multipart/form-data; boundary=XyZ",
+        "--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHoli",
+    );
+    assert_eq!(
+        request.multipart().unwrap_err().to_string(),
+        "multipart body ends early"
+    );
+}
+
+#[test]
+fn server_reads_bodies() {
+    for backend in backends() {
+        let server = start(backend);
+        let addr = server.addr();
+
+        let response = send(
+            addr,
+            b"POST /form HTTP/1.1\r\nHost: localhost\r\n\
+              Content-Type: application/x-www-form-urlencoded\r\n\
+              Content-Length: 15\r\n\r\n\
+              name=Ferris&x=1",
+        );
+        assert_eq!(body_of(&response), "Ferris", "{:?}", backend);
+
+        // Chunked, in pieces that split the size lines, with an extension and a trailer
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for piece in [
+            &b"POST /form HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-www-form-urlencoded\r\n"[..],
+            b"Transfer-Encoding: chunked\r\n\r\n5\r\nname=\r\n",
+            b"6;ext=1\r",
+            b"\nFerris\r\n0\r\nX-Trailer: yes\r\n\r\n",
+        ] {
+            stream.write_all(piece).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(body_of(&response), "Ferris", "{:?}", backend);
+
+        // Bigger than what is kept in memory, waiting for 100 Continue before sending it
+        let body: Vec<u8> = (0..150_000u32).map(|i| (i % 256) as u8).collect();
+        let sum: u64 = body.iter().map(|&b| b as u64).sum();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /size HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        let mut continue_ = [0; 25];
+        stream.read_exact(&mut continue_).unwrap();
+        assert_eq!(&continue_, b"HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(&body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            body_of(&response),
+            format!("150000 {}", sum),
+            "{:?}",
+            backend
+        );
+
+        server.stop();
+    }
+}
+
+#[test]
+fn refuses_bodies_that_are_too_big_or_malformed() {
+    for backend in backends() {
+        let server = start(backend);
+        let addr = server.addr();
+
+        // Refused by the Content-Length alone, the client doesn't have to send the body
+        let response = send(
+            addr,
+            b"POST /size HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 999999\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 413 "),
+            "{:?} {}",
+            backend,
+            response
+        );
+
+        // A chunked body is refused once it gets too big
+        let mut request =
+            b"POST /size HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n"
+                .to_vec();
+        for _ in 0..4 {
+            request.extend_from_slice(b"10000\r\n");
+            request.extend_from_slice(&[b'x'; 0x10000]);
+            request.extend_from_slice(b"\r\n");
+        }
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // The server may stop reading and close before all of it is written
+        let _ = stream.write_all(&request);
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        assert!(response.starts_with(b"HTTP/1.1 413 "), "{:?}", backend);
+
+        for (request, status) in [
+            (
+                &b"POST /size HTTP/1.1\r\nHost: localhost\r\nContent-Length: ten\r\n\r\n"[..],
+                "400",
+            ),
+            (
+                b"POST /size HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\nabc",
+                "400",
+            ),
+            (
+                b"POST /size HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+                "400",
+            ),
+            (
+                b"POST /size HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: gzip\r\n\r\n",
+                "501",
+            ),
+            (b"POST /form HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc", "415"),
+        ] {
+            let response = send(addr, request);
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {} ", status)),
+                "{:?} {}",
+                backend,
+                response
+            );
+        }
+
+        server.stop();
+    }
+}
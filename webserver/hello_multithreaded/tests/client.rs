@@ -0,0 +1,79 @@
+use hello_multithreaded::client::Client;
+use hello_multithreaded::request::{Method, Request};
+use hello_multithreaded::response::StatusCode;
+use hello_multithreaded::server::{Backend, Server};
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{backends, TestServer};
+
+// The server with its default routes, and a client for it
+fn start(backend: Backend) -> (TestServer, Client) {
+    let server = TestServer::start(Server::bind("127.0.0.1:0", 4).unwrap().backend(backend));
+    let client = Client::new(server.addr()).unwrap();
+    (server, client)
+}
+
+#[test]
+fn serves_hello_and_not_found() {
+    let hello = fs::read_to_string("hello.html").unwrap();
+    let not_found = fs::read_to_string("404.html").unwrap();
+
+    for backend in backends() {
+        let (_server, client) = start(backend);
+
+        let response = client.get("/").unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", backend);
+        assert!(response
+            .header("Content-Type")
+            .unwrap()
+            .starts_with("text/html"));
+        assert_eq!(response.text(), hello);
+
+        let response = client.get("/nothing/here?at=all").unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{:?}", backend);
+        assert_eq!(response.text(), not_found);
+
+        // Known path, wrong method
+        let request = Request::new(Method::Post, "/").with_body("hello");
+        let response = client.send(&request).unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(response.header("Allow").unwrap().contains("GET"));
+    }
+}
+
+#[test]
+fn sleep_requests_run_concurrently() {
+    for backend in backends() {
+        let (_server, client) = start(backend);
+        let started = Instant::now();
+
+        // Three of the four workers sleep for 5 seconds each, at the same time
+        let sleepers: Vec<_> = (0..3)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || client.get("/sleep").unwrap())
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(200));
+
+        // The fourth one is free for everybody else
+        let response = client.get("/").unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", backend);
+
+        for sleeper in sleepers {
+            assert_eq!(sleeper.join().unwrap().status(), StatusCode::OK);
+        }
+        // One after the other would have taken 15
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(5) && elapsed < Duration::from_secs(9),
+            "{:?} {:?}",
+            backend,
+            elapsed
+        );
+    }
+}
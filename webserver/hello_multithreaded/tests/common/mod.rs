@@ -0,0 +1,133 @@
+// Helpers for the integration tests. Every file in tests/ is a crate of its own with a `mod
+// common;`, and not every one of them uses everything in here
+#![allow(dead_code)]
+
+use hello_multithreaded::server::{Backend, Server, ShutdownHandle};
+use std::fs;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// Every backend this platform has, for tests that should pass on all of them
+pub fn backends() -> Vec<Backend> {
+    let mut backends = vec![Backend::Threads];
+    #[cfg(target_os = "linux")]
+    backends.push(Backend::EventLoop);
+    backends
+}
+
+// A server running on a thread of its own. Bind it to port 0, so tests don't fight over 7878
+// and the OS picks a free port. It shuts down when dropped, also when an assert fails halfway
+// through a test
+pub struct TestServer {
+    addr: SocketAddr,
+    handle: ShutdownHandle,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(server: Server) -> TestServer {
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.run());
+        TestServer {
+            addr,
+            handle,
+            thread: Some(thread),
+        }
+    }
+
+    // The plain HTTP listener
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // For starting the shutdown without waiting for it
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
+
+    // Shut down and wait until run has returned
+    pub fn stop(mut self) {
+        self.handle.shutdown();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Sends `request` on a connection of its own and returns everything the server sent back. A
+// connection the server refuses may be closed before the request is written, or reset after
+// the answer, so errors only cut the response short
+pub fn send(addr: SocketAddr, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let _ = stream.write_all(request);
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+// A fresh directory under the system's temp dir for files a test needs. The name has the
+// process id and a counter in it, so tests running in parallel each get their own. It's removed
+// when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "hello_multithreaded-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Left over from an earlier run that had the same process id
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    // A new directory with `files` in it, paths relative to the directory
+    pub fn with_files(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new(name);
+        for (path, contents) in files {
+            dir.write(path, contents);
+        }
+        dir
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, path: &str) -> PathBuf {
+        self.path.join(path)
+    }
+
+    // Writes the file, and the directories it's in, and returns its full path
+    pub fn write(&self, path: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,139 @@
+// The event loop backend, epoll is Linux only
+#![cfg(target_os = "linux")]
+
+use hello_multithreaded::server::{Backend, Server};
+use hello_multithreaded::{OverflowPolicy, ThreadPool};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{send, TestServer};
+
+fn start(configure: impl FnOnce(Server) -> Server) -> TestServer {
+    let server = Server::bind("127.0.0.1:0", 1)
+        .unwrap()
+        .backend(Backend::EventLoop);
+    TestServer::start(configure(server))
+}
+
+#[test]
+fn serves_the_default_routes() {
+    let server = start(|server| server);
+    let addr = server.addr();
+
+    let response = send(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Hello!"));
+
+    assert!(
+        send(addr, b"GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n").starts_with("HTTP/1.1 404")
+    );
+    assert!(send(addr, b"GET /\r\n\r\n").starts_with("HTTP/1.1 400"));
+    // HTTP/1.1 without a Host
+    assert!(send(addr, b"GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 400"));
+
+    server.stop();
+}
+
+#[test]
+fn silent_clients_dont_hold_the_worker() {
+    let server = start(|server| server.read_timeout(Duration::from_secs(10)));
+    let addr = server.addr();
+
+    // With the thread backend, the first of these would keep the only worker for 10 seconds
+    let silent: Vec<_> = (0..8)
+        .map(|_| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+                .unwrap();
+            stream
+        })
+        .collect();
+
+    let start = Instant::now();
+    assert!(send(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").ends_with("\r\n\r\nOK"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    drop(silent);
+    server.stop();
+}
+
+#[test]
+fn enforces_the_connection_limits() {
+    let server = start(|server| {
+        server
+            .read_timeout(Duration::from_millis(200))
+            .max_header_size(1024)
+    });
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 408"));
+
+    let mut request = b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: ".to_vec();
+    request.resize(2000, b'a');
+    request.extend_from_slice(b"\r\n\r\n");
+    assert!(send(addr, &request).starts_with("HTTP/1.1 431"));
+
+    server.stop();
+}
+
+#[test]
+fn full_queue_answers_503() {
+    let pool = ThreadPool::builder(1)
+        .queue_capacity(1)
+        .overflow(OverflowPolicy::Reject)
+        .build()
+        .unwrap();
+    let server = Server::with_pool("127.0.0.1:0", pool)
+        .unwrap()
+        .backend(Backend::EventLoop);
+    let server = TestServer::start(server);
+    let addr = server.addr();
+
+    // One request keeps the worker busy, one waits in the queue
+    let mut busy = Vec::new();
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        busy.push(stream);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let response = send(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert!(response.contains("Retry-After: 1\r\n"));
+
+    server.stop();
+}
+
+#[test]
+fn in_flight_request_finishes_during_shutdown() {
+    let server = start(|server| server);
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    server.shutdown_handle().shutdown();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    server.stop();
+    assert!(TcpStream::connect(addr).is_err());
+}
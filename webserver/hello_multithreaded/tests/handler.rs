@@ -0,0 +1,239 @@
+use hello_multithreaded::handler::{Handler, Next, Router};
+use hello_multithreaded::middleware::Compression;
+use hello_multithreaded::request::{Method, Request};
+use hello_multithreaded::response::{Body, Response, StatusCode};
+use std::io::Read;
+
+fn with_header(mut request: Request, name: &str, value: &str) -> Request {
+    request.headers_mut().insert(name, value).unwrap();
+    request
+}
+
+fn body_bytes(response: &mut Response) -> Vec<u8> {
+    match response.take_body() {
+        Body::Empty => Vec::new(),
+        Body::Bytes(bytes) => bytes,
+        Body::File { mut file, .. } => {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).unwrap();
+            bytes
+        }
+        Body::Chunked(chunks) => chunks.flatten().collect(),
+    }
+}
+
+fn gunzip(compressed: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    flate2::read::GzDecoder::new(compressed)
+        .read_to_end(&mut bytes)
+        .unwrap();
+    bytes
+}
+
+fn unbrotli(compressed: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    brotli::Decompressor::new(compressed, 4096)
+        .read_to_end(&mut bytes)
+        .unwrap();
+    bytes
+}
+
+// Compressible text, well over the 1 KiB default minimum
+fn big_text() -> String {
+    "Hello from the thread pool! ".repeat(200)
+}
+
+fn text_app() -> Router {
+    Router::new()
+        .get("/big", |_: Request| {
+            Response::new(StatusCode::OK)
+                .header("Content-Type", "text/plain")
+                .body(big_text())
+        })
+        .get("/small", |_: Request| {
+            Response::new(StatusCode::OK).body("tiny")
+        })
+        .get("/photo", |_: Request| {
+            Response::new(StatusCode::OK)
+                .header("Content-Type", "image/jpeg")
+                .body(big_text())
+        })
+        .get("/stream", |_: Request| {
+            let chunks = (0..3).map(|_| big_text().into_bytes());
+            Response::new(StatusCode::OK).body(Body::chunked(chunks))
+        })
+        .layer(Compression::new())
+}
+
+#[test]
+fn parses_request_head() {
+    let request = Request::parse(
+        b"POST /items?page=2 HTTP/1.1\r\nHost: localhost\r\nX-Tag: a\r\nx-tag: b\r\n\r\nbody",
+    )
+    .unwrap();
+
+    assert_eq!(*request.method(), Method::Post);
+    assert_eq!(request.path(), "/items");
+    assert_eq!(request.query(), Some("page=2"));
+    assert_eq!(request.header("host"), Some("localhost"));
+    assert_eq!(
+        request.headers().get_all("X-Tag").collect::<Vec<_>>(),
+        ["a", "b"]
+    );
+}
+
+#[test]
+fn rejects_malformed_request_heads() {
+    for head in [
+        &b"GET / HTTP/1.1\r\nHost: localhost\r\n"[..],
+        b"GET /  HTTP/1.1\r\n\r\n",
+        b"get / HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/2\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nno colon\r\n\r\n",
+        b"GET /a%2Fb HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        // HTTP/1.1 needs a Host, and only one
+        b"GET / HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/1.0\r\nHost: a.test\r\nhost: b.test\r\n\r\n",
+    ] {
+        assert!(
+            Request::parse(head).is_err(),
+            "{:?}",
+            String::from_utf8_lossy(head)
+        );
+    }
+    assert!(Request::parse(b"GET / HTTP/1.0\r\n\r\n").is_ok());
+}
+
+#[test]
+fn router_answers_404_and_405() {
+    let router = Router::new().get("/", |_: Request| Response::new(StatusCode::OK));
+
+    let response = router.handle(Request::new(Method::Get, "/?x=1"));
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = router.handle(Request::new(Method::Get, "/missing"));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = router.handle(Request::new(Method::Delete, "/"));
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers().get("Allow"), Some("GET"));
+}
+
+#[test]
+fn layers_run_in_the_order_they_were_added() {
+    let router = Router::new()
+        .get("/", |request: Request| {
+            let trail = request.header("X-Trail").unwrap_or_default().to_string();
+            Response::new(StatusCode::OK).body(trail)
+        })
+        .layer(|request: Request, next: Next<'_>| {
+            next.run(with_header(request, "X-Trail", "outer"))
+                .header("X-Seen-By", "outer")
+        })
+        .layer(|request: Request, next: Next<'_>| {
+            let trail = format!("{} inner", request.header("X-Trail").unwrap_or_default());
+            next.run(with_header(request, "X-Trail", &trail))
+        });
+
+    let mut response = router.handle(Request::new(Method::Get, "/"));
+    assert_eq!(body_bytes(&mut response), b"outer inner");
+    assert_eq!(response.headers().get("X-Seen-By"), Some("outer"));
+
+    // Middleware sees unrouted requests too
+    let response = router.handle(Request::new(Method::Get, "/missing"));
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers().get("X-Seen-By"), Some("outer"));
+}
+
+#[test]
+fn compresses_with_the_preferred_encoding() {
+    let app = text_app();
+
+    let request = with_header(Request::new(Method::Get, "/big"), "Accept-Encoding", "gzip");
+    let mut response = app.handle(request);
+    assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+    let compressed = body_bytes(&mut response);
+    assert!(compressed.len() < big_text().len());
+    assert_eq!(gunzip(&compressed), big_text().as_bytes());
+
+    let request = with_header(
+        Request::new(Method::Get, "/big"),
+        "Accept-Encoding",
+        "gzip;q=0.5, br",
+    );
+    let mut response = app.handle(request);
+    assert_eq!(response.headers().get("Content-Encoding"), Some("br"));
+    assert_eq!(unbrotli(&body_bytes(&mut response)), big_text().as_bytes());
+
+    // br is refused, gzip comes through the wildcard
+    let request = with_header(
+        Request::new(Method::Get, "/big"),
+        "Accept-Encoding",
+        "br;q=0, *",
+    );
+    let response = app.handle(request);
+    assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+}
+
+#[test]
+fn leaves_small_and_precompressed_bodies_alone() {
+    let app = text_app();
+
+    // Still varies on Accept-Encoding, a bigger version of it would be compressed
+    let request = with_header(
+        Request::new(Method::Get, "/small"),
+        "Accept-Encoding",
+        "gzip",
+    );
+    let mut response = app.handle(request);
+    assert!(response.headers().get("Content-Encoding").is_none());
+    assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+    assert_eq!(body_bytes(&mut response), b"tiny");
+
+    let request = with_header(
+        Request::new(Method::Get, "/photo"),
+        "Accept-Encoding",
+        "gzip",
+    );
+    let response = app.handle(request);
+    assert!(response.headers().get("Content-Encoding").is_none());
+    assert!(response.headers().get("Vary").is_none());
+
+    // No Accept-Encoding, no compression
+    let response = app.handle(Request::new(Method::Get, "/big"));
+    assert!(response.headers().get("Content-Encoding").is_none());
+    assert_eq!(response.headers().get("Vary"), Some("Accept-Encoding"));
+}
+
+#[test]
+fn compresses_chunked_and_file_bodies_while_streaming() {
+    let app = text_app();
+
+    let request = with_header(
+        Request::new(Method::Get, "/stream"),
+        "Accept-Encoding",
+        "br",
+    );
+    let mut response = app.handle(request);
+    assert_eq!(response.headers().get("Content-Encoding"), Some("br"));
+    assert_eq!(
+        unbrotli(&body_bytes(&mut response)),
+        big_text().repeat(3).as_bytes()
+    );
+
+    let app = Router::new()
+        .get("/", |_: Request| {
+            Response::html_file(StatusCode::OK, "hello.html").unwrap()
+        })
+        .layer(Compression::new().min_size(0));
+    let request = with_header(Request::new(Method::Get, "/"), "Accept-Encoding", "gzip");
+    let mut response = app.handle(request);
+    assert_eq!(response.headers().get("Content-Encoding"), Some("gzip"));
+    assert!(matches!(response.body_ref(), Body::Chunked(_)));
+    assert_eq!(
+        gunzip(&body_bytes(&mut response)),
+        std::fs::read("hello.html").unwrap()
+    );
+}
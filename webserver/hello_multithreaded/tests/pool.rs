@@ -0,0 +1,397 @@
+use hello_multithreaded::log::{Level, NoopLogger};
+use hello_multithreaded::{
+    ExecuteError, JoinError, OverflowPolicy, PoolCreationError, Priority, ThreadPool,
+};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn build_rejects_zero_size() {
+    assert!(matches!(
+        ThreadPool::build(0),
+        Err(PoolCreationError::ZeroSize)
+    ));
+}
+
+#[test]
+fn build_runs_jobs() {
+    let pool = ThreadPool::build(2).unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    for i in 0..4 {
+        let tx = tx.clone();
+        pool.execute(move || tx.send(i).unwrap()).unwrap();
+    }
+
+    let mut results: Vec<i32> = rx.iter().take(4).collect();
+    results.sort();
+    assert_eq!(results, vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn panicking_job_keeps_pool_capacity() {
+    let pool = ThreadPool::build(2).unwrap();
+
+    for _ in 0..2 {
+        pool.execute(|| panic!("job failed on purpose")).unwrap();
+    }
+
+    // Both jobs wait on the barrier, so they only finish if two workers are still alive to run
+    // them at the same time
+    let barrier = Arc::new(Barrier::new(2));
+    let (tx, rx) = mpsc::channel();
+
+    for i in 0..2 {
+        let barrier = Arc::clone(&barrier);
+        let tx = tx.clone();
+        pool.execute(move || {
+            barrier.wait();
+            tx.send(i).unwrap();
+        })
+        .unwrap();
+    }
+
+    for _ in 0..2 {
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("a worker died after its job panicked");
+    }
+}
+
+#[test]
+fn spawn_returns_value_and_panic_payload() {
+    let pool = ThreadPool::build(2).unwrap();
+
+    let answer = pool.spawn(|| 6 * 7).unwrap();
+    assert_eq!(answer.join().unwrap(), 42);
+
+    let failed = pool.spawn(|| -> i32 { panic!("no answer") }).unwrap();
+    let payload = failed.join().unwrap_err().into_panic().unwrap();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"no answer"));
+}
+
+#[test]
+fn try_join_hands_back_unfinished_handle() {
+    let pool = ThreadPool::build(1).unwrap();
+    let (tx, rx) = mpsc::channel::<()>();
+
+    let handle = pool
+        .spawn(move || {
+            rx.recv().unwrap();
+            "done"
+        })
+        .unwrap();
+    let handle = handle.try_join().unwrap_err();
+
+    tx.send(()).unwrap();
+    assert_eq!(handle.join().unwrap(), "done");
+}
+
+#[test]
+fn scope_jobs_borrow_local_data() {
+    let pool = ThreadPool::build(4).unwrap();
+    let numbers = [1, 2, 3, 4, 5, 6, 7, 8];
+    let mut total = 0;
+
+    pool.scope(|s| {
+        let handles: Vec<_> = numbers
+            .chunks(2)
+            .map(|chunk| s.spawn(move || chunk.iter().sum::<i32>()).unwrap())
+            .collect();
+        total = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    });
+
+    assert_eq!(total, 36);
+}
+
+// Tells the data it borrows when it's dropped, slowly
+struct Borrower<'a>(&'a Mutex<Vec<&'static str>>);
+
+impl Drop for Borrower<'_> {
+    fn drop(&mut self) {
+        thread::sleep(Duration::from_millis(100));
+        self.0.lock().unwrap().push("dropped");
+    }
+}
+
+#[test]
+fn scope_drops_unclaimed_results_before_returning() {
+    let pool = ThreadPool::build(2).unwrap();
+    let log = Mutex::new(Vec::new());
+
+    pool.scope(|s| {
+        let handle = s
+            .spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                Borrower(&log)
+            })
+            .unwrap();
+        // Nobody will claim the result, the worker has to drop it while `log` is still there
+        drop(handle);
+    });
+
+    assert_eq!(*log.lock().unwrap(), ["dropped"]);
+}
+
+#[test]
+#[should_panic(expected = "a scoped job panicked")]
+fn scope_panics_when_unjoined_job_panicked() {
+    let pool = ThreadPool::build(2).unwrap();
+
+    pool.scope(|s| {
+        s.spawn(|| panic!("scoped job failed")).unwrap();
+    });
+}
+
+// Occupies the pool's only worker until the returned sender is used or dropped
+fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+    let (tx, rx) = mpsc::channel();
+    let (started_tx, started_rx) = mpsc::channel();
+    pool.execute(move || {
+        started_tx.send(()).unwrap();
+        let _ = rx.recv();
+    })
+    .unwrap();
+    started_rx.recv().unwrap();
+    tx
+}
+
+#[test]
+fn full_queue_rejects_jobs() {
+    let pool = ThreadPool::builder(1)
+        .queue_capacity(1)
+        .overflow(OverflowPolicy::Reject)
+        .build()
+        .unwrap();
+    let release = block_worker(&pool);
+
+    pool.execute(|| {}).unwrap();
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+
+    drop(release);
+}
+
+#[test]
+fn full_queue_drops_oldest_job() {
+    let pool = ThreadPool::builder(1)
+        .queue_capacity(1)
+        .overflow(OverflowPolicy::DropOldest)
+        .build()
+        .unwrap();
+    let release = block_worker(&pool);
+
+    let oldest = pool.spawn(|| "oldest").unwrap();
+    let newest = pool.spawn(|| "newest").unwrap();
+    drop(release);
+
+    assert!(matches!(oldest.join(), Err(JoinError::Cancelled)));
+    assert_eq!(newest.join().unwrap(), "newest");
+}
+
+#[test]
+fn jobs_count_towards_the_capacity_until_they_start() {
+    let pool = ThreadPool::builder(1)
+        .queue_capacity(2)
+        .overflow(OverflowPolicy::Reject)
+        .build()
+        .unwrap();
+    let release = block_worker(&pool);
+
+    // Both wait in the queue. Once the worker is free it starts the first, which blocks again,
+    // and only the second one may still count as waiting
+    let (started_tx, started) = mpsc::channel();
+    let (again, rx) = mpsc::channel::<()>();
+    pool.execute(move || {
+        started_tx.send(()).unwrap();
+        let _ = rx.recv();
+    })
+    .unwrap();
+    pool.execute(|| {}).unwrap();
+    drop(release);
+    started.recv().unwrap();
+
+    pool.execute(|| {}).unwrap();
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+
+    drop(again);
+}
+
+// Polls until the pool has `size` workers, retiring happens in the background
+fn wait_for_size(pool: &ThreadPool, size: usize) {
+    for _ in 0..200 {
+        if pool.size() == size {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("pool has {} workers, expected {}", pool.size(), size);
+}
+
+#[test]
+fn elastic_pool_grows_and_retires_idle_workers() {
+    let pool = ThreadPool::builder(1)
+        .max_size(3)
+        .keep_alive(Duration::from_millis(50))
+        .build()
+        .unwrap();
+
+    // Three jobs that only finish together need three workers at once
+    let barrier = Arc::new(Barrier::new(3));
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let barrier = Arc::clone(&barrier);
+            pool.spawn(move || {
+                barrier.wait();
+            })
+            .unwrap()
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(pool.size(), 3);
+
+    wait_for_size(&pool, 1);
+}
+
+#[test]
+fn resize_changes_worker_count() {
+    let pool = ThreadPool::build(2).unwrap();
+
+    pool.resize(4).unwrap();
+    assert_eq!(pool.size(), 4);
+
+    pool.resize(1).unwrap();
+    wait_for_size(&pool, 1);
+    assert_eq!(pool.spawn(|| 1).unwrap().join().unwrap(), 1);
+
+    assert!(matches!(pool.resize(0), Err(PoolCreationError::ZeroSize)));
+}
+
+#[test]
+fn stats_count_completed_and_panicked_jobs() {
+    let pool = ThreadPool::builder(2).logger(NoopLogger).build().unwrap();
+
+    pool.spawn(|| ()).unwrap().join().unwrap();
+    pool.spawn(|| panic!("counted"))
+        .unwrap()
+        .join()
+        .unwrap_err();
+    pool.execute(|| panic!("counted too")).unwrap();
+
+    // The stats are updated right after a job returns, which can be a moment after join
+    for _ in 0..200 {
+        if pool.stats().execution.count == 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let stats = pool.stats();
+    assert_eq!(stats.completed, 1);
+    assert_eq!(stats.panicked, 2);
+    assert_eq!(stats.workers, 2);
+    assert_eq!(stats.execution.count, 3);
+    assert_eq!(stats.queue_wait.count, 3);
+}
+
+#[test]
+fn logger_receives_worker_messages() {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let pool = ThreadPool::builder(1)
+        .logger(move |level: Level, message: fmt::Arguments| {
+            if level == Level::Warn {
+                tx.lock().unwrap().send(message.to_string()).unwrap();
+            }
+        })
+        .build()
+        .unwrap();
+
+    pool.execute(|| panic!("logged")).unwrap();
+
+    let message = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(message, "Worker 0 job panicked: logged; continuing.");
+}
+
+#[test]
+fn waiting_jobs_start_in_priority_order() {
+    let pool = ThreadPool::build(1).unwrap();
+    let release = block_worker(&pool);
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    for (priority, name) in [
+        (Priority::Low, "low"),
+        (Priority::Normal, "normal"),
+        (Priority::High, "high"),
+        (Priority::Normal, "normal 2"),
+    ] {
+        let order = Arc::clone(&order);
+        pool.execute_with_priority(priority, move || order.lock().unwrap().push(name))
+            .unwrap();
+    }
+
+    drop(release);
+    drop(pool);
+    assert_eq!(
+        *order.lock().unwrap(),
+        ["high", "normal", "normal 2", "low"]
+    );
+}
+
+#[test]
+fn drop_oldest_keeps_more_urgent_jobs() {
+    let pool = ThreadPool::builder(1)
+        .queue_capacity(1)
+        .overflow(OverflowPolicy::DropOldest)
+        .build()
+        .unwrap();
+    let release = block_worker(&pool);
+
+    pool.execute_with_priority(Priority::High, || {}).unwrap();
+    assert_eq!(
+        pool.execute_with_priority(Priority::Low, || {}),
+        Err(ExecuteError::QueueFull)
+    );
+
+    drop(release);
+}
+
+#[test]
+fn execute_after_waits_for_the_delay() {
+    let pool = ThreadPool::build(1).unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    let start = Instant::now();
+    pool.execute_after(Duration::from_millis(200), move || tx.send(()).unwrap())
+        .unwrap();
+
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn execute_every_repeats_until_cancelled() {
+    let pool = ThreadPool::build(1).unwrap();
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    let counter = Arc::clone(&runs);
+    let handle = pool
+        .execute_every(Duration::from_millis(20), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+
+    while runs.load(Ordering::SeqCst) < 3 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    handle.cancel();
+
+    // A run that was already queued may still happen, nothing after that
+    thread::sleep(Duration::from_millis(50));
+    let after_cancel = runs.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+}
@@ -0,0 +1,185 @@
+use hello_multithreaded::handler::{Handler, Router};
+use hello_multithreaded::proxy::Proxy;
+use hello_multithreaded::request::{Method, Request};
+use hello_multithreaded::response::{Body, Response, StatusCode};
+use hello_multithreaded::server::Server;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+mod common;
+use common::TestServer;
+
+fn serve(handler: impl Handler) -> TestServer {
+    TestServer::start(Server::bind("127.0.0.1:0", 2).unwrap().handler(handler))
+}
+
+// Tells what it got, and answers /big with a body too big to be buffered by the proxy
+fn echo(name: &'static str) -> Router {
+    Router::new()
+        .fallback(move |request: Request| {
+            let text = format!(
+                "{} {} {} for={} host={} connection={} secret={} body={}",
+                name,
+                request.method(),
+                request.target(),
+                request.header("X-Forwarded-For").unwrap_or("-"),
+                request.header("X-Forwarded-Host").unwrap_or("-"),
+                request.header("Connection").unwrap_or("-"),
+                request.header("X-Secret").unwrap_or("-"),
+                String::from_utf8_lossy(&request.body().bytes().unwrap()),
+            );
+            Response::new(StatusCode::OK)
+                .header("X-Upstream", name)
+                .body(text)
+        })
+        .get("/big", |_: Request| {
+            let chunks = (0..100u8).map(|i| vec![i; 1000]);
+            Response::new(StatusCode::OK).body(Body::chunked(chunks))
+        })
+        .get("/health", |_: Request| Response::new(StatusCode::OK))
+}
+
+fn text(response: &mut Response) -> String {
+    String::from_utf8(bytes(response)).unwrap()
+}
+
+fn bytes(response: &mut Response) -> Vec<u8> {
+    match response.take_body() {
+        Body::Empty => Vec::new(),
+        Body::Bytes(bytes) => bytes,
+        Body::Chunked(chunks) => chunks.flatten().collect(),
+        Body::File { .. } => unreachable!(),
+    }
+}
+
+fn get(proxy: &Proxy, target: &str) -> Response {
+    proxy.handle(Request::new(Method::Get, target))
+}
+
+#[test]
+fn forwards_requests_and_streams_responses() {
+    let upstream = serve(echo("a"));
+    let app = Router::new()
+        .get("/", |_: Request| {
+            Response::new(StatusCode::OK).body("front")
+        })
+        .nest(
+            "/api",
+            Proxy::new([upstream.addr()])
+                .unwrap()
+                .rewrite("/api", "/v2"),
+        );
+    let front = serve(app);
+
+    // Through the front server: rewritten, with the client's address and host
+    let mut stream = TcpStream::connect(front.addr()).unwrap();
+    stream
+        .write_all(
+            b"POST /api/items?page=2 HTTP/1.1\r\nHost: example.com\r\n\
+              Connection: close, X-Secret\r\nX-Secret: 1\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("X-Upstream: a\r\n"));
+    assert!(response.ends_with(
+        "a POST /v2/items?page=2 for=127.0.0.1 host=example.com connection=close secret=- body=hello"
+    ));
+
+    let mut stream = TcpStream::connect(front.addr()).unwrap();
+    stream
+        .write_all(b"GET /apis HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+
+    // Straight to the handler: earlier proxies' X-Forwarded-For is kept, big bodies stream
+    let proxy = Proxy::new([upstream.addr()]).unwrap().rewrite("/api", "/");
+    let mut request = Request::new(Method::Get, "/api").with_peer_addr(front.addr());
+    request
+        .headers_mut()
+        .insert("X-Forwarded-For", "10.0.0.1")
+        .unwrap();
+    let mut response = proxy.handle(request.clone());
+    assert!(text(&mut response).starts_with("a GET / for=10.0.0.1, 127.0.0.1 "));
+    // All of them, when they came in more than one line
+    request
+        .headers_mut()
+        .append("X-Forwarded-For", "10.0.0.2, 10.0.0.3")
+        .unwrap();
+    let mut response = proxy.handle(request);
+    assert!(text(&mut response).starts_with("a GET / for=10.0.0.1, 10.0.0.2, 10.0.0.3, 127.0.0.1 "));
+
+    let mut response = get(&proxy, "/api/big");
+    assert!(matches!(response.body_ref(), Body::Chunked(_)));
+    let expected: Vec<u8> = (0..100u8).flat_map(|i| vec![i; 1000]).collect();
+    assert_eq!(bytes(&mut response), expected);
+
+    front.stop();
+    upstream.stop();
+}
+
+#[test]
+fn round_robin_with_health_checks() {
+    let a = serve(echo("a"));
+    let b = serve(echo("b"));
+    let upstream_of = |response: Response| response.headers().get("X-Upstream").map(String::from);
+
+    let checked = Proxy::new([a.addr(), b.addr()])
+        .unwrap()
+        .health_check("/health", Duration::from_millis(50));
+    let unchecked = Proxy::new([a.addr(), b.addr()]).unwrap();
+    let names: Vec<_> = (0..4)
+        .map(|_| upstream_of(get(&checked, "/")).unwrap())
+        .collect();
+    assert_eq!(names, ["a", "b", "a", "b"]);
+
+    // With b gone, requests still get through: b is out of the rotation once the health check
+    // notices, and without health checks the next upstream gets the request
+    b.stop();
+    thread::sleep(Duration::from_millis(200));
+    for _ in 0..4 {
+        assert_eq!(upstream_of(get(&checked, "/")).as_deref(), Some("a"));
+        assert_eq!(upstream_of(get(&unchecked, "/")).as_deref(), Some("a"));
+    }
+
+    a.stop();
+    assert_eq!(get(&checked, "/").status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(get(&unchecked, "/").status(), StatusCode::BAD_GATEWAY);
+}
+
+#[test]
+fn upstream_failures() {
+    // Accepts, reads the request and never answers
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut streams = Vec::new();
+        for mut stream in silent.incoming().flatten() {
+            let _ = stream.read(&mut [0; 1024]);
+            streams.push(stream);
+        }
+    });
+    let proxy = Proxy::new([silent_addr])
+        .unwrap()
+        .timeout(Duration::from_millis(200));
+    assert_eq!(get(&proxy, "/").status(), StatusCode::GATEWAY_TIMEOUT);
+
+    // Answers with garbage
+    let broken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let broken_addr = broken.local_addr().unwrap();
+    thread::spawn(move || {
+        for mut stream in broken.incoming().flatten() {
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(b"SMTP ready\r\n\r\n");
+        }
+    });
+    let proxy = Proxy::new([broken_addr]).unwrap();
+    assert_eq!(get(&proxy, "/").status(), StatusCode::BAD_GATEWAY);
+
+    assert!(Proxy::new(Vec::<SocketAddr>::new()).is_err());
+}
@@ -0,0 +1,134 @@
+use hello_multithreaded::handler::{Handler, Router};
+use hello_multithreaded::middleware::{Rate, RateLimit};
+use hello_multithreaded::request::{Method, Request};
+use hello_multithreaded::response::{Response, StatusCode};
+use hello_multithreaded::server::Server;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{backends, send, TestServer};
+
+fn get(router: &Router, path: &str, client: Option<&str>) -> Response {
+    let mut request = Request::new(Method::Get, path);
+    if let Some(client) = client {
+        request = request.with_peer_addr(client.parse().unwrap());
+    }
+    router.handle(request)
+}
+
+#[test]
+fn token_bucket_per_client_and_route() {
+    let ok = |_: Request| Response::new(StatusCode::OK);
+    let router = Router::new()
+        .get("/", ok)
+        .get("/login", ok)
+        .layer(RateLimit::new(Rate::per_minute(2)).route("/login", Rate::per_second(20).burst(1)));
+    let a = Some("10.0.0.1:5000");
+    let b = Some("10.0.0.2:5000");
+
+    // A burst of two, then one more every 30 seconds
+    assert_eq!(get(&router, "/", a).status(), StatusCode::OK);
+    assert_eq!(get(&router, "/?page=2", a).status(), StatusCode::OK);
+    let response = get(&router, "/", a);
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers().get("Retry-After"), Some("30"));
+
+    // Other clients and routes have buckets of their own, so do requests from nowhere
+    assert_eq!(get(&router, "/", b).status(), StatusCode::OK);
+    assert_eq!(get(&router, "/login", a).status(), StatusCode::OK);
+    let response = get(&router, "/login", a);
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers().get("Retry-After"), Some("1"));
+    for _ in 0..5 {
+        assert_eq!(get(&router, "/", None).status(), StatusCode::OK);
+    }
+
+    // 20 a second is a token every 50 ms
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(get(&router, "/login", a).status(), StatusCode::OK);
+    assert_eq!(get(&router, "/", a).status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn every_spelling_of_a_route_shares_its_bucket() {
+    let app = || {
+        let ok = |_: Request| Response::new(StatusCode::OK);
+        Router::new()
+            .get("/login", ok)
+            .layer(RateLimit::new(Rate::per_minute(100)).route("/%6Cogin/", Rate::per_minute(1)))
+    };
+    let router = app();
+    let a = Some("10.0.0.1:5000");
+
+    assert_eq!(get(&router, "/login", a).status(), StatusCode::OK);
+    for path in [
+        "//login",
+        "/%6Cogin",
+        "/%6cogin?next=/",
+        "/login/",
+        "/x/../login",
+    ] {
+        let response = get(&router, path, a);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS, "{}", path);
+    }
+    // An escaped slash keeps its segment from being /login to the limit and the router alike
+    let response = get(&router, "/x%2F..%2Flogin", a);
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // and a client can't send one at all
+    let server = TestServer::start(Server::bind("127.0.0.1:0", 2).unwrap().handler(app()));
+    for target in ["/x%2F..%2Flogin", "/x%5C..%5Clogin", "/login%2F"] {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+        let response = send(server.addr(), request.as_bytes());
+        assert!(
+            response.starts_with("HTTP/1.1 400 "),
+            "{}: {}",
+            target,
+            response
+        );
+    }
+    server.stop();
+}
+
+#[test]
+fn connections_per_ip_are_limited_before_the_pool() {
+    for backend in backends() {
+        let server = Server::bind("127.0.0.1:0", 4)
+            .unwrap()
+            .backend(backend)
+            .max_connections_per_ip(2);
+        let server = TestServer::start(server);
+        let addr = server.addr();
+
+        // Two idle connections, enough to hold two of the four workers with threads
+        let first = TcpStream::connect(addr).unwrap();
+        let second = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let response = send(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+            "{:?} {}",
+            backend,
+            response
+        );
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        // Closing one makes room again, once the server has noticed
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let response = send(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            if response.starts_with("HTTP/1.1 200 OK\r\n") {
+                break;
+            }
+            assert!(Instant::now() < deadline, "{:?} {}", backend, response);
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        drop(second);
+        server.stop();
+    }
+}
@@ -0,0 +1,101 @@
+use hello_multithreaded::response::{Body, Cookie, Response, SameSite, StatusCode};
+use std::time::Duration;
+
+fn serialize(response: Response) -> String {
+    let mut out = Vec::new();
+    response.write_to(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn bytes_body_gets_content_length() {
+    let response = Response::new(StatusCode::NOT_FOUND)
+        .header("Content-Type", "text/plain")
+        .body("nope");
+
+    assert_eq!(
+        serialize(response),
+        "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nnope"
+    );
+}
+
+#[test]
+fn framing_headers_cannot_be_set_by_hand() {
+    let response = Response::new(StatusCode::OK)
+        .header("content-length", "1000")
+        .header("Transfer-Encoding", "gzip")
+        .body("short");
+
+    assert_eq!(
+        serialize(response),
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nshort"
+    );
+}
+
+#[test]
+fn chunked_body_is_framed_per_chunk() {
+    let chunks = vec![b"Hello, ".to_vec(), Vec::new(), b"world!".to_vec()];
+    let response = Response::new(StatusCode::OK).body(Body::chunked(chunks));
+
+    assert_eq!(
+        serialize(response),
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n7\r\nHello, \r\n6\r\nworld!\r\n0\r\n\r\n"
+    );
+}
+
+#[test]
+fn file_body_is_streamed_with_its_length() {
+    let response = Response::html_file(StatusCode::OK, "hello.html").unwrap();
+    let expected = std::fs::read_to_string("hello.html").unwrap();
+
+    let serialized = serialize(response);
+    assert!(serialized.contains(&format!("Content-Length: {}\r\n", expected.len())));
+    assert!(serialized.ends_with(&format!("\r\n\r\n{}", expected)));
+}
+
+#[test]
+fn no_content_has_no_body() {
+    let response = Response::new(StatusCode::NO_CONTENT).body("ignored");
+    assert_eq!(serialize(response), "HTTP/1.1 204 No Content\r\n\r\n");
+}
+
+#[test]
+fn header_values_with_line_breaks_are_rejected() {
+    let mut response = Response::new(StatusCode::OK);
+    let headers = response.headers_mut();
+
+    assert!(headers
+        .insert("Location", "/\r\nSet-Cookie: evil=1")
+        .is_err());
+    assert!(headers.insert("Bad Name", "value").is_err());
+    assert!(headers.get("Location").is_none());
+}
+
+#[test]
+fn cookies_are_appended_not_replaced() {
+    let response = Response::new(StatusCode::OK)
+        .cookie(
+            Cookie::new("session", "abc123")
+                .path("/")
+                .max_age(Duration::from_secs(3600))
+                .http_only(true)
+                .secure(true)
+                .same_site(SameSite::Lax),
+        )
+        .cookie(Cookie::removal("old"));
+
+    let cookies: Vec<_> = response.headers().get_all("set-cookie").collect();
+    assert_eq!(
+        cookies,
+        [
+            "session=abc123; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Lax",
+            "old=; Max-Age=0",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "invalid cookie value")]
+fn cookie_value_with_semicolon_panics() {
+    Cookie::new("session", "a;b");
+}
@@ -0,0 +1,233 @@
+use hello_multithreaded::handler::{Handler, Router};
+use hello_multithreaded::middleware::{Cors, SecurityHeaders};
+use hello_multithreaded::request::{Method, Request};
+use hello_multithreaded::response::{Response, StatusCode};
+use std::time::Duration;
+
+fn request(method: Method, target: &str, headers: &[(&str, &str)]) -> Request {
+    let mut request = Request::new(method, target);
+    for (name, value) in headers {
+        request.headers_mut().append(name, value).unwrap();
+    }
+    request
+}
+
+fn api() -> Router {
+    Router::new()
+        .get("/items", |_: Request| {
+            Response::new(StatusCode::OK)
+                .header("X-Total", "3")
+                .body("[]")
+        })
+        .route(Method::Delete, "/items", |_: Request| {
+            Response::new(StatusCode::NO_CONTENT)
+        })
+}
+
+#[test]
+fn cors_preflight_and_actual_requests() {
+    let app = api().layer(
+        Cors::new()
+            .allow_origin("https://app.example.com")
+            .allow_origin("http://localhost:3000")
+            .allow_methods(&[Method::Get, Method::Delete])
+            .allow_headers(&["Content-Type", "Authorization"])
+            .expose_headers(&["X-Total"])
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600)),
+    );
+    let from = |origin| ("Origin", origin);
+
+    // The preflight is answered before routing, there is no OPTIONS route
+    let response = app.handle(request(
+        Method::Options,
+        "/items",
+        &[
+            from("https://app.example.com"),
+            ("Access-Control-Request-Method", "DELETE"),
+            (
+                "Access-Control-Request-Headers",
+                "authorization, content-type",
+            ),
+        ],
+    ));
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let headers = response.headers();
+    assert_eq!(
+        headers.get("Access-Control-Allow-Origin"),
+        Some("https://app.example.com")
+    );
+    assert_eq!(
+        headers.get("Access-Control-Allow-Methods"),
+        Some("GET, DELETE")
+    );
+    assert_eq!(
+        headers.get("Access-Control-Allow-Headers"),
+        Some("authorization, content-type")
+    );
+    assert_eq!(
+        headers.get("Access-Control-Allow-Credentials"),
+        Some("true")
+    );
+    assert_eq!(headers.get("Access-Control-Max-Age"), Some("600"));
+    assert!(headers.get_all("Vary").any(|vary| vary == "Origin"));
+
+    // A method, header or origin that isn't allowed
+    for (origin, method, headers) in [
+        ("https://app.example.com", "PUT", ""),
+        ("https://app.example.com", "GET", "X-Secret"),
+        ("https://evil.example.com", "GET", ""),
+    ] {
+        let response = app.handle(request(
+            Method::Options,
+            "/items",
+            &[
+                from(origin),
+                ("Access-Control-Request-Method", method),
+                ("Access-Control-Request-Headers", headers),
+            ],
+        ));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+    }
+
+    // The actual request goes to the route and gets the headers added
+    let response = app.handle(request(
+        Method::Get,
+        "/items",
+        &[from("http://LOCALHOST:3000")],
+    ));
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(
+        headers.get("Access-Control-Allow-Origin"),
+        Some("http://LOCALHOST:3000")
+    );
+    assert_eq!(
+        headers.get("Access-Control-Expose-Headers"),
+        Some("X-Total")
+    );
+
+    let response = app.handle(request(
+        Method::Get,
+        "/items",
+        &[from("https://evil.example.com")],
+    ));
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+
+    // Not cross-origin at all, an OPTIONS without Origin is routed as usual
+    let response = app.handle(request(Method::Get, "/items", &[]));
+    assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+    assert_eq!(response.headers().get("Vary"), Some("Origin"));
+    let response = app.handle(request(Method::Options, "/items", &[]));
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[test]
+fn cors_for_any_origin() {
+    let public = api().layer(Cors::new().allow_origin("*"));
+    let response = public.handle(request(
+        Method::Get,
+        "/items",
+        &[("Origin", "https://anyone.example")],
+    ));
+    assert_eq!(
+        response.headers().get("Access-Control-Allow-Origin"),
+        Some("*")
+    );
+    assert!(!response.headers().contains("Vary"));
+    assert!(!response
+        .headers()
+        .contains("Access-Control-Allow-Credentials"));
+}
+
+// Every site could read responses made with the user's cookies
+#[test]
+#[should_panic(expected = "must name its origins")]
+fn cors_any_origin_then_credentials_panics() {
+    Cors::new().allow_origin("*").allow_credentials(true);
+}
+
+#[test]
+#[should_panic(expected = "must name its origins")]
+fn cors_credentials_then_any_origin_panics() {
+    Cors::new()
+        .allow_origin("https://app.example.com")
+        .allow_credentials(true)
+        .allow_origin("*");
+}
+
+// Or every allowed response would fail to be built
+#[test]
+#[should_panic(expected = "invalid header name \"X-Request Id\"")]
+fn cors_with_a_bad_exposed_header_panics() {
+    Cors::new()
+        .allow_origin("https://app.example.com")
+        .expose_headers(&["X-Total-Count", "X-Request Id"]);
+}
+
+#[test]
+#[should_panic(expected = "invalid header name")]
+fn cors_with_a_bad_allowed_header_panics() {
+    Cors::new().allow_headers(&["Content-Type\r\nX-Evil"]);
+}
+
+#[test]
+fn security_headers_per_route() {
+    let app = Router::new()
+        .get("/", |_: Request| Response::new(StatusCode::OK))
+        .get("/admin/users", |_: Request| Response::new(StatusCode::OK))
+        .get("/embed", |_: Request| {
+            Response::new(StatusCode::OK).header("Content-Security-Policy", "frame-ancestors *")
+        })
+        .layer(
+            SecurityHeaders::new()
+                .content_security_policy("default-src 'self'")
+                .strict_transport_security(Duration::from_secs(31536000), true)
+                .route(
+                    "/admin",
+                    SecurityHeaders::new()
+                        .content_security_policy("default-src 'none'")
+                        .referrer_policy("no-referrer")
+                        .no_sniff(false),
+                ),
+        );
+    let get = |path| app.handle(Request::new(Method::Get, path));
+
+    let response = get("/");
+    let headers = response.headers();
+    assert_eq!(
+        headers.get("Content-Security-Policy"),
+        Some("default-src 'self'")
+    );
+    assert_eq!(
+        headers.get("Strict-Transport-Security"),
+        Some("max-age=31536000; includeSubDomains")
+    );
+    assert_eq!(headers.get("X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(
+        headers.get("Referrer-Policy"),
+        Some("strict-origin-when-cross-origin")
+    );
+
+    let response = get("/admin/users");
+    let headers = response.headers();
+    assert_eq!(
+        headers.get("Content-Security-Policy"),
+        Some("default-src 'none'")
+    );
+    assert_eq!(headers.get("Referrer-Policy"), Some("no-referrer"));
+    assert!(!headers.contains("X-Content-Type-Options"));
+    assert!(!headers.contains("Strict-Transport-Security"));
+
+    // The handler's own header wins, and 404s get the headers too
+    let response = get("/embed");
+    assert_eq!(
+        response.headers().get("Content-Security-Policy"),
+        Some("frame-ancestors *")
+    );
+    let response = get("/missing");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.headers().contains("Content-Security-Policy"));
+}
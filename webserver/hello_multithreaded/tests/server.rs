@@ -0,0 +1,289 @@
+use hello_multithreaded::request::Request;
+use hello_multithreaded::response::{Response, StatusCode};
+use hello_multithreaded::server::Server;
+use hello_multithreaded::{OverflowPolicy, ThreadPool};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::TestServer;
+
+fn start() -> TestServer {
+    let server = Server::bind("127.0.0.1:0", 2)
+        .unwrap()
+        .shutdown_timeout(Duration::from_secs(10));
+    TestServer::start(server)
+}
+
+#[test]
+fn shutdown_handle_stops_idle_server() {
+    let server = start();
+    let addr = server.addr();
+
+    server.stop();
+
+    // The listener is closed once run returns
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn in_flight_request_finishes_during_shutdown() {
+    let server = start();
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    // Give the worker time to pick the request up before shutting down
+    thread::sleep(Duration::from_millis(200));
+
+    server.shutdown_handle().shutdown();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    server.stop();
+}
+
+#[test]
+fn full_queue_answers_503() {
+    let pool = ThreadPool::builder(1)
+        .queue_capacity(1)
+        .overflow(OverflowPolicy::Reject)
+        .build()
+        .unwrap();
+    let server = Server::with_pool("127.0.0.1:0", pool).unwrap();
+    let server = TestServer::start(server);
+    let addr = server.addr();
+
+    // One request keeps the worker busy, one waits in the queue
+    let mut busy = Vec::new();
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        busy.push(stream);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert!(response.contains("Retry-After: 1\r\n"));
+
+    server.stop();
+}
+
+#[test]
+fn metrics_endpoint_serves_pool_stats() {
+    let server = start();
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("# TYPE threadpool_workers gauge\nthreadpool_workers 2\n"));
+    // The job serving this request is running while the stats are taken
+    assert!(response.contains("threadpool_active_workers 1\n"));
+    assert!(response.contains("threadpool_execution_seconds_bucket{le=\"+Inf\"}"));
+
+    server.stop();
+}
+
+#[test]
+fn health_check_jumps_ahead_of_sleep() {
+    let server = Server::bind("127.0.0.1:0", 1)
+        .unwrap()
+        .shutdown_timeout(Duration::from_millis(100));
+    let addr = server.local_addr().unwrap();
+
+    // Every request is in its socket before the accept loop starts, it doesn't wait for them.
+    // The only worker sleeps, a second /sleep waits in the queue, then comes the health check
+    let mut streams = Vec::new();
+    for request in ["/sleep", "/sleep", "/health"] {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            request
+        )
+        .unwrap();
+        streams.push(stream);
+    }
+    let start = Instant::now();
+    let server = TestServer::start(server);
+
+    let mut response = String::new();
+    streams[2].read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nOK"));
+
+    // Served as soon as the first /sleep was done, in first come first served order it would
+    // have waited for both
+    assert!(start.elapsed() < Duration::from_secs(8));
+
+    server.stop();
+}
+
+#[test]
+fn silent_clients_dont_hold_up_the_accept_loop() {
+    // Enough workers that every connection gets one right away, only the accept loop is shared
+    let server = Server::bind("127.0.0.1:0", 64)
+        .unwrap()
+        .shutdown_timeout(Duration::from_millis(100));
+    let server = TestServer::start(server);
+    let addr = server.addr();
+
+    // Connected, but they never send a request
+    let silent: Vec<TcpStream> = (0..50).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(
+        start.elapsed() < Duration::from_millis(500),
+        "{:?}",
+        start.elapsed()
+    );
+
+    drop(silent);
+    server.stop();
+}
+
+// A server with short limits, for the slow client tests
+fn start_with(configure: impl FnOnce(Server) -> Server) -> TestServer {
+    TestServer::start(configure(Server::bind("127.0.0.1:0", 1).unwrap()))
+}
+
+fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn silent_client_gets_408() {
+    let server = start_with(|server| server.read_timeout(Duration::from_millis(200)));
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+        .unwrap();
+    assert!(read_response(&mut stream).starts_with("HTTP/1.1 408"));
+
+    // The only worker is free again
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut stream).starts_with("HTTP/1.1 200 OK"));
+
+    server.stop();
+}
+
+#[test]
+fn trickling_client_is_cut_off() {
+    let server = start_with(|server| {
+        server
+            .read_timeout(Duration::from_millis(200))
+            .request_timeout(Duration::from_millis(500))
+    });
+    let addr = server.addr();
+
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    // Every byte arrives well within the read timeout, the request as a whole never does
+    for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: yes".iter() {
+        if stream.write_all(&[*byte]).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(read_response(&mut stream).starts_with("HTTP/1.1 408"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    server.stop();
+}
+
+#[test]
+fn oversized_header_gets_431() {
+    let server = start_with(|server| server.max_header_size(1024));
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut request = b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: ".to_vec();
+    request.resize(2000, b'a');
+    request.extend_from_slice(b"\r\n\r\n");
+    stream.write_all(&request).unwrap();
+
+    assert!(read_response(&mut stream).starts_with("HTTP/1.1 431"));
+
+    server.stop();
+}
+
+#[test]
+fn slow_handler_gives_up_at_deadline() {
+    let server = start_with(|server| server.handler_timeout(Duration::from_millis(200)));
+    let addr = server.addr();
+
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+
+    assert!(read_response(&mut stream).starts_with("HTTP/1.1 503"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    server.stop();
+}
+
+#[test]
+fn custom_handler_replaces_default_routes() {
+    let server = start_with(|server| {
+        server.handler(|request: Request| {
+            Response::new(StatusCode::OK)
+                .header("Content-Type", "text/plain")
+                .body(format!("{} {}", request.method(), request.path()))
+        })
+    });
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"DELETE /items/1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut stream).ends_with("\r\n\r\nDELETE /items/1"));
+
+    server.stop();
+}
+
+#[test]
+fn malformed_request_gets_400() {
+    let server = start_with(|server| server);
+    let addr = server.addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /\r\n\r\n").unwrap();
+    assert!(read_response(&mut stream).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    server.stop();
+}
@@ -0,0 +1,248 @@
+use hello_multithreaded::handler::Router;
+use hello_multithreaded::request::Request;
+use hello_multithreaded::response::{Response, StatusCode};
+use hello_multithreaded::server::{Backend, Server};
+use hello_multithreaded::sse::{Event, EventStream};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{backends, TestServer};
+
+fn open(addr: SocketAddr, path: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n",
+        path
+    )
+    .unwrap();
+    stream
+}
+
+// The chunks put back together, the stream ends with the last one
+fn dechunk(mut body: &str) -> String {
+    let mut out = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n").unwrap();
+        let size = usize::from_str_radix(size, 16).unwrap();
+        if size == 0 {
+            return out;
+        }
+        out.push_str(&rest[..size]);
+        body = &rest[size + 2..];
+    }
+}
+
+#[test]
+fn streams_events_until_the_senders_are_gone() {
+    for backend in backends() {
+        // One worker, which the stream must not keep
+        let app = Router::new()
+            .get("/events", |_: Request| -> Response {
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    tx.send(Event::new("first")).unwrap();
+                    // Quiet long enough for a heartbeat
+                    thread::sleep(Duration::from_millis(300));
+                    tx.send(Event::new("two\nlines").event("update").id("2"))
+                        .unwrap();
+                    tx.send("last".into()).unwrap();
+                });
+                EventStream::new(rx)
+                    .heartbeat(Duration::from_millis(100))
+                    .retry(Duration::from_secs(3))
+                    .into()
+            })
+            .get("/", |_: Request| Response::new(StatusCode::OK));
+        let server = Server::bind("127.0.0.1:0", 1)
+            .unwrap()
+            .backend(backend)
+            .handler(app);
+        let server = TestServer::start(server);
+        let addr = server.addr();
+
+        let mut stream = open(addr, "/events");
+        let mut other = open(addr, "/");
+        let mut response = String::new();
+        other.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "), "{:?}", backend);
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        for header in [
+            "Content-Type: text/event-stream",
+            "Cache-Control: no-cache",
+            "Transfer-Encoding: chunked",
+        ] {
+            assert!(head.lines().any(|line| line == header), "{}", head);
+        }
+
+        let body = dechunk(body);
+        let (start, rest) = body.split_once(": heartbeat\n\n").unwrap();
+        assert_eq!(start, "retry: 3000\n\ndata: first\n\n");
+        assert_eq!(
+            rest.trim_start_matches(": heartbeat\n\n"),
+            "id: 2\nevent: update\ndata: two\ndata: lines\n\ndata: last\n\n"
+        );
+
+        server.stop();
+    }
+}
+
+#[test]
+fn stops_the_sender_when_the_client_leaves() {
+    for backend in backends() {
+        let (done_tx, done) = mpsc::channel();
+        let done_tx = Arc::new(Mutex::new(done_tx));
+        let app = Router::new().get("/events", move |_: Request| -> Response {
+            let (tx, rx) = mpsc::channel();
+            let done = done_tx.lock().unwrap().clone();
+            thread::spawn(move || {
+                tx.send(Event::new("hello")).unwrap();
+                // Nothing more to say for a while, then the send fails
+                thread::sleep(Duration::from_millis(1500));
+                done.send(tx.send(Event::new("anyone?")).is_err()).unwrap();
+            });
+            EventStream::new(rx).into()
+        });
+        let server = Server::bind("127.0.0.1:0", 1)
+            .unwrap()
+            .backend(backend)
+            .handler(app);
+        let server = TestServer::start(server);
+        let addr = server.addr();
+
+        let mut stream = open(addr, "/events");
+        let mut received = Vec::new();
+        let mut buffer = [0; 1024];
+        while !String::from_utf8_lossy(&received).contains("data: hello") {
+            let read = stream.read(&mut buffer).unwrap();
+            assert!(read > 0);
+            received.extend_from_slice(&buffer[..read]);
+        }
+        drop(stream);
+
+        assert!(
+            done.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "{:?}",
+            backend
+        );
+
+        server.stop();
+    }
+}
+
+// The head only, the body goes on
+fn head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+// The handler passes every stream's sender to the test, which decides when they end
+fn held_streams(
+    backend: Backend,
+    configure: impl FnOnce(Server) -> Server,
+) -> (TestServer, mpsc::Receiver<mpsc::Sender<Event>>) {
+    let (senders_tx, senders) = mpsc::channel();
+    let senders_tx = Mutex::new(senders_tx);
+    let app = Router::new().get("/events", move |_: Request| -> Response {
+        let (tx, rx) = mpsc::channel();
+        senders_tx.lock().unwrap().send(tx).unwrap();
+        EventStream::new(rx).into()
+    });
+    let server = Server::bind("127.0.0.1:0", 1)
+        .unwrap()
+        .backend(backend)
+        .handler(app);
+    (TestServer::start(configure(server)), senders)
+}
+
+// Opens streams until one gets a 200 again, after an earlier one ended
+fn reopens(addr: SocketAddr) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        // A refused connection may be closed before the request is written, or reset
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let _ = stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while !line.ends_with(b"\r\n") && stream.read_exact(&mut byte).is_ok() {
+            line.push(byte[0]);
+        }
+        if line.starts_with(b"HTTP/1.1 200 ") {
+            return;
+        }
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn open_streams_count_towards_the_limits() {
+    for backend in backends() {
+        // Every stream is a connection of its client's
+        let (server, senders) = held_streams(backend, |server| server.max_connections_per_ip(2));
+        let addr = server.addr();
+        let mut streams: Vec<TcpStream> = (0..2).map(|_| open(addr, "/events")).collect();
+        for stream in &mut streams {
+            assert!(head(stream).starts_with("HTTP/1.1 200 "), "{:?}", backend);
+        }
+        let mut held: Vec<_> = (0..2)
+            .map(|_| senders.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        // Refused on accept, before it sent anything, unread bytes would make the close a reset
+        let mut response = String::new();
+        TcpStream::connect(addr)
+            .unwrap()
+            .read_to_string(&mut response)
+            .unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 429 "),
+            "{:?} {}",
+            backend,
+            response
+        );
+        // Ending a stream gives its slot back
+        held.remove(0);
+        let mut rest = String::new();
+        streams[0].read_to_string(&mut rest).unwrap();
+        assert!(rest.ends_with("0\r\n\r\n"));
+        reopens(addr);
+        drop(held);
+        server.stop();
+
+        // And of the server's upgraded connections
+        let (server, senders) = held_streams(backend, |server| server.max_upgraded_connections(1));
+        let addr = server.addr();
+        let mut first = open(addr, "/events");
+        assert!(head(&mut first).starts_with("HTTP/1.1 200 "));
+        let mut held = vec![senders.recv_timeout(Duration::from_secs(5)).unwrap()];
+        let mut second = open(addr, "/events");
+        let response = head(&mut second);
+        assert!(
+            response.starts_with("HTTP/1.1 503 "),
+            "{:?} {}",
+            backend,
+            response
+        );
+        held.clear();
+        first.read_to_string(&mut String::new()).unwrap();
+        reopens(addr);
+        server.stop();
+    }
+}
@@ -0,0 +1,163 @@
+use hello_multithreaded::response::StatusCode;
+use hello_multithreaded::template::{Context, Templates};
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+mod common;
+use common::TempDir;
+
+// A fresh directory with the given templates in it
+fn templates(files: &[(&str, &str)]) -> TempDir {
+    TempDir::with_files("templates", files)
+}
+
+fn post(title: &str, slug: &str) -> Context {
+    Context::new().insert("title", title).insert("slug", slug)
+}
+
+#[test]
+fn renders_values_loops_and_conditionals() {
+    let dir = templates(&[(
+        "posts.html",
+        "<h1>{{ title }}</h1>{# not shown #}\n\
+         {% for post in posts %}{{ loop.index }}. <a href=\"/{{ post.slug }}\">{{ post.title }}</a>\
+         {% if not loop.last %}, {% endif %}{% else %}No posts yet.{% endfor %}\n\
+         {% if admin %}admin{% elif user.name %}hi {{ user.name }}{% else %}guest{% endif %}\n\
+         {{ footer | raw }} {{ count }} {{ missing_but_null }}",
+    )]);
+    let templates = Templates::new(dir.path());
+
+    let context = Context::new()
+        .insert("title", "Tom & Jerry's <blog>")
+        .insert(
+            "posts",
+            vec![post("First", "first"), post("<script>", "second")],
+        )
+        .insert("user", Context::new().insert("name", "ferris"))
+        .insert("footer", "<hr>")
+        .insert("count", 2)
+        .insert("missing_but_null", None::<String>);
+    assert_eq!(
+        templates.render("posts.html", &context).unwrap(),
+        "<h1>Tom &amp; Jerry&#39;s &lt;blog&gt;</h1>\n\
+         1. <a href=\"/first\">First</a>, 2. <a href=\"/second\">&lt;script&gt;</a>\n\
+         hi ferris\n\
+         <hr> 2 "
+    );
+
+    let context = Context::new()
+        .insert("title", "Empty")
+        .insert("posts", Vec::<Context>::new())
+        .insert("footer", "")
+        .insert("count", 0)
+        .insert("missing_but_null", None::<String>);
+    let html = templates.render("posts.html", &context).unwrap();
+    assert!(html.contains("No posts yet.\nguest\n"), "{}", html);
+
+    let response = templates.response(StatusCode::OK, "posts.html", &context);
+    assert_eq!(
+        response.headers().get("Content-Type"),
+        Some("text/html; charset=utf-8")
+    );
+    let response = templates.response(StatusCode::OK, "missing.html", &context);
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[test]
+fn layouts_and_includes() {
+    let dir = templates(&[
+        (
+            "base.html",
+            "<title>{% block title %}Blog{% endblock %}</title>\
+             {% include \"partials/nav.html\" %}<main>{% block content %}{% endblock %}</main>",
+        ),
+        ("partials/nav.html", "<nav>{{ user }}</nav>"),
+        (
+            "post.html",
+            "{% extends \"base.html\" %}\n\
+             {% block title %}{{ post.title }} - {% block site %}Blog{% endblock %}{% endblock %}\n\
+             {% block content %}<p>{{ post.title }}</p>{% endblock %}",
+        ),
+        // Extends a page that extends the base, blocks from the most specific template win
+        (
+            "draft.html",
+            "{% extends \"post.html\" %}{% block site %}Drafts{% endblock %}",
+        ),
+    ]);
+    let templates = Templates::new(dir.path());
+    let context = Context::new()
+        .insert("user", "ferris")
+        .insert("post", post("Hello", "hello"));
+
+    assert_eq!(
+        templates.render("post.html", &context).unwrap(),
+        "<title>Hello - Blog</title><nav>ferris</nav><main><p>Hello</p></main>"
+    );
+    assert_eq!(
+        templates.render("draft.html", &context).unwrap(),
+        "<title>Hello - Drafts</title><nav>ferris</nav><main><p>Hello</p></main>"
+    );
+    // The layout on its own has its defaults
+    assert_eq!(
+        templates.render("base.html", &context).unwrap(),
+        "<title>Blog</title><nav>ferris</nav><main></main>"
+    );
+}
+
+#[test]
+fn errors_name_the_template_and_line() {
+    let dir = templates(&[
+        ("unclosed.html", "<ul>\n{% for post in posts %}\n<li>\n"),
+        ("unknown.html", "\n\n{% while true %}"),
+        ("undefined.html", "<p>\n{{ post.title }}</p>"),
+        ("late_extends.html", "<p>{% extends \"base.html\" %}"),
+        ("loop.html", "{% include \"loop.html\" %}"),
+        ("includes_missing.html", "{% include \"missing.html\" %}"),
+    ]);
+    let templates = Templates::new(dir.path());
+    let context = Context::new().insert("posts", Vec::<Context>::new());
+    let error = |name: &str| templates.render(name, &context).unwrap_err().to_string();
+
+    assert_eq!(
+        error("unclosed.html"),
+        "unclosed.html:2: missing {% endfor %}"
+    );
+    assert_eq!(
+        error("unknown.html"),
+        "unknown.html:3: unknown tag {% while true %}"
+    );
+    assert_eq!(
+        error("undefined.html"),
+        "undefined.html:2: `post.title` is undefined"
+    );
+    assert_eq!(
+        error("late_extends.html"),
+        "late_extends.html:1: extends must be the first tag"
+    );
+    assert_eq!(error("loop.html"), "loop.html: includes or extends itself");
+    assert!(error("includes_missing.html").starts_with("missing.html: "));
+    assert!(error("../outside.html").starts_with("../outside.html: "));
+}
+
+#[test]
+fn reloads_changed_templates_only_when_asked() {
+    let dir = templates(&[("page.html", "old")]);
+    let cached = Templates::new(dir.path());
+    let reloading = Templates::new(dir.path()).reload(true);
+    let context = Context::new();
+
+    assert_eq!(cached.render("page.html", &context).unwrap(), "old");
+    assert_eq!(reloading.render("page.html", &context).unwrap(), "old");
+
+    fs::write(dir.join("page.html"), "new").unwrap();
+    // Not every file system keeps more than whole seconds
+    let file = fs::File::options()
+        .write(true)
+        .open(dir.join("page.html"))
+        .unwrap();
+    file.set_modified(SystemTime::now() + Duration::from_secs(10))
+        .unwrap();
+
+    assert_eq!(cached.render("page.html", &context).unwrap(), "old");
+    assert_eq!(reloading.render("page.html", &context).unwrap(), "new");
+}
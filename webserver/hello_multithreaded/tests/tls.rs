@@ -0,0 +1,179 @@
+// cargo test --features tls
+#![cfg(feature = "tls")]
+
+use hello_multithreaded::handler::Router;
+use hello_multithreaded::server::{Server, TlsConfig};
+use hello_multithreaded::websocket::{Endpoint, Message, WebSocket};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+use common::{send, TempDir, TestServer};
+
+// A fresh self-signed certificate for "localhost", and a client config that trusts it
+fn certificate() -> (TlsConfig, Arc<ClientConfig>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+    // Through files, the way a real server loads them
+    let dir = TempDir::new("tls");
+    let cert_path = dir.write("cert.pem", certified.cert.pem());
+    let key_path = dir.write("key.pem", certified.key_pair.serialize_pem());
+    let server = TlsConfig::from_pem_files(&cert_path, &key_path).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    (server, Arc::new(client))
+}
+
+// Plain HTTP and HTTPS side by side, the server's addr() is the plain one
+fn start(configure: impl FnOnce(Server) -> Server) -> (TestServer, SocketAddr, Arc<ClientConfig>) {
+    let (tls, client) = certificate();
+    let server = Server::bind("127.0.0.1:0", 2)
+        .unwrap()
+        .listen_tls("127.0.0.1:0", tls)
+        .unwrap();
+    let server = configure(server);
+
+    let https = server.tls_addr().unwrap().unwrap();
+    (TestServer::start(server), https, client)
+}
+
+fn get_https(addr: SocketAddr, client: &Arc<ClientConfig>, request: &[u8]) -> String {
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(Arc::clone(client), name).unwrap();
+    let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+
+    stream.write_all(request).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serves_http_and_https_side_by_side() {
+    let (server, https, client) = start(|server| server);
+    let http = server.addr();
+
+    let response = get_https(https, &client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("Hello!"));
+
+    let response = send(http, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    server.stop();
+}
+
+#[test]
+fn redirects_http_to_https() {
+    let (server, https, client) = start(|server| server.redirect_to_https(true));
+    let http = server.addr();
+
+    let response = send(
+        http,
+        b"GET /sleep?x=1 HTTP/1.1\r\nHost: localhost:8080\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 301"));
+    assert!(response.contains(&format!(
+        "Location: https://localhost:{}/sleep?x=1\r\n",
+        https.port()
+    )));
+
+    // HTTPS itself isn't redirected
+    let response = get_https(https, &client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    server.stop();
+}
+
+#[test]
+fn websockets_work_over_tls() {
+    let app = Router::new().get(
+        "/ws",
+        Endpoint::new(|socket: &WebSocket, message: Message| {
+            socket.send(message).unwrap();
+        }),
+    );
+    let (server, https, client) = start(|server| server.handler(app));
+
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(client, name).unwrap();
+    let tcp = TcpStream::connect(https).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut stream = StreamOwned::new(connection, tcp);
+    stream
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101 "));
+
+    // Two binary frames in one TLS record, together bigger than the server reads at once. An
+    // all-zero mask leaves the payload as it is
+    let payload = [7; 3000];
+    let mut frames = Vec::new();
+    for _ in 0..2 {
+        frames.extend_from_slice(&[0x82, 0x80 | 126]);
+        frames.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frames.extend_from_slice(&[0; 4]);
+        frames.extend_from_slice(&payload);
+    }
+    stream.write_all(&frames).unwrap();
+
+    for _ in 0..2 {
+        let mut echo = [0; 4 + 3000];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(echo[..4], [0x82, 126, 0x0b, 0xb8]);
+        assert_eq!(echo[4..], payload);
+    }
+
+    server.stop();
+}
+
+#[test]
+fn rejects_pem_without_key() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let error = TlsConfig::from_pem(certified.cert.pem().as_bytes(), b"").unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+// HTTPS keeps its accept loop, the redirect comes from the event loop
+#[cfg(target_os = "linux")]
+#[test]
+fn event_loop_redirects_http_to_https() {
+    use hello_multithreaded::server::Backend;
+
+    let (server, https, client) =
+        start(|server| server.backend(Backend::EventLoop).redirect_to_https(true));
+    let http = server.addr();
+
+    let response = send(http, b"GET /x HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 301"));
+    assert!(response.contains(&format!(
+        "Location: https://localhost:{}/x\r\n",
+        https.port()
+    )));
+
+    let response = get_https(https, &client, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    server.stop();
+}
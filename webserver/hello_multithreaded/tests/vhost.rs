@@ -0,0 +1,144 @@
+use hello_multithreaded::client::Client;
+use hello_multithreaded::files::Files;
+use hello_multithreaded::handler::{Handler, Router};
+use hello_multithreaded::request::{Method, Request};
+use hello_multithreaded::response::{Body, Response, StatusCode};
+use hello_multithreaded::server::Server;
+use hello_multithreaded::vhost::VirtualHosts;
+use std::io::prelude::*;
+
+mod common;
+use common::{send, TempDir, TestServer};
+
+// Two document roots and a file next to them that must not be reachable. Every test gets its
+// own copy, they run in parallel
+fn sites(test: &str) -> TempDir {
+    TempDir::with_files(
+        test,
+        &[
+            ("a/index.html", "site a"),
+            ("a/style.css", "body {}"),
+            ("a/docs/index.html", "a docs"),
+            ("a/.env", "SECRET=1"),
+            ("b/index.html", "site b"),
+            ("private.txt", "private"),
+        ],
+    )
+}
+
+fn text(mut response: Response) -> String {
+    match response.take_body() {
+        Body::Empty => String::new(),
+        Body::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+        Body::File { mut file, .. } => {
+            let mut text = String::new();
+            file.read_to_string(&mut text).unwrap();
+            text
+        }
+        Body::Chunked(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn files_stay_inside_the_document_root() {
+    let dir = sites("files");
+    let files = Files::new(dir.join("a"));
+    let get = |target: &str| files.handle(Request::new(Method::Get, target));
+
+    let response = get("/");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("Content-Type"),
+        Some("text/html; charset=utf-8")
+    );
+    assert_eq!(text(response), "site a");
+    let response = get("/style.css");
+    assert_eq!(
+        response.headers().get("Content-Type"),
+        Some("text/css; charset=utf-8")
+    );
+    assert_eq!(text(get("/docs/")), "a docs");
+    assert_eq!(text(get("/%64ocs/index.html")), "a docs");
+
+    // Directories get their slash first
+    let response = get("/docs?page=2");
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(response.headers().get("Location"), Some("/docs/?page=2"));
+
+    for target in [
+        "/missing.html",
+        "/../private.txt",
+        "/docs/../../private.txt",
+        "/%2e%2e/private.txt",
+        "/..%5cprivate.txt",
+        "/docs%2Findex.html",
+        "/docs%5Cindex.html",
+        "/.env",
+    ] {
+        assert_eq!(get(target).status(), StatusCode::NOT_FOUND, "{}", target);
+    }
+    let response = files.handle(Request::new(Method::Post, "/"));
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    // Mounted below a prefix
+    let router = Router::new().nest("/static", Files::new(dir.join("a")).strip_prefix("/static"));
+    let response = router.handle(Request::new(Method::Get, "/static/docs/"));
+    assert_eq!(text(response), "a docs");
+}
+
+#[test]
+fn routes_by_host_on_every_listener() {
+    let dir = sites("vhost");
+    let hosts = VirtualHosts::new(|_: Request| Response::new(StatusCode::OK).body("default"))
+        .host("a.test", Files::new(dir.join("a")))
+        .host("B.test.", Files::new(dir.join("b")))
+        .host(
+            "*.wiki.test",
+            Router::new().get("/", |request: Request| {
+                let host = request.header("Host").unwrap_or_default().to_string();
+                Response::new(StatusCode::OK).body(format!("wiki {}", host))
+            }),
+        );
+    let server = Server::bind("127.0.0.1:0", 2)
+        .unwrap()
+        .listen("127.0.0.1:0")
+        .unwrap()
+        .handler(hosts);
+    let addrs = server.local_addrs().unwrap();
+    assert_eq!(addrs.len(), 2);
+    let server = TestServer::start(server);
+
+    let get = |addr, host: &str| {
+        let mut request = Request::new(Method::Get, "/");
+        request.headers_mut().insert("Host", host).unwrap();
+        let response = Client::new(addr).unwrap().send(&request).unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", host);
+        response.text().into_owned()
+    };
+    for addr in addrs.iter().copied() {
+        assert_eq!(get(addr, "a.test"), "site a");
+        assert_eq!(get(addr, "A.Test:8080"), "site a");
+        assert_eq!(get(addr, "b.test"), "site b");
+        assert_eq!(get(addr, "team.wiki.test"), "wiki team.wiki.test");
+        assert_eq!(get(addr, "wiki.test"), "default");
+        assert_eq!(get(addr, "[::1]:8080"), "default");
+    }
+
+    // HTTP/1.1 requires one Host that can be told from its port, HTTP/1.0 clients get the
+    // default host
+    for (request, expected) in [
+        (&b"GET / HTTP/1.1\r\n\r\n"[..], "HTTP/1.1 400 "),
+        (
+            b"GET / HTTP/1.1\r\nHost: a.test\r\nHost: b.test\r\n\r\n",
+            "HTTP/1.1 400 ",
+        ),
+        (b"GET / HTTP/1.0\r\n\r\n", "HTTP/1.1 200 "),
+        // Is 8080 the port or part of the address?
+        (b"GET / HTTP/1.1\r\nHost: ::1:8080\r\n\r\n", "HTTP/1.1 400 "),
+    ] {
+        let response = send(addrs[1], request);
+        assert!(response.starts_with(expected), "{}", response);
+    }
+
+    server.stop();
+}
@@ -0,0 +1,456 @@
+use hello_multithreaded::handler::Router;
+use hello_multithreaded::request::Request;
+use hello_multithreaded::response::{Response, StatusCode};
+use hello_multithreaded::server::{Backend, Server};
+use hello_multithreaded::websocket::{CloseFrame, Endpoint, Message, WebSocket, WebSocketHandler};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{backends, TestServer};
+
+// The example from RFC 6455, section 1.3
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Echoes messages, and tells the test about connections opening and closing
+struct Echo {
+    opened: Mutex<Sender<WebSocket>>,
+    closed: Mutex<Sender<CloseFrame>>,
+}
+
+struct Events {
+    opened: Receiver<WebSocket>,
+    closed: Receiver<CloseFrame>,
+}
+
+impl WebSocketHandler for Echo {
+    fn on_open(&self, socket: &WebSocket) {
+        let _ = self.opened.lock().unwrap().send(socket.clone());
+    }
+
+    fn on_message(&self, socket: &WebSocket, message: Message) {
+        socket.send(message).unwrap();
+    }
+
+    fn on_close(&self, _socket: &WebSocket, frame: CloseFrame) {
+        let _ = self.closed.lock().unwrap().send(frame);
+    }
+}
+
+fn echo() -> (Echo, Events) {
+    let (opened, opened_events) = mpsc::channel();
+    let (closed, closed_events) = mpsc::channel();
+    let echo = Echo {
+        opened: Mutex::new(opened),
+        closed: Mutex::new(closed),
+    };
+    let events = Events {
+        opened: opened_events,
+        closed: closed_events,
+    };
+    (echo, events)
+}
+
+// A single worker, so an upgraded connection that kept it would starve everything else
+fn start(backend: Backend, endpoint: Endpoint) -> TestServer {
+    start_with(backend, endpoint, |server| server)
+}
+
+fn start_with(
+    backend: Backend,
+    endpoint: Endpoint,
+    configure: impl FnOnce(Server) -> Server,
+) -> TestServer {
+    let app = Router::new().get("/ws", endpoint).get("/", |_: Request| {
+        Response::new(StatusCode::OK).body("plain HTTP")
+    });
+    let server = Server::bind("127.0.0.1:0", 1)
+        .unwrap()
+        .backend(backend)
+        .handler(app);
+    TestServer::start(configure(server))
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+         Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        KEY
+    )
+    .unwrap();
+
+    // Byte by byte, so nothing after the head is read by accident
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{}",
+        head
+    );
+    assert!(head.contains(&format!("Sec-WebSocket-Accept: {}\r\n", ACCEPT)));
+    stream
+}
+
+// Clients have to mask their frames
+fn send(stream: &mut TcpStream, fin: bool, opcode: u8, payload: &[u8]) {
+    send_frame(stream, fin, opcode, payload, Some([0x37, 0xfa, 0x21, 0x3d]));
+}
+
+fn send_frame(
+    stream: &mut TcpStream,
+    fin: bool,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) {
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(
+                payload
+                    .iter()
+                    .enumerate()
+                    .map(|(i, byte)| byte ^ mask[i % 4]),
+            );
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    stream.write_all(&frame).unwrap();
+}
+
+// (fin, opcode, payload) of the next server frame
+fn receive(stream: &mut TcpStream) -> (bool, u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[1] & 0x80, 0, "server frames aren't masked");
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0] & 0x80 != 0, head[0] & 0x0F, payload)
+}
+
+fn close_code(payload: &[u8]) -> u16 {
+    u16::from_be_bytes([payload[0], payload[1]])
+}
+
+fn assert_closed(stream: &mut TcpStream) {
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn handshake_is_checked() {
+    let (echo, _events) = echo();
+    let server = start(Backend::Threads, Endpoint::new(echo));
+    let addr = server.addr();
+
+    connect(addr);
+
+    let response = common::send(addr, b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    assert!(response.contains("Upgrade: websocket\r\n"));
+
+    let response = common::send(
+        addr,
+        b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 426"));
+    assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+
+    let response = common::send(
+        addr,
+        b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: too-short\r\nSec-WebSocket-Version: 13\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 400"));
+
+    server.stop();
+}
+
+#[test]
+fn echoes_messages_without_holding_the_worker() {
+    for backend in backends() {
+        let (echo, _events) = echo();
+        let server = start(backend, Endpoint::new(echo));
+        let addr = server.addr();
+
+        // Both stay open, and the only worker still has time for plain requests
+        let mut first = connect(addr);
+        let mut second = connect(addr);
+        assert!(
+            common::send(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .ends_with("plain HTTP")
+        );
+
+        send(&mut first, true, TEXT, "héllo".as_bytes());
+        assert_eq!(
+            receive(&mut first),
+            (true, TEXT, "héllo".as_bytes().to_vec())
+        );
+
+        // 16 and 64 bit lengths
+        for len in [300, 70_000] {
+            let bytes: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            send(&mut second, true, BINARY, &bytes);
+            assert_eq!(receive(&mut second), (true, BINARY, bytes));
+        }
+
+        // A fragmented message, with a ping in between that is answered right away
+        send(&mut first, false, TEXT, b"frag");
+        send(&mut first, true, PING, b"are you there");
+        send(&mut first, false, 0x0, b"men");
+        send(&mut first, true, 0x0, b"ted");
+        assert_eq!(receive(&mut first), (true, PONG, b"are you there".to_vec()));
+        assert_eq!(receive(&mut first), (true, TEXT, b"fragmented".to_vec()));
+
+        drop((first, second));
+        server.stop();
+    }
+}
+
+#[test]
+fn close_handshake() {
+    let (echo, events) = echo();
+    let server = start(Backend::Threads, Endpoint::new(echo));
+    let addr = server.addr();
+
+    // The client closes
+    let mut stream = connect(addr);
+    events.opened.recv_timeout(Duration::from_secs(5)).unwrap();
+    send(&mut stream, true, CLOSE, b"\x03\xe8bye");
+    let (_, opcode, payload) = receive(&mut stream);
+    assert_eq!((opcode, close_code(&payload)), (CLOSE, CloseFrame::NORMAL));
+    assert_closed(&mut stream);
+    let frame = events.closed.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(frame.code, CloseFrame::NORMAL);
+    assert_eq!(frame.reason, "bye");
+
+    // The server closes, from another thread
+    let mut stream = connect(addr);
+    let socket = events.opened.recv_timeout(Duration::from_secs(5)).unwrap();
+    socket.send("pushed").unwrap();
+    socket.close(4000, "done here").unwrap();
+    assert!(socket.send("too late").is_err());
+    assert_eq!(receive(&mut stream), (true, TEXT, b"pushed".to_vec()));
+    let (_, opcode, payload) = receive(&mut stream);
+    assert_eq!(opcode, CLOSE);
+    assert_eq!(close_code(&payload), 4000);
+    assert_eq!(&payload[2..], b"done here");
+    send(&mut stream, true, CLOSE, b"\x0f\xa0");
+    assert_closed(&mut stream);
+    assert_eq!(
+        events
+            .closed
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .code,
+        4000
+    );
+
+    // Shutdown says goodbye to connections that are still open
+    let mut stream = connect(addr);
+    server.shutdown_handle().shutdown();
+    let (_, opcode, payload) = receive(&mut stream);
+    assert_eq!(
+        (opcode, close_code(&payload)),
+        (CLOSE, CloseFrame::GOING_AWAY)
+    );
+    server.stop();
+}
+
+#[test]
+fn protocol_errors_fail_the_connection() {
+    let (echo, events) = echo();
+    let server = start(Backend::Threads, Endpoint::new(echo).max_message_size(1024));
+    let addr = server.addr();
+
+    let mut stream = connect(addr);
+    send_frame(&mut stream, true, TEXT, b"unmasked", None);
+    let (_, opcode, payload) = receive(&mut stream);
+    assert_eq!(
+        (opcode, close_code(&payload)),
+        (CLOSE, CloseFrame::PROTOCOL_ERROR)
+    );
+    assert_closed(&mut stream);
+
+    let mut stream = connect(addr);
+    send(&mut stream, true, TEXT, b"\xff\xfe");
+    let (_, _, payload) = receive(&mut stream);
+    assert_eq!(close_code(&payload), CloseFrame::INVALID_DATA);
+
+    // Too big in one frame, and in fragments that are small on their own
+    let mut stream = connect(addr);
+    send(&mut stream, true, BINARY, &[0; 2000]);
+    let (_, _, payload) = receive(&mut stream);
+    assert_eq!(close_code(&payload), CloseFrame::TOO_BIG);
+
+    let mut stream = connect(addr);
+    send(&mut stream, false, BINARY, &[0; 600]);
+    send(&mut stream, true, 0x0, &[0; 600]);
+    let (_, _, payload) = receive(&mut stream);
+    assert_eq!(close_code(&payload), CloseFrame::TOO_BIG);
+
+    // Continuation without a start
+    let mut stream = connect(addr);
+    send(&mut stream, true, 0x0, b"orphan");
+    let (_, _, payload) = receive(&mut stream);
+    assert_eq!(close_code(&payload), CloseFrame::PROTOCOL_ERROR);
+
+    for _ in 0..5 {
+        let frame = events.closed.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(frame.code, CloseFrame::ABNORMAL);
+    }
+
+    server.stop();
+}
+
+#[test]
+fn quiet_clients_are_pinged_and_dropped() {
+    let (echo, events) = echo();
+    let server = start(
+        Backend::Threads,
+        Endpoint::new(echo).ping_interval(Duration::from_millis(200)),
+    );
+    let addr = server.addr();
+
+    // Answering pings keeps the connection open
+    let mut stream = connect(addr);
+    for _ in 0..3 {
+        let (_, opcode, payload) = receive(&mut stream);
+        assert_eq!(opcode, PING);
+        send(&mut stream, true, PONG, &payload);
+    }
+
+    // Ignoring them doesn't
+    assert_eq!(receive(&mut stream).1, PING);
+    assert_closed(&mut stream);
+    let frame = events.closed.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(frame.code, CloseFrame::ABNORMAL);
+
+    server.stop();
+}
+
+// The connection is given back by the upgraded connection's thread once it's done, shortly
+// after the client sees the close
+fn eventually(mut check: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !check() {
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Only the status line, an upgraded connection doesn't end after the response. A refused
+// connection may be reset before or after its answer, which leaves the line short
+fn status(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let _ = stream.write_all(request.as_bytes());
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") && stream.read_exact(&mut byte).is_ok() {
+        line.push(byte[0]);
+    }
+    String::from_utf8_lossy(&line).into_owned()
+}
+
+#[test]
+fn upgraded_connections_are_limited() {
+    for backend in backends() {
+        // Still a connection of its client's
+        let (handler, events) = echo();
+        let server = start_with(backend, Endpoint::new(handler), |server| {
+            server.max_connections_per_ip(1)
+        });
+        let addr = server.addr();
+        let mut stream = connect(addr);
+        events.opened.recv_timeout(Duration::from_secs(5)).unwrap();
+        // Refused on accept, before it could send anything. Unread bytes would make the close
+        // a reset
+        let mut response = String::new();
+        TcpStream::connect(addr)
+            .unwrap()
+            .read_to_string(&mut response)
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 429 "), "{}", response);
+        send(&mut stream, true, CLOSE, b"\x03\xe8");
+        receive(&mut stream);
+        assert_closed(&mut stream);
+        eventually(|| {
+            status(addr, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n") == "HTTP/1.1 200 OK\r\n"
+        });
+        server.stop();
+
+        // And only so many of them at once
+        let (handler, events) = echo();
+        let server = start_with(backend, Endpoint::new(handler), |server| {
+            server.max_upgraded_connections(1)
+        });
+        let addr = server.addr();
+        let upgrade = format!(
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            KEY
+        );
+        let mut stream = connect(addr);
+        events.opened.recv_timeout(Duration::from_secs(5)).unwrap();
+        let response = common::send(addr, upgrade.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        assert!(
+            common::send(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .ends_with("plain HTTP")
+        );
+        send(&mut stream, true, CLOSE, b"\x03\xe8");
+        receive(&mut stream);
+        assert_closed(&mut stream);
+        eventually(|| status(addr, &upgrade).starts_with("HTTP/1.1 101 "));
+        server.stop();
+    }
+}